@@ -0,0 +1,63 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rconv::stepmania::*;
+
+const ROWS: [&str; 8] = [
+    "1000", "0100", "0010", "0001", "2000", "0000", "3000", "M00M",
+];
+
+/// Builds a simfile with the amount of charts, each with the amount of 16th-note measures
+fn simfile(charts: usize, measures: usize) -> String {
+    let mut out =
+        String::from("#TITLE:Benchmark;\n#ARTIST:rconv;\n#OFFSET:-0.010;\n#BPMS:0.000=150.000");
+    for beat in 1..measures {
+        out.push_str(&format!(",\n{}.000={}.000", beat * 4, 120 + beat % 60));
+    }
+    out.push_str(";\n#STOPS:;\n");
+
+    for chart in 0..charts {
+        out.push_str(&format!(
+            "#NOTES:\n     dance-single:\n     Chart {}:\n     Challenge:\n     {}:\n     0,0,0,0,0:\n",
+            chart,
+            chart % 20
+        ));
+        for measure in 0..measures {
+            if measure > 0 {
+                out.push_str(",\n");
+            }
+            for row in 0..16 {
+                out.push_str(ROWS[(measure + row + chart) % ROWS.len()]);
+                out.push('\n');
+            }
+        }
+        out.push_str(";\n");
+    }
+
+    out
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("stepmania");
+    for (charts, measures) in [(4, 100), (20, 400)] {
+        let input = simfile(charts, measures);
+        let id = format!("{}x{}", charts, measures);
+        group.throughput(Throughput::Bytes(input.len() as u64));
+
+        group.bench_with_input(BenchmarkId::new("tokenize", &id), &input, |b, input| {
+            b.iter(|| StepmaniaTokenizer::new(black_box(input)).count())
+        });
+        group.bench_with_input(BenchmarkId::new("parse", &id), &input, |b, input| {
+            b.iter(|| {
+                StepmaniaParser::new()
+                    .parse_from_string(black_box(input))
+                    .unwrap()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("stream", &id), &input, |b, input| {
+            b.iter(|| StepmaniaStreamParser::new(black_box(input.as_bytes())).count())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse);
+criterion_main!(benches);
@@ -0,0 +1,255 @@
+use serde::Serialize;
+
+use super::stepmania::*;
+
+/// Size of the window in which the peak NPS is measured, in ms
+const PEAK_WINDOW: f64 = 1000.0;
+/// Size of a single entry in the NPS graph, in ms
+const GRAPH_RESOLUTION: f64 = 1000.0;
+/// The quantizations a measure can be streamed in, from the densest one.
+/// A measure is a stream when it has at least as many rows with notes as the quantization.
+/// They have to divide the rows of a measure evenly, so 20ths can't be detected.
+const STREAM_QUANTIZATIONS: [usize; 3] = [32, 24, 16];
+
+/// If the note counts towards the density, i.E. has to be hit by the player
+fn is_hittable(note_type: StepmaniaNoteType) -> bool {
+    matches!(
+        note_type,
+        StepmaniaNoteType::Tap
+            | StepmaniaNoteType::HoldHead
+            | StepmaniaNoteType::RollHead
+            | StepmaniaNoteType::Lift
+    )
+}
+
+/// A section of consecutive measures which are either streamed or a break
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepmaniaStreamSegment {
+    /// The first measure of the segment
+    pub start_measure: usize,
+    /// The amount of measures in the segment
+    pub measure_count: usize,
+    /// The quantization the segment is streamed in (i.E. 16 for 16ths),
+    /// or `None` if it's a break.
+    pub quantization: Option<usize>,
+    /// The start of the segment in ms
+    pub start_time: i64,
+    /// The end of the segment in ms
+    pub end_time: i64,
+}
+
+impl StepmaniaStreamSegment {
+    /// A readable description, like "16ths stream for 32 measures"
+    pub fn description(&self) -> String {
+        let measures = if self.measure_count == 1 {
+            "measure"
+        } else {
+            "measures"
+        };
+        match self.quantization {
+            Some(quantization) => format!(
+                "{}ths stream for {} {}",
+                quantization, self.measure_count, measures
+            ),
+            None => format!("break for {} {}", self.measure_count, measures),
+        }
+    }
+}
+
+/// The note density of a chart over time
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct StepmaniaDensity {
+    /// The amount of notes which have to be hit
+    pub note_count: usize,
+    /// Time of the first note in ms
+    pub first_note_time: i64,
+    /// Time of the last note in ms
+    pub last_note_time: i64,
+    /// Average notes per second between the first and last note
+    pub average_nps: f64,
+    /// The most notes hit in any one second window
+    pub peak_nps: f64,
+    /// The time in ms where the peak window starts
+    pub peak_time: i64,
+    /// The notes per second for each second since the start of the music
+    pub graph: Vec<f64>,
+    /// The streams and breaks of the chart
+    pub segments: Vec<StepmaniaStreamSegment>,
+    /// Total amount of measures which are streamed
+    pub stream_measures: usize,
+}
+
+impl StepmaniaDensity {
+    /// Analyzes the density of the chart, with the timing of the file
+    pub fn from_chart(step: &StepmaniaFile, chart: &StepmaniaChart) -> StepmaniaDensity {
+        let notes: Vec<StepmaniaPositionedNote> = chart
+            .data
+            .positioned_notes()
+            .into_iter()
+            .filter(|note| is_hittable(note.note_type))
+            .collect();
+        let mut times: Vec<f64> = notes
+            .iter()
+            .map(|note| step.beat_to_ms(note.beat()))
+            .collect();
+        times.sort_by(|a, b| a.total_cmp(b));
+
+        let mut density = StepmaniaDensity {
+            note_count: times.len(),
+            ..Default::default()
+        };
+        let (Some(first), Some(last)) = (times.first(), times.last()) else {
+            return density;
+        };
+        density.first_note_time = first.round() as i64;
+        density.last_note_time = last.round() as i64;
+
+        let duration = (last - first) / 1000.0;
+        if duration > 0.0 {
+            density.average_nps = times.len() as f64 / duration;
+        }
+
+        // Sliding window over the sorted note times
+        let mut start = 0;
+        let mut peak = 0;
+        for (end, time) in times.iter().enumerate() {
+            while times[start] <= time - PEAK_WINDOW {
+                start += 1;
+            }
+            if end - start + 1 > peak {
+                peak = end - start + 1;
+                density.peak_time = times[start].round() as i64;
+            }
+        }
+        density.peak_nps = peak as f64 * 1000.0 / PEAK_WINDOW;
+
+        let bucket_count = (last.max(0.0) / GRAPH_RESOLUTION).floor() as usize + 1;
+        density.graph = vec![0.0; bucket_count];
+        for time in times.iter().filter(|time| **time >= 0.0) {
+            density.graph[(time / GRAPH_RESOLUTION) as usize] += 1000.0 / GRAPH_RESOLUTION;
+        }
+
+        density.segments = StepmaniaDensity::segments(step, &notes);
+        density.stream_measures = density
+            .segments
+            .iter()
+            .filter(|segment| segment.quantization.is_some())
+            .map(|segment| segment.measure_count)
+            .sum();
+
+        density
+    }
+
+    fn segments(
+        step: &StepmaniaFile,
+        notes: &[StepmaniaPositionedNote],
+    ) -> Vec<StepmaniaStreamSegment> {
+        let measure_count = notes.iter().map(|n| n.measure + 1).max().unwrap_or(0);
+        let mut rows: Vec<Vec<usize>> = vec![vec![]; measure_count];
+        for note in notes.iter() {
+            // Normalize the rows, as the measures may be divided differently
+            let row = note.row * MAX_ROWS_PER_MEASURE / note.row_count.max(1);
+            if !rows[note.measure].contains(&row) {
+                rows[note.measure].push(row);
+            }
+        }
+
+        let mut segments: Vec<StepmaniaStreamSegment> = vec![];
+        for (measure, rows) in rows.iter().enumerate() {
+            let quantization = STREAM_QUANTIZATIONS
+                .iter()
+                .find(|quantization| {
+                    rows.len() >= **quantization
+                        && rows
+                            .iter()
+                            .all(|row| row % (MAX_ROWS_PER_MEASURE / **quantization) == 0)
+                })
+                .copied()
+                // Dense measures with mixed quantizations still count as the lowest stream
+                .or_else(|| Some(16).filter(|min| rows.len() >= *min));
+
+            let start_beat = (measure * BEATS_PER_MEASURE) as f64;
+            let end_time = step
+                .beat_to_ms(start_beat + BEATS_PER_MEASURE as f64)
+                .round() as i64;
+            match segments.last_mut() {
+                Some(last) if last.quantization == quantization => {
+                    last.measure_count += 1;
+                    last.end_time = end_time;
+                }
+                _ => segments.push(StepmaniaStreamSegment {
+                    start_measure: measure,
+                    measure_count: 1,
+                    quantization,
+                    start_time: step.beat_to_ms(start_beat).round() as i64,
+                    end_time,
+                }),
+            }
+        }
+
+        // A leading break is just the intro of the song
+        if segments.first().is_some_and(|s| s.quantization.is_none()) {
+            segments.remove(0);
+        }
+
+        segments
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stepstyle::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn it_should_calculate_nps() {
+        // 8ths for 4 beats (2 seconds at 120 BPM), then 16ths for 4 beats
+        let mut notes: Vec<(f64, u8)> = (0..8).map(|i| (i as f64 * 0.5, (i % 4) as u8)).collect();
+        notes.extend((0..16).map(|i| (4.0 + i as f64 * 0.25, (i % 4) as u8)));
+        let step = file_with(
+            120000,
+            chart_from(StepmaniaStepStyle::DanceSingle, &taps(notes)),
+        );
+        let density = StepmaniaDensity::from_chart(&step, &step.notes[0]);
+
+        assert_eq!(density.note_count, 24);
+        assert_eq!(density.first_note_time, 0);
+        assert_eq!(density.last_note_time, 3875);
+        assert_eq!(density.peak_nps, 8.0);
+        assert_eq!(density.peak_time, 2000);
+        assert!((density.average_nps - 24.0 / 3.875).abs() < 0.0001);
+        assert_eq!(density.graph, vec![4.0, 4.0, 8.0, 8.0]);
+    }
+
+    #[test]
+    fn it_should_segment_streams() {
+        let mut notes: Vec<(f64, u8)> = vec![(0.0, 0)];
+        // Two measures of 16ths, one break, one measure of 24ths and one of 20ths
+        notes.extend((0..32).map(|i| (4.0 + i as f64 * 0.25, (i % 4) as u8)));
+        notes.push((12.0, 0));
+        notes.extend((0..24).map(|i| (16.0 + i as f64 / 6.0, (i % 4) as u8)));
+        notes.extend((0..20).map(|i| (20.0 + i as f64 / 5.0, (i % 4) as u8)));
+        let step = file_with(
+            120000,
+            chart_from(StepmaniaStepStyle::DanceSingle, &taps(notes)),
+        );
+        let density = StepmaniaDensity::from_chart(&step, &step.notes[0]);
+
+        let descriptions: Vec<String> = density.segments.iter().map(|s| s.description()).collect();
+        assert_eq!(
+            descriptions,
+            vec![
+                "16ths stream for 2 measures",
+                "break for 1 measure",
+                "24ths stream for 1 measure",
+                // 20ths don't fit into the rows of a measure, and only count as the lowest stream
+                "16ths stream for 1 measure",
+            ]
+        );
+        assert_eq!(density.segments[0].start_measure, 1);
+        assert_eq!(density.segments[0].start_time, 2000);
+        assert_eq!(density.segments[0].end_time, 6000);
+        assert_eq!(density.stream_measures, 4);
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use super::stepmania::*;
+
+/// The extensions of files Stepmania uses as images, videos, audio or lyrics
+const ASSET_EXTENSIONS: [&str; 17] = [
+    "png", "jpg", "jpeg", "gif", "bmp", "avi", "mp4", "mpg", "mpeg", "wmv", "mkv", "ogv", "ogg",
+    "mp3", "wav", "flac", "lrc",
+];
+const IMAGE_EXTENSIONS: [&str; 5] = ["png", "jpg", "jpeg", "gif", "bmp"];
+
+/// A file referenced by the simfile which doesn't exist
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepmaniaMissingAsset {
+    /// The tag the file is referenced by, in lowercase
+    pub tag: String,
+    pub path: String,
+}
+
+/// A file referenced by the simfile, and where it was found
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepmaniaResolvedAsset {
+    /// The tag the file is referenced by, in lowercase
+    pub tag: String,
+    pub path: String,
+    /// The actual path of the file, which may differ in case
+    pub resolved: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct StepmaniaAssetReport {
+    pub resolved: Vec<StepmaniaResolvedAsset>,
+    pub missing: Vec<StepmaniaMissingAsset>,
+    /// Asset files in the song directory which aren't referenced by the simfile
+    pub unused: Vec<PathBuf>,
+}
+
+impl StepmaniaAssetReport {
+    pub fn is_valid(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+/// Checks the files referenced by a simfile against the directory of the song
+#[derive(Debug)]
+pub struct StepmaniaAssetValidator {
+    pub song_dir: PathBuf,
+}
+
+impl StepmaniaAssetValidator {
+    pub fn new(song_dir: impl Into<PathBuf>) -> StepmaniaAssetValidator {
+        StepmaniaAssetValidator {
+            song_dir: song_dir.into(),
+        }
+    }
+
+    /// Creates a validator for the directory the simfile is in
+    pub fn for_simfile(path: &Path) -> StepmaniaAssetValidator {
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        StepmaniaAssetValidator::new(dir)
+    }
+
+    /// Resolves the path relative to the song directory.
+    /// Like Stepmania, the case of the path is ignored if there is no exact match.
+    pub fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let mut resolved = self.song_dir.clone();
+        for part in path.split(['/', '\\']) {
+            match part {
+                "" | "." => continue,
+                ".." => {
+                    resolved.push("..");
+                    continue;
+                }
+                _ => {}
+            }
+
+            let exact = resolved.join(part);
+            if exact.exists() {
+                resolved = exact;
+                continue;
+            }
+
+            let lower = part.to_lowercase();
+            let entry = fs::read_dir(&resolved).ok()?.flatten().find(|entry| {
+                entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|name| name.to_lowercase() == lower)
+            })?;
+            resolved.push(entry.file_name());
+        }
+
+        Some(resolved)
+    }
+
+    /// Checks which of the referenced files exist, and which asset files are not used
+    pub fn validate(&self, step: &StepmaniaFile) -> Result<StepmaniaAssetReport> {
+        let mut report = StepmaniaAssetReport {
+            ..Default::default()
+        };
+
+        let mut used = HashSet::new();
+        for (tag, path) in step.referenced_files() {
+            match self.resolve(&path) {
+                Some(resolved) => {
+                    used.insert(normalize(&resolved));
+                    report.resolved.push(StepmaniaResolvedAsset {
+                        tag: tag.to_string(),
+                        path,
+                        resolved,
+                    });
+                }
+                None => report.missing.push(StepmaniaMissingAsset {
+                    tag: tag.to_string(),
+                    path,
+                }),
+            }
+        }
+
+        let mut files = vec![];
+        collect_files(&self.song_dir, &mut files)?;
+        for file in files {
+            if !is_asset(&file) {
+                continue;
+            }
+            // Files inside of a referenced directory, like a BGAnimation, are used as well
+            let normalized = normalize(&file);
+            let is_used = normalized
+                .ancestors()
+                .any(|ancestor| used.contains(ancestor));
+            if !is_used {
+                report.unused.push(
+                    file.strip_prefix(&self.song_dir)
+                        .unwrap_or(&file)
+                        .to_path_buf(),
+                );
+            }
+        }
+        report.unused.sort();
+
+        Ok(report)
+    }
+
+    /// Fills in the banner, background, CD title, jacket, CD image and disc image if they're not set,
+    /// using the same naming heuristics as Stepmania. Returns the tags which have been set.
+    pub fn discover(&self, step: &mut StepmaniaFile) -> Result<Vec<&'static str>> {
+        let mut images: Vec<String> = fs::read_dir(&self.song_dir)?
+            .flatten()
+            .filter(|entry| entry.path().is_file())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter(|name| has_extension(name, &IMAGE_EXTENSIONS))
+            .collect();
+        images.sort();
+
+        let targets = [
+            ("banner", &mut step.banner),
+            ("background", &mut step.background),
+            ("cdtitle", &mut step.cd_title),
+            ("jacket", &mut step.jacket),
+            ("cdimage", &mut step.cd_image),
+            ("discimage", &mut step.disk_image),
+        ];
+
+        let mut discovered = vec![];
+        for (tag, value) in targets {
+            if value.as_ref().is_some_and(|v| !v.trim().is_empty()) {
+                continue;
+            }
+
+            let found = images.iter().find(|name| {
+                let stem = Path::new(name.as_str())
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .unwrap_or_default()
+                    .to_lowercase();
+                is_named_like(tag, &stem)
+            });
+            if let Some(found) = found {
+                *value = Some(found.clone());
+                discovered.push(tag);
+            }
+        }
+
+        Ok(discovered)
+    }
+}
+
+/// If the file name without extension is one Stepmania would pick for the tag
+fn is_named_like(tag: &str, name: &str) -> bool {
+    match tag {
+        "banner" => name.starts_with("banner") || name.ends_with("bn"),
+        "background" => name.starts_with("background") || name.ends_with("bg"),
+        "cdtitle" => name.contains("cdtitle"),
+        "jacket" => name.starts_with("jk_") || name.contains("jacket") || name.contains("albumart"),
+        "cdimage" => name.contains("-cd"),
+        "discimage" => name.contains(" disc") || name.contains(" title"),
+        _ => false,
+    }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        // The file type of the entry itself, as symlinks aren't followed by it
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        if file_type.is_dir() {
+            collect_files(&path, files)?;
+        } else if file_type.is_symlink() && path.is_dir() {
+            // Linked directories are skipped, as they might link back to the song directory
+            continue;
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn has_extension(name: &str, extensions: &[&str]) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| extensions.contains(&ext.to_lowercase().as_str()))
+}
+
+fn is_asset(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|path| has_extension(path, &ASSET_EXTENSIONS))
+}
+
+/// Removes `.` and `..` from the path, so that paths to the same file can be compared
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push("..");
+                }
+            }
+            _ => normalized.push(component),
+        }
+    }
+
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty song directory in the temp directory with the files
+    fn song_dir(name: &str, files: &[&str]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("rconv-assets-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for file in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        dir
+    }
+
+    #[test]
+    fn it_should_resolve_case_insensitive() {
+        let dir = song_dir("resolve", &["Song.OGG", "Gfx/Banner.png"]);
+        let validator = StepmaniaAssetValidator::new(&dir);
+
+        assert_eq!(validator.resolve("song.ogg"), Some(dir.join("Song.OGG")));
+        assert_eq!(
+            validator.resolve("gfx\\BANNER.PNG"),
+            Some(dir.join("Gfx").join("Banner.png"))
+        );
+        assert_eq!(validator.resolve("missing.png"), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_should_report_missing_and_unused_assets() {
+        let dir = song_dir(
+            "validate",
+            &["song.sm", "Song.ogg", "bn.png", "old-bg.png", "anim/1.png"],
+        );
+        let step = StepmaniaFile {
+            music: Some("song.ogg".to_string()),
+            banner: Some("bn.png".to_string()),
+            background: Some("bg.png".to_string()),
+            background_changes: vec![StepmaniaTimedVisualChange {
+                path: "anim".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let report = StepmaniaAssetValidator::new(&dir).validate(&step).unwrap();
+
+        assert_eq!(report.resolved.len(), 3);
+        assert_eq!(
+            report.missing,
+            vec![StepmaniaMissingAsset {
+                tag: "background".to_string(),
+                path: "bg.png".to_string()
+            }]
+        );
+        assert_eq!(report.unused, vec![PathBuf::from("old-bg.png")]);
+        assert!(!report.is_valid());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_should_skip_linked_directories() {
+        let dir = song_dir("symlink", &["song.ogg", "anim/1.png"]);
+        std::os::unix::fs::symlink(&dir, dir.join("anim").join("loop")).unwrap();
+        let step = StepmaniaFile {
+            music: Some("song.ogg".to_string()),
+            ..Default::default()
+        };
+        let report = StepmaniaAssetValidator::new(&dir).validate(&step).unwrap();
+
+        assert_eq!(report.unused, vec![PathBuf::from("anim/1.png")]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_should_discover_images() {
+        let dir = song_dir(
+            "discover",
+            &[
+                "song.ogg",
+                "Song-BN.png",
+                "song-bg.JPG",
+                "jk_song.png",
+                "cover.png",
+            ],
+        );
+        let mut step = StepmaniaFile {
+            banner: Some("custom.png".to_string()),
+            ..Default::default()
+        };
+        let discovered = StepmaniaAssetValidator::new(&dir)
+            .discover(&mut step)
+            .unwrap();
+
+        assert_eq!(discovered, vec!["background", "jacket"]);
+        assert_eq!(step.banner, Some("custom.png".to_string()));
+        assert_eq!(step.background, Some("song-bg.JPG".to_string()));
+        assert_eq!(step.jacket, Some("jk_song.png".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
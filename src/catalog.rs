@@ -0,0 +1,327 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use rayon::prelude::*;
+use serde::Serialize;
+
+use super::common::*;
+use super::dwi::*;
+use super::stepmania::*;
+
+/// The simfile extensions used by song folders, the preferred one first
+const SIMFILE_EXTENSIONS: [&str; 3] = ["ssc", "sm", "dwi"];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepmaniaCatalogChart {
+    pub step_style: String,
+    pub difficulty: String,
+    pub meter: u16,
+    pub credit: String,
+    pub note_count: usize,
+}
+
+/// A single song of the catalog
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct StepmaniaCatalogEntry {
+    /// The simfile the song has been read from
+    pub path: PathBuf,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub charts: Vec<StepmaniaCatalogChart>,
+    pub min_bpm: Option<f64>,
+    pub max_bpm: Option<f64>,
+    /// Time of the last note in ms
+    pub length: Option<i64>,
+    /// The errors found while reading the file
+    pub errors: Vec<String>,
+}
+
+/// A single row of the CSV output, with the charts joined into single columns
+#[derive(Debug, Serialize)]
+struct StepmaniaCatalogRow<'a> {
+    path: String,
+    title: &'a str,
+    artist: &'a str,
+    charts: String,
+    meters: String,
+    min_bpm: Option<f64>,
+    max_bpm: Option<f64>,
+    length: Option<f64>,
+    errors: usize,
+}
+
+/// All songs found in a directory of song packs
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct StepmaniaCatalog {
+    pub songs: Vec<StepmaniaCatalogEntry>,
+}
+
+impl StepmaniaCatalog {
+    /// Finds the preferred simfile of every song folder in the directory and all
+    /// of its subdirectories, and reads them in parallel.
+    /// With `skip_notes`, only the metadata and the chart headers are read, which is much faster
+    /// for large files. The note counts are 0 and the lengths are unknown then.
+    pub fn scan(dir: &Path, skip_notes: bool) -> Result<StepmaniaCatalog> {
+        if !dir.is_dir() {
+            bail!("not a directory: {}", dir.display());
+        }
+
+        let mut files = vec![];
+        find_simfiles(dir, &mut files)?;
+        files.sort();
+
+        let songs = files
+            .par_iter()
+            .map(|file| StepmaniaCatalogEntry::from_file(file, skip_notes))
+            .collect();
+
+        Ok(StepmaniaCatalog { songs })
+    }
+
+    /// Writes the catalog as CSV, with one row per song
+    pub fn to_csv(&self) -> Result<String> {
+        let mut writer = csv::Writer::from_writer(vec![]);
+        for song in self.songs.iter() {
+            writer.serialize(StepmaniaCatalogRow {
+                path: song.path.display().to_string(),
+                title: song.title.as_deref().unwrap_or_default(),
+                artist: song.artist.as_deref().unwrap_or_default(),
+                charts: song
+                    .charts
+                    .iter()
+                    .map(|chart| format!("{} {}", chart.step_style, chart.difficulty))
+                    .collect::<Vec<String>>()
+                    .join(";"),
+                meters: song
+                    .charts
+                    .iter()
+                    .map(|chart| chart.meter.to_string())
+                    .collect::<Vec<String>>()
+                    .join(";"),
+                min_bpm: song.min_bpm,
+                max_bpm: song.max_bpm,
+                length: song.length.map(|length| length as f64 / 1000.0),
+                errors: song.errors.len(),
+            })?;
+        }
+
+        Ok(String::from_utf8(writer.into_inner()?)?)
+    }
+}
+
+impl StepmaniaCatalogEntry {
+    /// Reads the simfile. Files which can't be read result in an entry with only errors.
+    pub fn from_file(path: &Path, skip_notes: bool) -> StepmaniaCatalogEntry {
+        let result = match skip_notes {
+            true => read_song_info(path)
+                .map(|(info, errors)| (StepmaniaCatalogEntry::from_song_info(path, &info), errors)),
+            false => read_simfile(path)
+                .map(|(step, errors)| (StepmaniaCatalogEntry::from_step(path, &step), errors)),
+        };
+
+        match result {
+            Ok((mut entry, errors)) => {
+                entry.errors = errors
+                    .iter()
+                    .map(|error| format!("{:?} at {}:{}", error.code, error.line, error.column))
+                    .collect();
+                entry
+            }
+            Err(err) => StepmaniaCatalogEntry {
+                path: path.to_path_buf(),
+                errors: vec![err.to_string()],
+                ..Default::default()
+            },
+        }
+    }
+
+    /// The entry of a song of which only the metadata and the chart headers are known.
+    /// The note counts are 0 and the length is unknown.
+    pub fn from_song_info(path: &Path, info: &StepmaniaSongInfo) -> StepmaniaCatalogEntry {
+        StepmaniaCatalogEntry {
+            path: path.to_path_buf(),
+            title: info.title.clone(),
+            artist: info.artist.clone(),
+            charts: info
+                .charts
+                .iter()
+                .map(|chart| StepmaniaCatalogChart {
+                    step_style: chart.step_style.name().to_string(),
+                    difficulty: chart.difficulty.name().to_string(),
+                    meter: chart.meter,
+                    credit: chart.credit.clone(),
+                    note_count: 0,
+                })
+                .collect(),
+            min_bpm: info.min_bpm.map(|bpm| bpm as f64 / 1000.0),
+            max_bpm: info.max_bpm.map(|bpm| bpm as f64 / 1000.0),
+            length: None,
+            errors: vec![],
+        }
+    }
+
+    pub fn from_step(path: &Path, step: &StepmaniaFile) -> StepmaniaCatalogEntry {
+        let mut entry =
+            StepmaniaCatalogEntry::from_song_info(path, &StepmaniaSongInfo::from_step(step));
+        for (catalog_chart, chart) in entry.charts.iter_mut().zip(step.notes.iter()) {
+            catalog_chart.note_count = chart.data.positioned_notes().len();
+        }
+
+        let last_beat = step
+            .notes
+            .iter()
+            .filter_map(|chart| chart.data.positioned_notes().last().map(|note| note.beat()))
+            .reduce(f64::max);
+        entry.length = last_beat.map(|beat| step.beat_to_ms(beat).round() as i64);
+        entry
+    }
+}
+
+fn read_simfile(path: &Path) -> Result<(StepmaniaFile, Vec<ParseError>)> {
+    let content = fs::read_to_string(path)?;
+    if extension_of(path) == "dwi" {
+        let mut parser = DwiParser::new();
+        let step = parser.parse_from_string(&content)?;
+        Ok((step, parser.errors))
+    } else {
+        let mut parser = StepmaniaParser::new();
+        let step = parser.parse_from_string(&content)?;
+        Ok((step, parser.errors))
+    }
+}
+
+/// Reads only the metadata and the chart headers. DWI files are always read completely.
+fn read_song_info(path: &Path) -> Result<(StepmaniaSongInfo, Vec<ParseError>)> {
+    if extension_of(path) == "dwi" {
+        let (step, errors) = read_simfile(path)?;
+        return Ok((StepmaniaSongInfo::from_step(&step), errors));
+    }
+
+    let content = fs::read_to_string(path)?;
+    let mut parser = StepmaniaParser::new();
+    let info = parser.parse_song_info(&content)?;
+    Ok((info, parser.errors))
+}
+
+fn extension_of(path: &Path) -> String {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+/// Adds the preferred simfile of the directory, and of all its subdirectories
+fn find_simfiles(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut preferred: Option<(usize, PathBuf)> = None;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        // Linked directories are skipped, as they might link back to the pack
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        if file_type.is_dir() {
+            find_simfiles(&path, files)?;
+            continue;
+        }
+        if file_type.is_symlink() && path.is_dir() {
+            continue;
+        }
+
+        let extension = extension_of(&path);
+        let Some(priority) = SIMFILE_EXTENSIONS.iter().position(|ext| *ext == extension) else {
+            continue;
+        };
+        let is_preferred = match &preferred {
+            Some((current, current_path)) => {
+                priority < *current || (priority == *current && path < *current_path)
+            }
+            None => true,
+        };
+        if is_preferred {
+            preferred = Some((priority, path));
+        }
+    }
+
+    if let Some((_, path)) = preferred {
+        files.push(path);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIMFILE: &str = "
+#TITLE:Song;
+#ARTIST:Artist;
+#BPMS:0=120,4=-60,8=180;
+#NOTES:
+     dance-single:
+     :
+     Easy:
+     3:
+     0,0,0,0,0:
+1000
+0100
+0010
+0001
+;
+";
+
+    #[test]
+    fn it_should_scan_packs() {
+        let dir = std::env::temp_dir().join(format!("rconv-catalog-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let files = [
+            ("Pack/A/a.sm", SIMFILE),
+            ("Pack/A/a.ssc", SIMFILE),
+            ("Pack/A/a.dwi", ""),
+            ("Pack/B/b.dwi", "#TITLE:Other;\n#BPM:150;\n"),
+            ("Pack/B/b.ogg", ""),
+        ];
+        for (file, content) in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        // A link back to the pack isn't followed
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&dir, dir.join("Pack/B/loop")).unwrap();
+
+        let catalog = StepmaniaCatalog::scan(&dir, false).unwrap();
+        assert_eq!(catalog.songs.len(), 2);
+
+        let song = &catalog.songs[0];
+        assert_eq!(song.path, dir.join("Pack/A/a.ssc"));
+        assert_eq!(song.title, Some("Song".to_string()));
+        assert_eq!(song.charts.len(), 1);
+        assert_eq!(song.charts[0].meter, 3);
+        assert_eq!(song.charts[0].note_count, 4);
+        assert_eq!(song.min_bpm, Some(120.0));
+        assert_eq!(song.max_bpm, Some(180.0));
+        assert_eq!(song.length, Some(1500));
+
+        assert_eq!(catalog.songs[1].path, dir.join("Pack/B/b.dwi"));
+        assert_eq!(catalog.songs[1].title, Some("Other".to_string()));
+
+        let csv = catalog.to_csv().unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            "path,title,artist,charts,meters,min_bpm,max_bpm,length,errors"
+        );
+        assert!(lines[1].ends_with(",Song,Artist,dance-single Easy,3,120.0,180.0,1.5,0"));
+
+        let catalog = StepmaniaCatalog::scan(&dir, true).unwrap();
+        let song = &catalog.songs[0];
+        assert_eq!(song.charts[0].meter, 3);
+        assert_eq!(song.charts[0].note_count, 0);
+        assert_eq!(song.length, None);
+        assert_eq!((song.min_bpm, song.max_bpm), (Some(120.0), Some(180.0)));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
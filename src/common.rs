@@ -32,12 +32,174 @@ pub enum ParseErrorCode {
     StepmaniaInvalidAttackValueOrder,
     /// When the value of an attack modifier is invalid.
     StepmaniaInvalidModifierValue,
+    /// When a row of the note-data doesn't have as many notes as the chart has columns
+    StepmaniaInvalidRowLength,
+    /// When a chart has its own timing (split timing), which is ignored in favor of the timing of the song
+    StepmaniaUnsupportedSplitTiming,
+    /// When the file is not valid JSON or doesn't match the Malody structure
+    MalodyInvalidJson,
+    /// When the chart is not a key-mode chart
+    MalodyUnsupportedMode,
+    /// When a beat triplet is invalid (i.E. has a denominator of 0)
+    MalodyInvalidBeat,
+    /// When a note is placed outside of the available columns
+    MalodyInvalidColumn,
+    /// When the file is not valid YAML or doesn't match the Quaver structure
+    QuaverInvalidYaml,
+    /// When the map uses a mode other than 4K and 7K
+    QuaverUnsupportedMode,
+    /// When a timing point has no positive BPM
+    QuaverInvalidTimingPoint,
+    /// When a hit-object is placed in a lane which doesn't exist
+    QuaverInvalidLane,
+    /// When there's content which isn't associated with any property.
+    DwiExpectedPropertyStart,
+    /// When a property-end ";" was expected, but hasn't been found.
+    DwiExpectedValueEnd,
+    /// When the property-value is an invalid number
+    DwiInvalidNumber,
+    /// When a step character is unknown or not available on the pad
+    DwiInvalidStep,
+    /// When a chart doesn't have the difficulty, meter and steps for each pad
+    DwiInvalidValueCount,
+    /// When a header or option value is an invalid number
+    KshInvalidNumber,
+    /// When a line is neither an option nor a valid chart line
+    KshInvalidChartLine,
+    /// When a value could not be parsed as number
+    DtxInvalidNumber,
+    /// When the data of an object command is not made up of id pairs
+    DtxInvalidObjectData,
+    /// When a chip references a WAV which has not been defined
+    DtxUndefinedWav,
+    /// When multiple chips are placed in the same column at the same time
+    DtxOverlappingChips,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ParseError {
     pub code: ParseErrorCode,
     pub line: usize,
     pub column: usize,
     pub len: usize,
+    /// The exact range in the source, for formats which track it
+    pub span: Option<Span>,
+}
+
+/// A range of the source in byte offsets, the end is exclusive
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    /// The span of `part`, which has to be a slice of `source`
+    pub fn of(source: &str, part: &str) -> Span {
+        let start = part.as_ptr() as usize - source.as_ptr() as usize;
+        debug_assert!(start + part.len() <= source.len());
+        Span::new(start, start + part.len())
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// The smallest span containing both spans
+    pub fn merge(&self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+
+    /// The part of the source the span covers
+    pub fn slice<'a>(&self, source: &'a str) -> &'a str {
+        &source[self.start..self.end]
+    }
+
+    /// The line and column of the start. Use a `LineIndex` when converting many spans of the same source.
+    pub fn line_column(&self, source: &str) -> (usize, usize) {
+        LineIndex::new(source).line_column(self.start)
+    }
+}
+
+/// The offsets of all lines of a source, to convert byte offsets to lines and columns
+#[derive(Debug)]
+pub struct LineIndex<'a> {
+    source: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub fn new(source: &'a str) -> LineIndex<'a> {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(idx, _)| idx + 1))
+            .collect();
+        LineIndex {
+            source,
+            line_starts,
+        }
+    }
+
+    /// The line and column of the offset, both starting at 1 like the ones of `ParseError`.
+    /// Columns are counted in characters, not bytes.
+    pub fn line_column(&self, offset: usize) -> (usize, usize) {
+        let line = self.line_starts.partition_point(|start| *start <= offset) - 1;
+        let line_start = self.line_starts[line];
+        let column = self.source[line_start..offset.min(self.source.len())]
+            .chars()
+            .count();
+
+        (line + 1, column + 1)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy)]
+pub enum ConversionWarningCode {
+    /// When the target format has no warps, and the warped section is kept as is.
+    UnsupportedWarp,
+    /// When the target format has no fake sections, and the fake notes are written as regular notes.
+    UnsupportedFake,
+    /// When a note type doesn't exist in the target format, and the note is dropped.
+    UnsupportedNoteType,
+    /// When the target format has no stops/delays. The timing of notes is kept, but not the visual pause.
+    ApproximatedStop,
+    /// When a speed change is applied instantly instead of over the specified duration.
+    ApproximatedSpeedChange,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionWarning {
+    pub code: ConversionWarningCode,
+    /// At which beat the feature is used
+    pub beat: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_convert_offsets_to_lines_and_columns() {
+        let source = "#TITLE:ゾンビー;\n#ARTIST:かめりあ;\n";
+        let index = LineIndex::new(source);
+
+        let title = Span::new(7, 7 + "ゾンビー".len());
+        assert_eq!(title.slice(source), "ゾンビー");
+        assert_eq!(index.line_column(title.start), (1, 8));
+        assert_eq!(index.line_column(title.end), (1, 12));
+
+        let artist = Span::of(source, &source[source.find("かめりあ").unwrap()..][..12]);
+        assert_eq!(artist.slice(source), "かめりあ");
+        assert_eq!(artist.line_column(source), (2, 9));
+        assert_eq!(index.line_column(source.len()), (3, 1));
+    }
 }
@@ -0,0 +1,677 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Serialize;
+
+use super::stepmania::*;
+
+/// A value which differs between the files, or which is only set in one of them
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepmaniaValueChange {
+    /// The lowercase property name, like "title" or "meter"
+    pub property: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// A timing change which has been added, removed or edited
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepmaniaTimingChange {
+    pub beat: f64,
+    /// The BPM, or the duration of the stop in seconds
+    pub old: Option<f64>,
+    pub new: Option<f64>,
+}
+
+/// An entry of another timed list which has been added, removed or edited
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepmaniaTimedValueChange {
+    /// The lowercase property name of the list, like "delays" or "timesignatures"
+    pub property: String,
+    pub beat: f64,
+    /// The values of the entry without its beat, like they are written in the simfile
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// A note which only exists in one of the files
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepmaniaNoteChange {
+    pub beat: f64,
+    pub column: u8,
+    pub note_type: String,
+}
+
+/// A note which has been moved to another column at the same beat,
+/// or to a beat at most one beat away in the same column
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepmaniaNoteMove {
+    pub from_beat: f64,
+    pub from_column: u8,
+    pub to_beat: f64,
+    pub to_column: u8,
+    pub note_type: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StepmaniaChartStatus {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// The differences of a chart, which is matched by its step-style and difficulty
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepmaniaChartDiff {
+    /// Like "dance-single Hard". Further charts of the same kind are numbered, like "dance-single Edit #2".
+    pub chart: String,
+    pub status: StepmaniaChartStatus,
+    pub changes: Vec<StepmaniaValueChange>,
+    pub added_notes: Vec<StepmaniaNoteChange>,
+    pub removed_notes: Vec<StepmaniaNoteChange>,
+    pub moved_notes: Vec<StepmaniaNoteMove>,
+}
+
+/// The differences in the content of two files. Their formatting and the spans are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct StepmaniaDiff {
+    pub metadata: Vec<StepmaniaValueChange>,
+    pub bpms: Vec<StepmaniaTimingChange>,
+    pub stops: Vec<StepmaniaTimingChange>,
+    /// The changes of the other timed lists, like delays, warps or time signatures
+    pub timing: Vec<StepmaniaTimedValueChange>,
+    /// If the files differ in content which isn't listed, like background changes or keysounds
+    pub other_changes: bool,
+    /// Only the charts which have been added, removed or changed
+    pub charts: Vec<StepmaniaChartDiff>,
+}
+
+/// The metadata which is compared, by its property name
+fn metadata(step: &StepmaniaFile) -> Vec<(&'static str, Option<String>)> {
+    let display_bpm = step.display_bpm.as_ref().map(|range| match range {
+        StepmaniaBPMRange::Single(bpm) => format_thousandths(*bpm),
+        StepmaniaBPMRange::Range(min, max) => {
+            format!("{}:{}", format_thousandths(*min), format_thousandths(*max))
+        }
+        StepmaniaBPMRange::Random => "*".to_string(),
+    });
+
+    vec![
+        ("title", step.title.clone()),
+        ("subtitle", step.subtitle.clone()),
+        ("artist", step.artist.clone()),
+        ("titletranslit", step.title_translit.clone()),
+        ("subtitletranslit", step.subtitle_translit.clone()),
+        ("artisttranslit", step.artist_translit.clone()),
+        ("genre", step.genre.clone()),
+        ("credit", step.credit.clone()),
+        ("banner", step.banner.clone()),
+        ("background", step.background.clone()),
+        ("cdtitle", step.cd_title.clone()),
+        ("jacket", step.jacket.clone()),
+        ("music", step.music.clone()),
+        ("offset", step.offset.map(format_thousandths)),
+        ("samplestart", step.sample_start.map(format_thousandths)),
+        ("samplelength", step.sample_length.map(format_thousandths)),
+        ("displaybpm", display_bpm),
+        ("attacks", format_attacks(&step.attacks)),
+    ]
+}
+
+/// The entries of the other timed lists by their property name, with the values joined like in the simfile
+fn timed_lists(step: &StepmaniaFile) -> Vec<(&'static str, Vec<(i64, String)>)> {
+    let join = |values: &[String]| values.join("=");
+    vec![
+        (
+            "delays",
+            step.delays
+                .iter()
+                .map(|delay| (delay.beat, format_thousandths(delay.duration)))
+                .collect(),
+        ),
+        (
+            "warps",
+            step.warps
+                .iter()
+                .map(|warp| (warp.beat, format_thousandths(warp.end_beat - warp.beat)))
+                .collect(),
+        ),
+        (
+            "timesignatures",
+            step.time_signatures
+                .iter()
+                .map(|sig| {
+                    let values = [sig.numerator.to_string(), sig.denominator.to_string()];
+                    (sig.beat, join(&values))
+                })
+                .collect(),
+        ),
+        (
+            "tickcounts",
+            step.tick_counts
+                .iter()
+                .map(|tick| (tick.beat, tick.value.to_string()))
+                .collect(),
+        ),
+        (
+            "combos",
+            step.combos
+                .iter()
+                .map(|combo| {
+                    let values = [combo.hit.to_string(), combo.miss.to_string()];
+                    (combo.beat, join(&values))
+                })
+                .collect(),
+        ),
+        (
+            "speeds",
+            step.speeds
+                .iter()
+                .map(|speed| {
+                    let values = [
+                        format!("{:.3}", speed.ratio),
+                        format_thousandths(speed.duration),
+                        (speed.in_seconds as u8).to_string(),
+                    ];
+                    (speed.beat, join(&values))
+                })
+                .collect(),
+        ),
+        (
+            "scrolls",
+            step.scrolls
+                .iter()
+                .map(|scroll| (scroll.beat, format!("{:.3}", scroll.factor)))
+                .collect(),
+        ),
+        (
+            "fakes",
+            step.fakes
+                .iter()
+                .map(|fake| (fake.beat, format_thousandths(fake.duration)))
+                .collect(),
+        ),
+        (
+            "labels",
+            step.labels
+                .iter()
+                .map(|label| (label.beat, label.label.clone()))
+                .collect(),
+        ),
+    ]
+}
+
+/// The file without the content which is listed in the diff, to detect any other changes
+fn unlisted_content(step: &StepmaniaFile) -> StepmaniaFile {
+    let mut rest = step.clone();
+    rest.title = None;
+    rest.subtitle = None;
+    rest.artist = None;
+    rest.title_translit = None;
+    rest.subtitle_translit = None;
+    rest.artist_translit = None;
+    rest.genre = None;
+    rest.credit = None;
+    rest.banner = None;
+    rest.background = None;
+    rest.cd_title = None;
+    rest.jacket = None;
+    rest.music = None;
+    rest.offset = None;
+    rest.sample_start = None;
+    rest.sample_length = None;
+    rest.display_bpm = None;
+    rest.attacks.clear();
+    rest.bpms.clear();
+    rest.stops.clear();
+    rest.delays.clear();
+    rest.warps.clear();
+    rest.time_signatures.clear();
+    rest.tick_counts.clear();
+    rest.combos.clear();
+    rest.speeds.clear();
+    rest.scrolls.clear();
+    rest.fakes.clear();
+    rest.labels.clear();
+    rest.notes.clear();
+    rest
+}
+
+/// The highest distance in beats a note may be moved by in the same column
+const MAX_MOVE_DISTANCE: f64 = 1.0;
+
+impl StepmaniaDiff {
+    pub fn between(old: &StepmaniaFile, new: &StepmaniaFile) -> StepmaniaDiff {
+        let metadata = metadata(old)
+            .into_iter()
+            .zip(metadata(new))
+            .filter_map(|((property, old), (_, new))| value_change(property, old, new))
+            .collect();
+
+        let bpms = timing_changes(
+            old.bpms.iter().map(|bpm| (bpm.beat, bpm.bpm)),
+            new.bpms.iter().map(|bpm| (bpm.beat, bpm.bpm)),
+        );
+        let stops = timing_changes(
+            old.stops.iter().map(|stop| (stop.beat, stop.duration)),
+            new.stops.iter().map(|stop| (stop.beat, stop.duration)),
+        );
+        let timing = timed_lists(old)
+            .into_iter()
+            .zip(timed_lists(new))
+            .flat_map(|((property, old), (_, new))| {
+                changes_by_beat(old, new)
+                    .into_iter()
+                    .map(move |(beat, old, new)| StepmaniaTimedValueChange {
+                        property: property.to_string(),
+                        beat: beat as f64 / 1000.0,
+                        old,
+                        new,
+                    })
+            })
+            .collect();
+        let other_changes = unlisted_content(old) != unlisted_content(new);
+
+        let old_charts = labeled_charts(old);
+        let mut new_charts = labeled_charts(new);
+        let mut charts = vec![];
+        for (label, old_chart) in old_charts {
+            let diff = match new_charts.iter().position(|(other, _)| *other == label) {
+                Some(idx) => chart_diff(label, old_chart, new_charts.remove(idx).1),
+                None => StepmaniaChartDiff {
+                    status: StepmaniaChartStatus::Removed,
+                    ..empty_chart_diff(label)
+                },
+            };
+            if diff.status != StepmaniaChartStatus::Changed || !diff.is_empty() {
+                charts.push(diff);
+            }
+        }
+        for (label, _) in new_charts {
+            charts.push(StepmaniaChartDiff {
+                status: StepmaniaChartStatus::Added,
+                ..empty_chart_diff(label)
+            });
+        }
+
+        StepmaniaDiff {
+            metadata,
+            bpms,
+            stops,
+            timing,
+            other_changes,
+            charts,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.metadata.is_empty()
+            && self.bpms.is_empty()
+            && self.stops.is_empty()
+            && self.timing.is_empty()
+            && !self.other_changes
+            && self.charts.is_empty()
+    }
+}
+
+impl StepmaniaChartDiff {
+    /// If nothing of the chart has changed
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+            && self.added_notes.is_empty()
+            && self.removed_notes.is_empty()
+            && self.moved_notes.is_empty()
+    }
+}
+
+/// Formats thousandths as decimal, like the values of simfiles are written
+fn format_thousandths(value: i64) -> String {
+    format!("{:.3}", value as f64 / 1000.0)
+}
+
+fn value_change(
+    property: &str,
+    old: Option<String>,
+    new: Option<String>,
+) -> Option<StepmaniaValueChange> {
+    (old != new).then(|| StepmaniaValueChange {
+        property: property.to_string(),
+        old,
+        new,
+    })
+}
+
+/// Formats the attacks like the ATTACKS property, or `None` if there are none
+fn format_attacks(attacks: &[StepmaniaAttack]) -> Option<String> {
+    (!attacks.is_empty()).then(|| {
+        attacks
+            .iter()
+            .map(format_attack)
+            .collect::<Vec<String>>()
+            .join(":")
+    })
+}
+
+/// Compares two lists of `(beat, value)` with the beat in thousandths.
+/// Of multiple values at the same beat, the last one is used.
+fn changes_by_beat<T: PartialEq>(
+    old: impl IntoIterator<Item = (i64, T)>,
+    new: impl IntoIterator<Item = (i64, T)>,
+) -> Vec<(i64, Option<T>, Option<T>)> {
+    let mut old: BTreeMap<i64, T> = old.into_iter().collect();
+    let mut new: BTreeMap<i64, T> = new.into_iter().collect();
+    let mut beats: Vec<i64> = old.keys().chain(new.keys()).copied().collect();
+    beats.sort_unstable();
+    beats.dedup();
+
+    beats
+        .into_iter()
+        .map(|beat| (beat, old.remove(&beat), new.remove(&beat)))
+        .filter(|(_, old, new)| old != new)
+        .collect()
+}
+
+/// Compares two lists of `(beat, value)` in thousandths
+fn timing_changes(
+    old: impl Iterator<Item = (i64, i64)>,
+    new: impl Iterator<Item = (i64, i64)>,
+) -> Vec<StepmaniaTimingChange> {
+    changes_by_beat(old, new)
+        .into_iter()
+        .map(|(beat, old, new)| StepmaniaTimingChange {
+            beat: beat as f64 / 1000.0,
+            old: old.map(|value| value as f64 / 1000.0),
+            new: new.map(|value| value as f64 / 1000.0),
+        })
+        .collect()
+}
+
+/// The charts with their labels, which are unique within the file
+fn labeled_charts(step: &StepmaniaFile) -> Vec<(String, &StepmaniaChart)> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    step.notes
+        .iter()
+        .map(|chart| {
+            let label = format!("{} {}", chart.step_style.name(), chart.difficulty.name());
+            let count = counts.entry(label.clone()).or_default();
+            *count += 1;
+            match *count {
+                1 => (label, chart),
+                count => (format!("{} #{}", label, count), chart),
+            }
+        })
+        .collect()
+}
+
+fn empty_chart_diff(chart: String) -> StepmaniaChartDiff {
+    StepmaniaChartDiff {
+        chart,
+        status: StepmaniaChartStatus::Changed,
+        changes: vec![],
+        added_notes: vec![],
+        removed_notes: vec![],
+        moved_notes: vec![],
+    }
+}
+
+fn chart_diff(label: String, old: &StepmaniaChart, new: &StepmaniaChart) -> StepmaniaChartDiff {
+    let mut diff = empty_chart_diff(label);
+    diff.changes = [
+        value_change("chartname", old.name.clone(), new.name.clone()),
+        value_change(
+            "chartstyle",
+            old.chart_style.clone(),
+            new.chart_style.clone(),
+        ),
+        value_change("credit", Some(old.credit.clone()), Some(new.credit.clone())),
+        value_change(
+            "meter",
+            Some(old.meter.to_string()),
+            Some(new.meter.to_string()),
+        ),
+        value_change(
+            "radarvalues",
+            Some(format_radar_values(&old.radar_values)),
+            Some(format_radar_values(&new.radar_values)),
+        ),
+        value_change(
+            "attacks",
+            format_attacks(&old.attacks),
+            format_attacks(&new.attacks),
+        ),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    // The notes by their exact position, so different row counts of a measure don't matter
+    let old_notes = notes_by_position(&old.data);
+    let new_notes = notes_by_position(&new.data);
+    let mut removed = changed_notes(&old_notes, &new_notes);
+    let mut added = changed_notes(&new_notes, &old_notes);
+
+    // Pair the removed notes with the closest added note they could have been moved to
+    let mut is_moved = vec![false; added.len()];
+    removed.retain(|note| {
+        let first = added.partition_point(|other| other.beat < note.beat - MAX_MOVE_DISTANCE);
+        let target = (first..added.len())
+            .take_while(|idx| added[*idx].beat <= note.beat + MAX_MOVE_DISTANCE)
+            .filter(|idx| !is_moved[*idx] && added[*idx].note_type == note.note_type)
+            .filter(|idx| added[*idx].beat == note.beat || added[*idx].column == note.column)
+            .min_by(|a, b| {
+                let distance = |idx: usize| {
+                    let other = &added[idx];
+                    (
+                        (other.beat - note.beat).abs(),
+                        other.column.abs_diff(note.column),
+                    )
+                };
+                distance(*a).partial_cmp(&distance(*b)).unwrap()
+            });
+
+        let Some(idx) = target else {
+            return true;
+        };
+        is_moved[idx] = true;
+        diff.moved_notes.push(StepmaniaNoteMove {
+            from_beat: note.beat,
+            from_column: note.column,
+            to_beat: added[idx].beat,
+            to_column: added[idx].column,
+            note_type: note.note_type.clone(),
+        });
+        false
+    });
+    let mut is_moved = is_moved.into_iter();
+    added.retain(|_| !is_moved.next().unwrap_or(false));
+
+    diff.added_notes = added;
+    diff.removed_notes = removed;
+    diff
+}
+
+/// The types of the notes by their beat as exact fraction and their column
+fn notes_by_position(
+    data: &StepmaniaNoteData,
+) -> HashMap<(usize, usize, usize, u8), StepmaniaPositionedNote> {
+    data.positioned_notes()
+        .into_iter()
+        .map(|note| {
+            let (beat, numerator, denominator) = note.beat_fraction();
+            ((beat, numerator, denominator, note.column), note)
+        })
+        .collect()
+}
+
+/// The notes which don't exist with the same type in `other`, ordered by beat and column
+fn changed_notes(
+    notes: &HashMap<(usize, usize, usize, u8), StepmaniaPositionedNote>,
+    other: &HashMap<(usize, usize, usize, u8), StepmaniaPositionedNote>,
+) -> Vec<StepmaniaNoteChange> {
+    let mut changed: Vec<StepmaniaNoteChange> = notes
+        .iter()
+        .filter(|(position, note)| {
+            other.get(*position).map(|other| other.note_type) != Some(note.note_type)
+        })
+        .map(|(_, note)| StepmaniaNoteChange {
+            beat: note.beat(),
+            column: note.column,
+            note_type: format!("{:?}", note.note_type),
+        })
+        .collect();
+    changed.sort_by(|a, b| a.beat.total_cmp(&b.beat).then(a.column.cmp(&b.column)));
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &str = "
+#TITLE:Song;
+#BPMS:0=120,4=180;
+#STOPS:2=0.5;
+#NOTES:
+     dance-single:
+     Someone:
+     Hard:
+     9:
+     0,0,0,0,0:
+1000
+0100
+0010
+0001
+;
+";
+
+    fn parse(data: &str) -> StepmaniaFile {
+        StepmaniaParser::new().parse_from_string(data).unwrap()
+    }
+
+    #[test]
+    fn it_should_compare_parsed_files() {
+        let step = parse(OLD);
+        assert_eq!(step, parse(OLD));
+        assert_eq!(step.clone(), step);
+        // The spans are not compared
+        assert_eq!(step, parse(&format!("\n{}", OLD)));
+        let written = StepmaniaWriter::new().write_to_string(&step).unwrap();
+        assert_eq!(step, parse(&written));
+
+        // The same notes in 8ths instead of 4ths, with different formatting
+        let reformatted = OLD.replace("#TITLE:Song;", "#TITLE:  Song ;").replace(
+            "1000\n0100\n0010\n0001",
+            "1000\n0000\n0100\n0000\n0010\n0000\n0001\n0000",
+        );
+        let other = parse(&reformatted);
+        assert_ne!(step, other);
+        assert!(StepmaniaDiff::between(&step, &other).is_empty());
+    }
+
+    #[test]
+    fn it_should_report_semantic_differences() {
+        let new = OLD
+            .replace("#TITLE:Song;", "#TITLE:Song (Remix);")
+            .replace("4=180", "4=200,8=90")
+            .replace("#STOPS:2=0.5;", "#STOPS:;")
+            .replace("     9:", "     10:")
+            .replace("1000\n0100\n0010\n0001", "0001\n0000\n0010\n0100")
+            + "#NOTES:\n     dance-double:\n     :\n     Easy:\n     3:\n     0,0,0,0,0:\n10000000\n;\n";
+        let diff = StepmaniaDiff::between(&parse(OLD), &parse(&new));
+
+        assert_eq!(
+            diff.metadata,
+            vec![StepmaniaValueChange {
+                property: "title".to_string(),
+                old: Some("Song".to_string()),
+                new: Some("Song (Remix)".to_string()),
+            }]
+        );
+        let bpms: Vec<(f64, Option<f64>, Option<f64>)> = diff
+            .bpms
+            .iter()
+            .map(|change| (change.beat, change.old, change.new))
+            .collect();
+        assert_eq!(
+            bpms,
+            vec![(4.0, Some(180.0), Some(200.0)), (8.0, None, Some(90.0))]
+        );
+        assert_eq!(diff.stops.len(), 1);
+        assert_eq!(diff.stops[0].old, Some(0.5));
+
+        assert_eq!(diff.charts.len(), 2);
+        let chart = &diff.charts[0];
+        assert_eq!(chart.chart, "dance-single Hard");
+        assert_eq!(chart.status, StepmaniaChartStatus::Changed);
+        assert_eq!(chart.changes[0].property, "meter");
+        // The taps on beats 0 and 3 have moved to other columns, and the one on beat 1 is gone
+        let moves: Vec<(f64, u8, f64, u8)> = chart
+            .moved_notes
+            .iter()
+            .map(|note| {
+                (
+                    note.from_beat,
+                    note.from_column,
+                    note.to_beat,
+                    note.to_column,
+                )
+            })
+            .collect();
+        assert_eq!(moves, vec![(0.0, 0, 0.0, 3), (3.0, 3, 3.0, 1)]);
+        assert_eq!(chart.removed_notes.len(), 1);
+        assert_eq!(
+            (chart.removed_notes[0].beat, chart.removed_notes[0].column),
+            (1.0, 1)
+        );
+        assert!(chart.added_notes.is_empty());
+
+        assert_eq!(diff.charts[1].chart, "dance-double Easy");
+        assert_eq!(diff.charts[1].status, StepmaniaChartStatus::Added);
+    }
+
+    #[test]
+    fn it_should_report_changes_of_the_other_timing_and_chart_values() {
+        let old = OLD.replace(
+            "#STOPS:2=0.5;",
+            "#STOPS:2=0.5;\n#DELAYS:1=0.25;\n#TIMESIGNATURES:0=4=4;\n#KEYSOUNDS:kick.wav;",
+        );
+        let new = old
+            .replace("#DELAYS:1=0.25;", "#DELAYS:1=0.5;\n#WARPS:3=1;")
+            .replace("0=4=4", "0=3=4")
+            .replace("kick.wav", "snare.wav");
+        let mut new = parse(&new);
+        new.notes[0].radar_values.stream = 0.5;
+        let diff = StepmaniaDiff::between(&parse(&old), &new);
+
+        let timing: Vec<(&str, f64, Option<&str>, Option<&str>)> = diff
+            .timing
+            .iter()
+            .map(|change| {
+                (
+                    change.property.as_str(),
+                    change.beat,
+                    change.old.as_deref(),
+                    change.new.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            timing,
+            vec![
+                ("delays", 1.0, Some("0.250"), Some("0.500")),
+                ("warps", 3.0, None, Some("1.000")),
+                ("timesignatures", 0.0, Some("4=4"), Some("3=4")),
+            ]
+        );
+        // The keysounds aren't listed, but still reported as change
+        assert!(diff.other_changes);
+
+        assert_eq!(diff.charts.len(), 1);
+        assert_eq!(diff.charts[0].changes.len(), 1);
+        assert_eq!(diff.charts[0].changes[0].property, "radarvalues");
+        assert_eq!(
+            diff.charts[0].changes[0].new.as_deref(),
+            Some("0.500,0.000,0.000,0.000,0.000")
+        );
+    }
+}
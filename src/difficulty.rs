@@ -0,0 +1,192 @@
+use serde::Serialize;
+
+use super::analysis::*;
+use super::patterns::*;
+use super::stepmania::*;
+
+/// How far the declared meter may be off before it's flagged
+const DEVIATION_THRESHOLD: i32 = 3;
+/// Which part of the busiest seconds is used as the sustained density
+const SUSTAINED_PERCENTILE: f64 = 0.8;
+/// The density factor is `DENSITY_SCALE * nps ^ DENSITY_EXPONENT`.
+/// Tuned so that 16ths at 150 BPM (10 NPS) are a 9, and at 200 BPM (13.3 NPS) a 14.
+const DENSITY_SCALE: f64 = 0.26;
+const DENSITY_EXPONENT: f64 = 1.54;
+/// The most each of the secondary factors may add to the meter
+const MAX_STAMINA: f64 = 3.0;
+const MAX_TECHNIQUE: f64 = 3.0;
+const MAX_TIMING: f64 = 2.0;
+const MAX_METER: f64 = 30.0;
+
+/// A single factor which contributed to the estimated meter
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepmaniaMeterFactor {
+    pub name: String,
+    /// The measured value the factor is based on
+    pub value: f64,
+    /// How much the factor adds to the meter
+    pub contribution: f64,
+}
+
+/// A suggested meter for a chart, with the factors it's based on
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepmaniaMeterEstimate {
+    /// The suggested meter
+    pub meter: u16,
+    /// The unrounded rating the meter is based on
+    pub rating: f64,
+    /// The meter the chart declares
+    pub declared_meter: u16,
+    /// If the declared meter deviates strongly from the suggested one
+    pub flagged: bool,
+    pub factors: Vec<StepmaniaMeterFactor>,
+}
+
+impl StepmaniaMeterEstimate {
+    /// Estimates the meter of the chart from its density, patterns and timing
+    pub fn from_chart(step: &StepmaniaFile, chart: &StepmaniaChart) -> StepmaniaMeterEstimate {
+        let density = StepmaniaDensity::from_chart(step, chart);
+        let patterns = StepmaniaPatterns::from_chart(chart).ok();
+        StepmaniaMeterEstimate::from_analysis(step, chart, &density, patterns.as_ref())
+    }
+
+    /// Estimates the meter from an already done analysis of the chart
+    pub fn from_analysis(
+        step: &StepmaniaFile,
+        chart: &StepmaniaChart,
+        density: &StepmaniaDensity,
+        patterns: Option<&StepmaniaPatterns>,
+    ) -> StepmaniaMeterEstimate {
+        let mut factors = vec![];
+
+        // The density players have to keep up with, ignoring the calmer parts of the song
+        let mut seconds: Vec<f64> = density
+            .graph
+            .iter()
+            .filter(|nps| **nps > 0.0)
+            .copied()
+            .collect();
+        seconds.sort_by(|a, b| a.total_cmp(b));
+        let sustained = match seconds.len() {
+            0 => 0.0,
+            len => seconds[((len - 1) as f64 * SUSTAINED_PERCENTILE).round() as usize],
+        };
+        factors.push(StepmaniaMeterFactor {
+            name: "density".to_string(),
+            value: sustained,
+            contribution: DENSITY_SCALE * sustained.powf(DENSITY_EXPONENT),
+        });
+
+        let stream = density.stream_measures as f64;
+        factors.push(StepmaniaMeterFactor {
+            name: "stamina".to_string(),
+            value: stream,
+            contribution: (1.0 + stream / 16.0).ln().min(MAX_STAMINA),
+        });
+
+        if let Some(patterns) = patterns.filter(|_| density.note_count > 0) {
+            let notes = density.note_count as f64;
+            let technique = (patterns.crossovers + patterns.footswitches + patterns.candles / 2)
+                as f64
+                + patterns.doublesteps as f64 * 1.5
+                + patterns.jacks as f64 / 2.0;
+            let chords = (patterns.hands * 2 + patterns.quads * 3) as f64;
+            let value = (technique + chords) / notes;
+            factors.push(StepmaniaMeterFactor {
+                name: "technique".to_string(),
+                value,
+                contribution: (value * 10.0).min(MAX_TECHNIQUE),
+            });
+        }
+
+        let changes = step.bpms.len().saturating_sub(1)
+            + step.stops.len()
+            + step.delays.len()
+            + step.warps.len()
+            + step.speeds.len()
+            + step.scrolls.len();
+        factors.push(StepmaniaMeterFactor {
+            name: "timing".to_string(),
+            value: changes as f64,
+            contribution: ((1.0 + changes as f64).ln() / 2.0).min(MAX_TIMING),
+        });
+
+        let rating = if density.note_count == 0 {
+            0.0
+        } else {
+            factors
+                .iter()
+                .map(|factor| factor.contribution)
+                .sum::<f64>()
+                .clamp(1.0, MAX_METER)
+        };
+        let meter = rating.round() as u16;
+
+        StepmaniaMeterEstimate {
+            meter,
+            rating,
+            declared_meter: chart.meter,
+            flagged: chart.meter > 0
+                && (chart.meter as i32 - meter as i32).abs() >= DEVIATION_THRESHOLD,
+            factors,
+        }
+    }
+
+    /// The contribution of the factor with the name, or 0 if there is none
+    pub fn contribution(&self, name: &str) -> f64 {
+        self.factors
+            .iter()
+            .find(|factor| factor.name == name)
+            .map_or(0.0, |factor| factor.contribution)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stepstyle::*;
+    use crate::test_utils::*;
+
+    /// A dance-single file with a simple 16th stream (L D U R U D) for the amount of measures
+    fn stream_file(bpm: i64, measures: usize, meter: u16) -> StepmaniaFile {
+        let notes =
+            taps((0..measures * 16).map(|idx| (idx as f64 / 4.0, [0, 1, 2, 3, 2, 1][idx % 6])));
+        let mut step = file_with(bpm, chart_from(StepmaniaStepStyle::DanceSingle, &notes));
+        step.notes[0].meter = meter;
+        step
+    }
+
+    #[test]
+    fn it_should_estimate_stream_charts() {
+        let step = stream_file(150000, 32, 10);
+        let estimate = StepmaniaMeterEstimate::from_chart(&step, &step.notes[0]);
+
+        assert!((estimate.contribution("density") - 9.0).abs() < 0.5);
+        assert!(estimate.contribution("stamina") > 0.5);
+        assert_eq!(estimate.contribution("timing"), 0.0);
+        assert!(estimate.meter >= 9 && estimate.meter <= 12);
+        assert!(!estimate.flagged);
+
+        let faster = stream_file(200000, 32, 10);
+        let faster_estimate = StepmaniaMeterEstimate::from_chart(&faster, &faster.notes[0]);
+        assert!(faster_estimate.meter > estimate.meter);
+    }
+
+    #[test]
+    fn it_should_flag_deviating_meters() {
+        let step = stream_file(180000, 64, 4);
+        let estimate = StepmaniaMeterEstimate::from_chart(&step, &step.notes[0]);
+
+        assert_eq!(estimate.declared_meter, 4);
+        assert!(estimate.flagged);
+    }
+
+    #[test]
+    fn it_should_not_rate_empty_charts() {
+        let step = stream_file(150000, 0, 0);
+        let estimate = StepmaniaMeterEstimate::from_chart(&step, &step.notes[0]);
+
+        assert_eq!(estimate.meter, 0);
+        assert!(!estimate.flagged);
+    }
+}
@@ -0,0 +1,524 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+
+use super::common::*;
+use super::stepmania::*;
+use super::stepstyle::*;
+
+const CHAR_PROPERTY_START: char = '#';
+const CHAR_VALUE_START: char = ':';
+const CHAR_COMMENT: char = ';';
+
+const CHANNEL_BGM: &str = "01";
+const CHANNEL_MEASURE_LENGTH: &str = "02";
+const CHANNEL_BPM: &str = "03";
+const CHANNEL_BPM_EXTENDED: &str = "08";
+/// An id of "00" marks an empty chip
+const CHIP_EMPTY: &str = "00";
+
+/// The drum channels, in the order of the columns they are mapped to.
+/// The open hi-hat and left bass drum share a column with the closed hi-hat and left pedal.
+const DRUM_CHANNELS: [(&str, u8); 12] = [
+    ("1A", 0), // Left cymbal
+    ("11", 1), // Hi-hat closed
+    ("18", 1), // Hi-hat open
+    ("1B", 2), // Left pedal
+    ("1C", 2), // Left bass drum
+    ("12", 3), // Snare
+    ("14", 4), // High tom
+    ("13", 5), // Bass drum
+    ("15", 6), // Low tom
+    ("17", 7), // Floor tom
+    ("16", 8), // Right cymbal
+    ("19", 9), // Ride cymbal
+];
+const DRUM_COLUMN_COUNT: u8 = 10;
+
+/// The guitar and bass channels by their instrument name in Stepmania's #INSTRUMENTTRACKS.
+/// Their chips are not playable in a drum chart, but their sound is kept as instrument track.
+const INSTRUMENT_CHANNELS: [(&str, [&str; 8]); 2] = [
+    ("guitar", ["20", "21", "22", "23", "24", "25", "26", "27"]),
+    ("bass", ["A0", "A1", "A2", "A3", "A4", "A5", "A6", "A7"]),
+];
+
+/// The denominators a measure length is tried to be expressed in as a time signature
+const SIGNATURE_DENOMINATORS: [u8; 5] = [4, 8, 16, 32, 64];
+const TIME_FACTOR: f64 = 1000.0;
+
+fn to_option(value: &str) -> Option<String> {
+    Some(value.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+/// A single object/chip of the chart
+#[derive(Debug)]
+struct DtxChip<'a> {
+    beat: f64,
+    channel: String,
+    id: String,
+    value: UnparsedPropertyValue<'a>,
+}
+
+#[derive(Debug, Default)]
+pub struct DtxParser {
+    // All errors for the Parse-Result
+    pub errors: Vec<ParseError>,
+}
+
+impl DtxParser {
+    pub fn new() -> DtxParser {
+        DtxParser {
+            ..Default::default()
+        }
+    }
+
+    fn push_error(&mut self, code: ParseErrorCode, value: &UnparsedPropertyValue) {
+        self.errors.push(ParseError {
+            code,
+            line: value.line,
+            column: value.column,
+            len: value.len,
+            span: Some(value.span),
+        });
+    }
+
+    fn parse_to_float(&mut self, value: &UnparsedPropertyValue) -> Option<f64> {
+        match value.raw.trim().parse::<f64>() {
+            Ok(val) => Some(val),
+            Err(_) => {
+                self.push_error(ParseErrorCode::DtxInvalidNumber, value);
+                None
+            }
+        }
+    }
+
+    /// Splits the input into the commands with their (uppercase) names
+    fn parse_to_property_list<'a>(
+        &mut self,
+        input: &'a str,
+    ) -> Vec<(String, UnparsedPropertyValue<'a>)> {
+        let mut list = vec![];
+
+        for (idx, line) in input.lines().enumerate() {
+            let line = line.trim();
+            let Some(command) = line.strip_prefix(CHAR_PROPERTY_START) else {
+                // Everything outside of commands is a comment
+                continue;
+            };
+
+            // The value may be separated by a colon or whitespace
+            let split = command
+                .find(|c: char| c == CHAR_VALUE_START || c.is_whitespace())
+                .unwrap_or(command.len());
+            let name = command[..split].to_uppercase();
+            let rest = &command[split..];
+            let rest = rest.strip_prefix(CHAR_VALUE_START).unwrap_or(rest);
+            let raw = match rest.find(CHAR_COMMENT) {
+                Some(comment) => &rest[..comment],
+                None => rest,
+            }
+            .trim();
+
+            list.push((
+                name,
+                UnparsedPropertyValue {
+                    raw: Cow::Borrowed(raw),
+                    line: idx + 1,
+                    column: line.len() - rest.len() + 1,
+                    len: raw.chars().count(),
+                    span: Span::of(input, raw),
+                    ..Default::default()
+                },
+            ));
+        }
+
+        list
+    }
+
+    /// Object commands are named `MMMCC`, with the measure `MMM` and the channel `CC`
+    fn parse_object_name(name: &str) -> Option<(usize, String)> {
+        if name.len() != 5 || !name.is_ascii() {
+            return None;
+        }
+        let measure = name[..3].parse::<usize>().ok()?;
+
+        Some((measure, name[3..].to_string()))
+    }
+
+    /// Converts the measure length to the closest time signature
+    fn to_time_signature(length: f64) -> (u8, u8) {
+        for den in SIGNATURE_DENOMINATORS.iter() {
+            let num = length * *den as f64;
+            if (num - num.round()).abs() < 0.0001 && num >= 1.0 && num <= u8::MAX as f64 {
+                return (num.round() as u8, *den);
+            }
+        }
+
+        ((length * 4.0).round().clamp(1.0, u8::MAX as f64) as u8, 4)
+    }
+
+    pub fn parse_from_string(&mut self, input: &str) -> Result<StepmaniaFile> {
+        let mut step = StepmaniaFile::default();
+        let mut chart = StepmaniaChart {
+            step_style: StepmaniaStepStyle::Custom("drum-single".to_string()),
+            ..Default::default()
+        };
+
+        // Keysound index by the WAV id
+        let mut wavs: HashMap<String, u32> = HashMap::new();
+        let mut bpm_values: HashMap<String, f64> = HashMap::new();
+        // The data of the object commands by measure
+        let mut objects: Vec<(usize, String, UnparsedPropertyValue)> = vec![];
+        let mut measure_lengths: HashMap<usize, f64> = HashMap::new();
+
+        for (name, value) in self.parse_to_property_list(input) {
+            match name.as_str() {
+                "TITLE" => step.title = to_option(&value.raw),
+                "ARTIST" => step.artist = to_option(&value.raw),
+                "GENRE" => step.genre = to_option(&value.raw),
+                "PREVIEW" => step.preview = to_option(&value.raw),
+                "PREIMAGE" => step.jacket = to_option(&value.raw),
+                "BACKGROUND" | "WALL" => step.background = to_option(&value.raw),
+                "DLEVEL" => match value.raw.parse::<u16>() {
+                    Ok(level) => chart.meter = level,
+                    Err(_) => self.push_error(ParseErrorCode::DtxInvalidNumber, &value),
+                },
+                "BPM" => {
+                    if let Some(bpm) = self.parse_to_float(&value) {
+                        step.bpms.push(StepmaniaTimedBPM {
+                            beat: 0,
+                            bpm: (bpm * TIME_FACTOR).round() as i64,
+                        });
+                    }
+                }
+                _ if name.starts_with("WAV") && name.len() == 5 => {
+                    wavs.insert(name[3..].to_string(), step.keysounds.len() as u32);
+                    step.keysounds.push(value.raw.to_string());
+                }
+                _ if name.starts_with("BPM") && name.len() == 5 => {
+                    if let Some(bpm) = self.parse_to_float(&value) {
+                        bpm_values.insert(name[3..].to_string(), bpm);
+                    }
+                }
+                _ => match DtxParser::parse_object_name(&name) {
+                    Some((measure, channel)) if channel == CHANNEL_MEASURE_LENGTH => {
+                        if let Some(length) = self.parse_to_float(&value).filter(|l| *l > 0.0) {
+                            measure_lengths.insert(measure, length);
+                        }
+                    }
+                    Some((measure, channel)) => objects.push((measure, channel, value)),
+                    // Other commands (volumes, images, ...) are not supported
+                    None => {}
+                },
+            }
+        }
+
+        // The measure length applies until it is changed again
+        let measure_count = objects.iter().map(|(m, _, _)| m + 1).max().unwrap_or(0);
+        let mut measure_beats: Vec<f64> = vec![];
+        let mut length = 1.0;
+        for measure in 0..measure_count {
+            if let Some(changed) = measure_lengths.get(&measure) {
+                length = *changed;
+                let (numerator, denominator) = DtxParser::to_time_signature(length);
+                let beat: f64 = measure_beats.iter().sum();
+                step.time_signatures.push(StepmaniaTimedTimeSignature {
+                    beat: (beat * TIME_FACTOR).round() as i64,
+                    numerator,
+                    denominator,
+                });
+            }
+            measure_beats.push(length * BEATS_PER_MEASURE as f64);
+        }
+
+        let mut chips: Vec<DtxChip> = vec![];
+        for (measure, channel, value) in objects.into_iter() {
+            let data: Vec<char> = value.raw.chars().filter(|c| !c.is_whitespace()).collect();
+            if !data.len().is_multiple_of(2) {
+                self.push_error(ParseErrorCode::DtxInvalidObjectData, &value);
+                continue;
+            }
+
+            let start: f64 = measure_beats[..measure].iter().sum();
+            let count = data.len() / 2;
+            for (idx, pair) in data.chunks(2).enumerate() {
+                let id: String = pair.iter().collect::<String>().to_uppercase();
+                if id == CHIP_EMPTY {
+                    continue;
+                }
+                chips.push(DtxChip {
+                    beat: start + measure_beats[measure] * idx as f64 / count as f64,
+                    channel: channel.clone(),
+                    id,
+                    value: value.clone(),
+                });
+            }
+        }
+        chips.sort_by(|a, b| a.beat.total_cmp(&b.beat));
+
+        // BPM changes have to be known before the music offset can be calculated
+        for chip in chips.iter() {
+            let bpm = match chip.channel.as_str() {
+                CHANNEL_BPM => u8::from_str_radix(&chip.id, 16).ok().map(|v| v as f64),
+                CHANNEL_BPM_EXTENDED => bpm_values.get(&chip.id).copied(),
+                _ => continue,
+            };
+            match bpm {
+                Some(bpm) => step.bpms.push(StepmaniaTimedBPM {
+                    beat: (chip.beat * TIME_FACTOR).round() as i64,
+                    bpm: (bpm * TIME_FACTOR).round() as i64,
+                }),
+                None => self.push_error(ParseErrorCode::DtxInvalidNumber, &chip.value),
+            }
+        }
+        step.bpms.sort_by_key(|bpm| bpm.beat);
+
+        let mut positioned: Vec<StepmaniaPositionedNote> = vec![];
+        let mut occupied: HashSet<(usize, usize, u8)> = HashSet::new();
+        // BGM chips are placed after all drum chips, so that they don't take the column of one
+        let mut autoplayed: Vec<(StepmaniaPositionedNote, &DtxChip)> = vec![];
+
+        for chip in chips.iter() {
+            let keysound = match wavs.get(&chip.id) {
+                Some(idx) => Some(*idx),
+                None => {
+                    self.push_error(ParseErrorCode::DtxUndefinedWav, &chip.value);
+                    None
+                }
+            };
+
+            if chip.channel == CHANNEL_BGM {
+                // A BGM chip without a sound has nothing to play
+                let Some(idx) = keysound else {
+                    continue;
+                };
+
+                // The first BGM chip is the song itself, which starts playing at the chip
+                if step.music.is_none() {
+                    step.music = Some(step.keysounds[idx as usize].clone());
+                    step.offset = Some(step.beat_to_ms(chip.beat).round() as i64);
+                    continue;
+                }
+
+                let mut note =
+                    StepmaniaPositionedNote::at_beat(chip.beat, 0, StepmaniaNoteType::Keysound);
+                note.keysound = keysound;
+                autoplayed.push((note, chip));
+                continue;
+            }
+
+            let instrument = INSTRUMENT_CHANNELS
+                .iter()
+                .find(|(_, channels)| channels.contains(&chip.channel.as_str()));
+            if let Some((instrument, _)) = instrument {
+                // Like the first BGM chip, the first chip of the part is its whole track
+                let is_known = step
+                    .instrument_tracks
+                    .iter()
+                    .any(|track| track.instrument == *instrument);
+                if let (Some(idx), false) = (keysound, is_known) {
+                    step.instrument_tracks.push(StepmaniaInstrumentTrack {
+                        instrument: instrument.to_string(),
+                        file: step.keysounds[idx as usize].clone(),
+                    });
+                }
+                continue;
+            }
+
+            let Some((_, column)) = DRUM_CHANNELS.iter().find(|(ch, _)| *ch == chip.channel) else {
+                // Visual channels are not supported
+                continue;
+            };
+
+            let mut note =
+                StepmaniaPositionedNote::at_beat(chip.beat, *column, StepmaniaNoteType::Tap);
+            note.keysound = keysound;
+            if !occupied.insert((note.measure, note.row, *column)) {
+                self.push_error(ParseErrorCode::DtxOverlappingChips, &chip.value);
+                continue;
+            }
+            positioned.push(note);
+        }
+
+        // Other BGM chips are played automatically in any free column
+        for (mut note, chip) in autoplayed {
+            match (0..DRUM_COLUMN_COUNT).find(|c| !occupied.contains(&(note.measure, note.row, *c)))
+            {
+                Some(column) => {
+                    note.column = column;
+                    occupied.insert((note.measure, note.row, column));
+                    positioned.push(note);
+                }
+                None => self.push_error(ParseErrorCode::DtxOverlappingChips, &chip.value),
+            }
+        }
+
+        chart.data = StepmaniaNoteData::from_positioned_notes(DRUM_COLUMN_COUNT, &positioned);
+        step.notes.push(chart);
+
+        Ok(step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "
+; Created by hand
+#TITLE: Sample Drums
+#ARTIST: PreFiXAUT
+#BPM: 120
+#BPM01: 180.5
+#DLEVEL: 65
+#WAV01: bgm.ogg
+#WAV02: snare.wav
+#WAV03: kick.wav
+#WAV04: crash.wav
+
+#00001: 01
+#00012: 00020002
+#00013: 03030303
+#00102: 0.75
+#00116: 04
+#00108: 000001
+#00101: 0004
+";
+
+    #[test]
+    fn it_should_parse_meta() {
+        let mut parser = DtxParser::new();
+        let res = parser.parse_from_string(SAMPLE);
+        assert!(res.is_ok());
+        assert_eq!(parser.errors.len(), 0);
+
+        let step = res.unwrap();
+        assert_eq!(step.title.as_deref(), Some("Sample Drums"));
+        assert_eq!(step.artist.as_deref(), Some("PreFiXAUT"));
+        assert_eq!(step.keysounds.len(), 4);
+        assert_eq!(step.music.as_deref(), Some("bgm.ogg"));
+        assert_eq!(step.offset, Some(0));
+        assert_eq!(step.notes[0].meter, 65);
+    }
+
+    #[test]
+    fn it_should_parse_timing() {
+        let mut parser = DtxParser::new();
+        let step = parser.parse_from_string(SAMPLE).unwrap();
+
+        assert_eq!(step.bpms.len(), 2);
+        assert_eq!(step.bpms[1].beat, 6000);
+        assert_eq!(step.bpms[1].bpm, 180500);
+        assert_eq!(step.time_signatures.len(), 1);
+        assert_eq!(step.time_signatures[0].beat, 4000);
+        assert_eq!(step.time_signatures[0].numerator, 3);
+        assert_eq!(step.time_signatures[0].denominator, 4);
+    }
+
+    #[test]
+    fn it_should_parse_keysounded_notes() {
+        let mut parser = DtxParser::new();
+        let step = parser.parse_from_string(SAMPLE).unwrap();
+        let notes: Vec<(f64, u8, StepmaniaNoteType, Option<u32>)> = step.notes[0]
+            .data
+            .positioned_notes()
+            .iter()
+            .map(|n| (n.beat(), n.column, n.note_type, n.keysound))
+            .collect();
+
+        assert_eq!(
+            notes,
+            vec![
+                (0.0, 5, StepmaniaNoteType::Tap, Some(2)),
+                (1.0, 3, StepmaniaNoteType::Tap, Some(1)),
+                (1.0, 5, StepmaniaNoteType::Tap, Some(2)),
+                (2.0, 5, StepmaniaNoteType::Tap, Some(2)),
+                (3.0, 3, StepmaniaNoteType::Tap, Some(1)),
+                (3.0, 5, StepmaniaNoteType::Tap, Some(2)),
+                (4.0, 8, StepmaniaNoteType::Tap, Some(3)),
+                (5.5, 0, StepmaniaNoteType::Keysound, Some(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_place_bgm_chips_after_drum_chips() {
+        let mut parser = DtxParser::new();
+        let step = parser
+            .parse_from_string(
+                "
+#WAV01: bgm.ogg
+#WAV02: effect.wav
+#WAV03: crash.wav
+#00001: 01
+#00101: 02
+#0011A: 03
+",
+            )
+            .unwrap();
+        assert_eq!(parser.errors.len(), 0);
+
+        let notes: Vec<(f64, u8, StepmaniaNoteType, Option<u32>)> = step.notes[0]
+            .data
+            .positioned_notes()
+            .iter()
+            .map(|n| (n.beat(), n.column, n.note_type, n.keysound))
+            .collect();
+        assert_eq!(
+            notes,
+            vec![
+                (4.0, 0, StepmaniaNoteType::Tap, Some(2)),
+                (4.0, 1, StepmaniaNoteType::Keysound, Some(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_keep_guitar_and_bass_as_instrument_tracks() {
+        let mut parser = DtxParser::new();
+        let data = format!(
+            "{}#WAV05: guitar.ogg\n#WAV06: bass.ogg\n#00020: 05\n#00122: 0202\n#000A1: 0006\n",
+            SAMPLE
+        );
+        let step = parser.parse_from_string(&data).unwrap();
+        assert_eq!(parser.errors.len(), 0);
+
+        let tracks: Vec<(&str, &str)> = step
+            .instrument_tracks
+            .iter()
+            .map(|track| (track.instrument.as_str(), track.file.as_str()))
+            .collect();
+        assert_eq!(tracks, vec![("guitar", "guitar.ogg"), ("bass", "bass.ogg")]);
+        // The chips of the parts are not added as notes
+        assert_eq!(step.notes[0].data.positioned_notes().len(), 8);
+
+        let written = StepmaniaWriter::new().write_to_string(&step).unwrap();
+        let reparsed = StepmaniaParser::new().parse_from_string(&written).unwrap();
+        assert_eq!(reparsed.instrument_tracks, step.instrument_tracks);
+    }
+
+    #[test]
+    fn it_should_write_as_stepmania() {
+        let mut parser = DtxParser::new();
+        let step = parser.parse_from_string(SAMPLE).unwrap();
+        let written = StepmaniaWriter::new().write_to_string(&step).unwrap();
+        assert!(written.contains("#KEYSOUNDS:bgm.ogg,snare.wav,kick.wav,crash.wav;"));
+
+        let mut parser = StepmaniaParser::new();
+        let reparsed = parser.parse_from_string(&written).unwrap();
+        assert_eq!(reparsed.notes.len(), 1);
+        assert_eq!(reparsed.notes[0].step_style.name(), "drum-single");
+        assert_eq!(reparsed.notes[0].data.column_count, 10);
+
+        let keysounds: Vec<Option<u32>> = reparsed.notes[0]
+            .data
+            .positioned_notes()
+            .iter()
+            .map(|n| n.keysound)
+            .collect();
+        assert_eq!(keysounds.len(), 8);
+        assert_eq!(keysounds[0], Some(2));
+        assert_eq!(keysounds[7], Some(3));
+    }
+}
@@ -0,0 +1,613 @@
+use std::borrow::Cow;
+
+use anyhow::Result;
+
+use super::common::*;
+use super::stepmania::*;
+use super::stepstyle::*;
+
+const CHAR_LINE_BREAK: char = '\n';
+const CHAR_PROPERTY_START: char = '#';
+const CHAR_VALUE_START: char = ':';
+const CHAR_VALUE_END: char = ';';
+const CHAR_OBJ_VAL_SEPARATOR: char = '=';
+const CHAR_OBJ_SEPARATOR: char = ',';
+const CHAR_COMMENT: &str = "//";
+const CHAR_HOLD: char = '!';
+const CHAR_GROUP_START: char = '<';
+const CHAR_GROUP_END: char = '>';
+
+/// Rows (in 192nds of a measure) each step advances by default (8th notes)
+const ROWS_DEFAULT: usize = 24;
+const ROWS_16TH: usize = 12;
+const ROWS_24TH: usize = 8;
+const ROWS_64TH: usize = 3;
+const ROWS_192ND: usize = 1;
+
+/// DWI beats for `#CHANGEBPM` and `#FREEZE` are in 16th notes, while ours are in 1000s of a beat
+const BEAT_FACTOR: f64 = 250.0;
+const TIME_FACTOR: f64 = 1000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DwiPanel {
+    Left,
+    UpLeft,
+    Down,
+    Up,
+    UpRight,
+    Right,
+}
+
+impl DwiPanel {
+    /// The panels which are stepped on with the character
+    fn from_char(c: char) -> Option<&'static [DwiPanel]> {
+        use DwiPanel::*;
+
+        let panels: &'static [DwiPanel] = match c.to_ascii_uppercase() {
+            '0' | '5' => &[],
+            '1' => &[Down, Left],
+            '2' => &[Down],
+            '3' => &[Down, Right],
+            '4' => &[Left],
+            '6' => &[Right],
+            '7' => &[Up, Left],
+            '8' => &[Up],
+            '9' => &[Up, Right],
+            'A' => &[Up, Down],
+            'B' => &[Left, Right],
+            'C' => &[UpLeft],
+            'D' => &[UpRight],
+            'E' => &[Left, UpLeft],
+            'F' => &[UpLeft, Down],
+            'G' => &[UpLeft, Up],
+            'H' => &[UpLeft, Right],
+            'I' => &[Left, UpRight],
+            'J' => &[Down, UpRight],
+            'K' => &[Up, UpRight],
+            'L' => &[UpRight, Right],
+            'M' => &[UpLeft, UpRight],
+            _ => return None,
+        };
+
+        Some(panels)
+    }
+
+    /// The column of the panel for a pad with the amount of columns (4 or 6)
+    fn column(&self, pad_columns: u8) -> Option<u8> {
+        match (pad_columns, self) {
+            (4, DwiPanel::Left) => Some(0),
+            (4, DwiPanel::Down) => Some(1),
+            (4, DwiPanel::Up) => Some(2),
+            (4, DwiPanel::Right) => Some(3),
+            (6, DwiPanel::Left) => Some(0),
+            (6, DwiPanel::UpLeft) => Some(1),
+            (6, DwiPanel::Down) => Some(2),
+            (6, DwiPanel::Up) => Some(3),
+            (6, DwiPanel::UpRight) => Some(4),
+            (6, DwiPanel::Right) => Some(5),
+            _ => None,
+        }
+    }
+}
+
+fn difficulty_from_name(name: &str) -> StepmaniaDifficulty {
+    match name.trim().to_uppercase().as_str() {
+        "BEGINNER" => StepmaniaDifficulty::Beginner,
+        "BASIC" => StepmaniaDifficulty::Easy,
+        "ANOTHER" => StepmaniaDifficulty::Medium,
+        "MANIAC" => StepmaniaDifficulty::Hard,
+        "SMANIAC" => StepmaniaDifficulty::Challenge,
+        _ => StepmaniaDifficulty::Edit,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DwiParser {
+    // All errors for the Parse-Result
+    pub errors: Vec<ParseError>,
+}
+
+impl DwiParser {
+    pub fn new() -> DwiParser {
+        DwiParser {
+            ..Default::default()
+        }
+    }
+
+    fn push_error(&mut self, code: ParseErrorCode, value: &UnparsedPropertyValue) {
+        self.errors.push(ParseError {
+            code,
+            line: value.line,
+            column: value.column,
+            len: value.len,
+            span: Some(value.span),
+        });
+    }
+
+    /// Splits the input into the properties, ignoring comments.
+    fn parse_to_property_list<'a>(
+        &mut self,
+        input: &'a str,
+    ) -> Vec<(String, UnparsedPropertyValue<'a>)> {
+        let mut list: Vec<(String, UnparsedPropertyValue)> = vec![];
+
+        for (line_idx, line) in input.lines().enumerate() {
+            let content = match line.find(CHAR_COMMENT) {
+                Some(idx) => &line[..idx],
+                None => line,
+            };
+
+            // Values may span multiple lines, which are continued here
+            if let Some((_, value)) = list.last_mut() {
+                if !value.raw.trim_end().ends_with(CHAR_VALUE_END) {
+                    let raw = value.raw.to_mut();
+                    raw.push(CHAR_LINE_BREAK);
+                    raw.push_str(content);
+                    value.len = value.raw.chars().count();
+                    value.span.end = Span::of(input, content).end;
+                    continue;
+                }
+            }
+
+            let trimmed = content.trim_start();
+            if trimmed.is_empty() {
+                continue;
+            }
+
+            let column = content.len() - trimmed.len() + 1;
+            let unparsed = UnparsedPropertyValue {
+                raw: Cow::Borrowed(trimmed),
+                line: line_idx + 1,
+                column,
+                len: trimmed.chars().count(),
+                span: Span::of(input, trimmed),
+                ..Default::default()
+            };
+
+            match trimmed
+                .strip_prefix(CHAR_PROPERTY_START)
+                .and_then(|rest| rest.split_once(CHAR_VALUE_START))
+            {
+                Some((name, value)) => list.push((
+                    name.trim().to_lowercase(),
+                    UnparsedPropertyValue {
+                        raw: Cow::Borrowed(value),
+                        column: column + name.len() + 2,
+                        len: value.chars().count(),
+                        span: Span::of(input, value),
+                        ..unparsed
+                    },
+                )),
+                None => self.push_error(ParseErrorCode::DwiExpectedPropertyStart, &unparsed),
+            }
+        }
+
+        for (_, value) in list.iter_mut() {
+            match value
+                .raw
+                .trim_end()
+                .strip_suffix(CHAR_VALUE_END)
+                .map(str::len)
+            {
+                Some(len) => {
+                    value.span.end = value.span.start + len;
+                    match &mut value.raw {
+                        Cow::Borrowed(raw) => *raw = &raw[..len],
+                        Cow::Owned(raw) => raw.truncate(len),
+                    }
+                }
+                None => {
+                    self.errors.push(ParseError {
+                        code: ParseErrorCode::DwiExpectedValueEnd,
+                        line: value.line,
+                        column: value.column,
+                        len: value.len,
+                        span: Some(value.span),
+                    });
+                }
+            }
+        }
+
+        list
+    }
+
+    fn parse_to_float(&mut self, value: &UnparsedPropertyValue) -> Option<f64> {
+        match value.raw.trim().parse::<f64>() {
+            Ok(val) => Some(val),
+            Err(_) => {
+                self.push_error(ParseErrorCode::DwiInvalidNumber, value);
+                None
+            }
+        }
+    }
+
+    /// Timestamps are either plain ms, or seconds with optional minutes/hours (`1:23.45`).
+    fn parse_to_timestamp(&mut self, value: &UnparsedPropertyValue) -> Option<i64> {
+        let raw = value.raw.trim();
+        if !raw.contains(':') && !raw.contains('.') {
+            return raw.parse::<i64>().ok().or_else(|| {
+                self.push_error(ParseErrorCode::DwiInvalidNumber, value);
+                None
+            });
+        }
+
+        let mut seconds = 0.0;
+        for part in raw.split(':') {
+            match part.trim().parse::<f64>() {
+                Ok(val) => seconds = seconds * 60.0 + val,
+                Err(_) => {
+                    self.push_error(ParseErrorCode::DwiInvalidNumber, value);
+                    return None;
+                }
+            }
+        }
+
+        Some((seconds * TIME_FACTOR).round() as i64)
+    }
+
+    /// Parses the `beat=value` pairs of `#CHANGEBPM` and `#FREEZE`
+    fn parse_to_beat_pairs(&mut self, value: &UnparsedPropertyValue) -> Vec<(i64, f64)> {
+        let mut list = vec![];
+
+        for entry in value.raw.split(CHAR_OBJ_SEPARATOR) {
+            if entry.trim().is_empty() {
+                continue;
+            }
+            let pair = entry
+                .split_once(CHAR_OBJ_VAL_SEPARATOR)
+                .and_then(|(beat, val)| {
+                    Some((
+                        beat.trim().parse::<f64>().ok()?,
+                        val.trim().parse::<f64>().ok()?,
+                    ))
+                });
+            match pair {
+                Some((beat, val)) => list.push(((beat * BEAT_FACTOR).round() as i64, val)),
+                None => self.push_error(ParseErrorCode::DwiInvalidNumber, value),
+            }
+        }
+
+        list
+    }
+
+    /// Parses the step string of a single pad to the notes as `(row, column, type)`,
+    /// where the row is in 192nds since the start.
+    fn parse_to_pad_notes(
+        &mut self,
+        value: &UnparsedPropertyValue,
+        pad_columns: u8,
+        column_offset: u8,
+    ) -> Vec<(usize, u8, StepmaniaNoteType)> {
+        let mut notes: Vec<(usize, u8, StepmaniaNoteType)> = vec![];
+        let mut step = ROWS_DEFAULT;
+        let mut pos = 0;
+        let mut last_pos = 0;
+        let mut in_group = false;
+        let mut chars = value.raw.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '(' => step = ROWS_16TH,
+                '[' => step = ROWS_24TH,
+                '{' => step = ROWS_64TH,
+                '`' => step = ROWS_192ND,
+                ')' | ']' | '}' | '\'' => step = ROWS_DEFAULT,
+                CHAR_GROUP_START => in_group = true,
+                CHAR_GROUP_END => {
+                    in_group = false;
+                    pos += step;
+                }
+                CHAR_HOLD => {
+                    // The character after it marks which of the previous panels are held
+                    let held = chars.next().and_then(DwiPanel::from_char).unwrap_or(&[]);
+                    for panel in held {
+                        let Some(column) = panel.column(pad_columns) else {
+                            self.push_error(ParseErrorCode::DwiInvalidStep, value);
+                            continue;
+                        };
+                        let column = column + column_offset;
+                        match notes.iter_mut().find(|n| n.0 == last_pos && n.1 == column) {
+                            Some(note) => note.2 = StepmaniaNoteType::HoldHead,
+                            None => notes.push((last_pos, column, StepmaniaNoteType::HoldHead)),
+                        }
+                    }
+                }
+                _ if c.is_whitespace() => {}
+                _ => {
+                    let Some(panels) = DwiPanel::from_char(c) else {
+                        self.push_error(ParseErrorCode::DwiInvalidStep, value);
+                        continue;
+                    };
+                    for panel in panels {
+                        match panel.column(pad_columns) {
+                            Some(column) => {
+                                notes.push((pos, column + column_offset, StepmaniaNoteType::Tap))
+                            }
+                            None => self.push_error(ParseErrorCode::DwiInvalidStep, value),
+                        }
+                    }
+                    last_pos = pos;
+                    if !in_group {
+                        pos += step;
+                    }
+                }
+            }
+        }
+
+        notes
+    }
+
+    fn parse_to_chart(
+        &mut self,
+        name: &str,
+        value: &UnparsedPropertyValue,
+    ) -> Option<StepmaniaChart> {
+        let step_style = match name {
+            "single" => StepmaniaStepStyle::DanceSingle,
+            "solo" => StepmaniaStepStyle::DanceSolo,
+            "double" => StepmaniaStepStyle::DanceDouble,
+            "couple" => StepmaniaStepStyle::DanceCouple,
+            _ => return None,
+        };
+        let pads = step_style.pad_count() as usize;
+        let pad_columns = step_style.column_count().unwrap_or(0) / pads as u8;
+
+        let parts: Vec<&str> = value.raw.split(CHAR_VALUE_START).collect();
+        if parts.len() != 2 + pads {
+            self.push_error(ParseErrorCode::DwiInvalidValueCount, value);
+            return None;
+        }
+
+        let mut chart = StepmaniaChart {
+            step_style,
+            difficulty: difficulty_from_name(parts[0]),
+            ..Default::default()
+        };
+        match parts[1].trim().parse::<u16>() {
+            Ok(meter) => chart.meter = meter,
+            Err(_) => self.push_error(ParseErrorCode::DwiInvalidNumber, value),
+        }
+
+        let mut notes = vec![];
+        for pad in 0..pads {
+            let steps = UnparsedPropertyValue {
+                raw: Cow::Borrowed(parts[2 + pad]),
+                ..value.clone()
+            };
+            notes.append(&mut self.parse_to_pad_notes(
+                &steps,
+                pad_columns,
+                pad as u8 * pad_columns,
+            ));
+        }
+        notes.sort_by_key(|(row, column, _)| (*row, *column));
+
+        // Holds are ended by the next step on the same panel
+        let column_count = pad_columns * pads as u8;
+        let mut holding = vec![false; column_count as usize];
+        let positioned: Vec<StepmaniaPositionedNote> = notes
+            .into_iter()
+            .map(|(row, column, mut note_type)| {
+                if holding[column as usize] {
+                    note_type = StepmaniaNoteType::Tail;
+                }
+                holding[column as usize] = note_type == StepmaniaNoteType::HoldHead;
+
+                StepmaniaPositionedNote {
+                    measure: row / MAX_ROWS_PER_MEASURE,
+                    row: row % MAX_ROWS_PER_MEASURE,
+                    row_count: MAX_ROWS_PER_MEASURE,
+                    column,
+                    note_type,
+                    keysound: None,
+                }
+            })
+            .collect();
+
+        chart.data = StepmaniaNoteData::from_positioned_notes(column_count, &positioned);
+
+        Some(chart)
+    }
+
+    pub fn parse_from_string(&mut self, input: &str) -> Result<StepmaniaFile> {
+        let mut step = StepmaniaFile::default();
+
+        for (name, value) in self.parse_to_property_list(input) {
+            match name.as_str() {
+                "title" => step.title = Some(value.raw.trim().to_string()),
+                "artist" => step.artist = Some(value.raw.trim().to_string()),
+                "genre" => step.genre = Some(value.raw.trim().to_string()),
+                "cdtitle" => step.cd_title = Some(value.raw.trim().to_string()),
+                "file" => step.music = Some(value.raw.trim().to_string()),
+
+                "bpm" => {
+                    if let Some(bpm) = self.parse_to_float(&value) {
+                        step.bpms.insert(
+                            0,
+                            StepmaniaTimedBPM {
+                                beat: 0,
+                                bpm: (bpm * TIME_FACTOR).round() as i64,
+                            },
+                        );
+                    }
+                }
+                "gap" => step.offset = self.parse_to_float(&value).map(|gap| -gap.round() as i64),
+                "changebpm" | "bpmchange" => {
+                    for (beat, bpm) in self.parse_to_beat_pairs(&value) {
+                        step.bpms.push(StepmaniaTimedBPM {
+                            beat,
+                            bpm: (bpm * TIME_FACTOR).round() as i64,
+                        });
+                    }
+                }
+                "freeze" => {
+                    for (beat, duration) in self.parse_to_beat_pairs(&value) {
+                        step.stops.push(StepmaniaTimedDuration {
+                            beat,
+                            duration: duration.round() as i64,
+                        });
+                    }
+                }
+                "displaybpm" => {
+                    let (min, max) = value
+                        .raw
+                        .split_once("..")
+                        .unwrap_or((&value.raw, &value.raw));
+                    if value.raw.trim() == "*" {
+                        step.display_bpm = Some(StepmaniaBPMRange::Random);
+                    } else if let (Ok(min), Ok(max)) =
+                        (min.trim().parse::<f64>(), max.trim().parse::<f64>())
+                    {
+                        step.display_bpm = Some(StepmaniaBPMRange::from_bounds(
+                            (min * TIME_FACTOR).round() as i64,
+                            (max * TIME_FACTOR).round() as i64,
+                        ));
+                    }
+                }
+                "samplestart" => step.sample_start = self.parse_to_timestamp(&value),
+                "samplelength" => step.sample_length = self.parse_to_timestamp(&value),
+
+                "single" | "solo" | "double" | "couple" => {
+                    if let Some(chart) = self.parse_to_chart(&name, &value) {
+                        step.notes.push(chart);
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        step.bpms.sort_by_key(|bpm| bpm.beat);
+
+        Ok(step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    const SAMPLE: &str = "
+#TITLE:Butterfly;
+#ARTIST:SMiLE.dk;
+#FILE:butterfly.mp3;
+#BPM:135.000;
+#GAP:-220;
+#CHANGEBPM:64.000=270.000;
+#FREEZE:32.000=500.000;
+#DISPLAYBPM:135..270;
+#SAMPLESTART:1:05.5;
+// A comment which should be ignored
+#SINGLE:MANIAC:7:
+2468(2468)<28>[000]
+8!80000008;
+#DOUBLE:BASIC:3:
+4000:
+0006;
+";
+
+    #[test]
+    fn it_should_parse_meta() {
+        let mut parser = DwiParser::new();
+        let res = parser.parse_from_string(SAMPLE);
+        assert!(res.is_ok());
+        assert_eq!(parser.errors.len(), 0);
+
+        let step = res.unwrap();
+        assert_eq!(step.title.unwrap(), "Butterfly");
+        assert_eq!(step.artist.unwrap(), "SMiLE.dk");
+        assert_eq!(step.music.unwrap(), "butterfly.mp3");
+        assert_eq!(step.sample_start.unwrap(), 65500);
+
+        assert_eq!(
+            step.display_bpm,
+            Some(StepmaniaBPMRange::Range(135000, 270000))
+        );
+    }
+
+    #[test]
+    fn it_should_parse_timing() {
+        let mut parser = DwiParser::new();
+        let step = parser.parse_from_string(SAMPLE).unwrap();
+
+        assert_eq!(step.offset.unwrap(), 220);
+        assert_eq!(step.bpms.len(), 2);
+        assert_eq!(step.bpms[1].beat, 16000);
+        assert_eq!(step.bpms[1].bpm, 270000);
+        assert_eq!(step.stops.len(), 1);
+        assert_eq!(step.stops[0].beat, 8000);
+        assert_eq!(step.stops[0].duration, 500);
+    }
+
+    #[test]
+    fn it_should_parse_single_steps() {
+        let mut parser = DwiParser::new();
+        let step = parser.parse_from_string(SAMPLE).unwrap();
+        let chart = &step.notes[0];
+
+        assert_eq!(chart.step_style, StepmaniaStepStyle::DanceSingle);
+        assert!(matches!(chart.difficulty, StepmaniaDifficulty::Hard));
+        assert_eq!(chart.meter, 7);
+        assert_eq!(chart.data.column_count, 4);
+
+        let notes = notes_of(chart);
+        assert_eq!(
+            notes[..8],
+            vec![
+                (0.0, 1, StepmaniaNoteType::Tap),
+                (0.5, 0, StepmaniaNoteType::Tap),
+                (1.0, 3, StepmaniaNoteType::Tap),
+                (1.5, 2, StepmaniaNoteType::Tap),
+                (2.0, 1, StepmaniaNoteType::Tap),
+                (2.25, 0, StepmaniaNoteType::Tap),
+                (2.5, 3, StepmaniaNoteType::Tap),
+                (2.75, 2, StepmaniaNoteType::Tap),
+            ]
+        );
+        // The jump is placed on a single row
+        assert_eq!(
+            notes[8..10],
+            vec![
+                (3.0, 1, StepmaniaNoteType::Tap),
+                (3.0, 2, StepmaniaNoteType::Tap),
+            ]
+        );
+        // Three 24ths and then the hold, which is ended by the next up-arrow
+        assert_eq!(
+            notes[10..],
+            vec![
+                (4.0, 2, StepmaniaNoteType::HoldHead),
+                (7.5, 2, StepmaniaNoteType::Tail),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_parse_double_steps() {
+        let mut parser = DwiParser::new();
+        let step = parser.parse_from_string(SAMPLE).unwrap();
+        let chart = &step.notes[1];
+
+        assert_eq!(chart.step_style, StepmaniaStepStyle::DanceDouble);
+        assert!(matches!(chart.difficulty, StepmaniaDifficulty::Easy));
+        assert_eq!(chart.data.column_count, 8);
+        assert_eq!(
+            notes_of(chart),
+            vec![
+                (0.0, 0, StepmaniaNoteType::Tap),
+                (1.5, 7, StepmaniaNoteType::Tap),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_report_invalid_steps() {
+        let mut parser = DwiParser::new();
+        let step = parser.parse_from_string("#SINGLE:BASIC:1:2X2C;").unwrap();
+
+        assert_eq!(step.notes[0].data.positioned_notes().len(), 2);
+        assert_eq!(parser.errors.len(), 2);
+        assert_eq!(parser.errors[0].code, ParseErrorCode::DwiInvalidStep);
+    }
+}
@@ -0,0 +1,548 @@
+use std::borrow::Cow;
+
+use anyhow::Result;
+
+use super::common::*;
+use super::stepmania::*;
+use super::stepstyle::*;
+
+const CHAR_OPTION_SEPARATOR: char = '=';
+const CHAR_LANE_SEPARATOR: char = '|';
+const CHAR_MUSIC_SEPARATOR: char = ';';
+const CHAR_SIGNATURE_SEPARATOR: char = '/';
+const CHAR_RANGE_SEPARATOR: char = '-';
+const MEASURE_SEPARATOR: &str = "--";
+const COMMENT_START: &str = "//";
+const BYTE_ORDER_MARK: char = '\u{feff}';
+
+const BT_CHIP: char = '1';
+const BT_LONG: char = '2';
+const FX_LONG: char = '1';
+const FX_CHIP: char = '2';
+const NOTE_NONE: char = '0';
+const LASER_NONE: char = '-';
+const LASER_CONNECTION: char = ':';
+/// All positions of a laser, from left to right
+const LASER_POSITIONS: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmno";
+
+const BT_COUNT: u8 = 4;
+const FX_COUNT: u8 = 2;
+const LASER_COUNT: u8 = 2;
+/// Stops are defined in 192nds of a 4/4 measure
+const STOP_ROWS_PER_BEAT: f64 = 48.0;
+const TIME_FACTOR: f64 = 1000.0;
+
+fn difficulty_from_name(name: &str) -> StepmaniaDifficulty {
+    match name.trim() {
+        "light" => StepmaniaDifficulty::Easy,
+        "challenge" => StepmaniaDifficulty::Medium,
+        "extended" => StepmaniaDifficulty::Hard,
+        "infinite" => StepmaniaDifficulty::Challenge,
+        _ => StepmaniaDifficulty::Edit,
+    }
+}
+
+fn to_option(value: &str) -> Option<String> {
+    Some(value.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+/// A line inside of a measure
+#[derive(Debug)]
+enum KshLine<'a> {
+    /// The lanes of a chart line
+    Notes(UnparsedPropertyValue<'a>),
+    /// An option (`key=value`) which applies before the chart line with the index
+    Option(usize, String, UnparsedPropertyValue<'a>),
+}
+
+#[derive(Debug)]
+pub struct KshParser {
+    // All errors for the Parse-Result
+    pub errors: Vec<ParseError>,
+    /// If the lasers should be kept as two additional columns of `Laser` notes.
+    /// Otherwise, only the BT and FX lanes are converted to a 6-key chart.
+    pub lasers: bool,
+    // The time signature of the measures
+    numerator: u8,
+    denominator: u8,
+    // The beat the current measure starts on
+    measure_beat: f64,
+    // If a hold is currently open, per column
+    holds: Vec<bool>,
+    notes: Vec<StepmaniaPositionedNote>,
+}
+
+impl Default for KshParser {
+    fn default() -> Self {
+        KshParser {
+            errors: vec![],
+            lasers: false,
+            numerator: 4,
+            denominator: 4,
+            measure_beat: 0.0,
+            holds: vec![],
+            notes: vec![],
+        }
+    }
+}
+
+impl KshParser {
+    pub fn new() -> KshParser {
+        KshParser {
+            ..Default::default()
+        }
+    }
+
+    fn push_error(&mut self, code: ParseErrorCode, value: &UnparsedPropertyValue) {
+        self.errors.push(ParseError {
+            code,
+            line: value.line,
+            column: value.column,
+            len: value.len,
+            span: Some(value.span),
+        });
+    }
+
+    fn column_count(&self) -> u8 {
+        if self.lasers {
+            BT_COUNT + FX_COUNT + LASER_COUNT
+        } else {
+            BT_COUNT + FX_COUNT
+        }
+    }
+
+    fn parse_to_float(&mut self, value: &UnparsedPropertyValue) -> Option<f64> {
+        match value.raw.trim().parse::<f64>() {
+            Ok(val) => Some(val),
+            Err(_) => {
+                self.push_error(ParseErrorCode::KshInvalidNumber, value);
+                None
+            }
+        }
+    }
+
+    fn parse_to_time_signature(&mut self, value: &UnparsedPropertyValue) -> Option<(u8, u8)> {
+        let signature = value
+            .raw
+            .split_once(CHAR_SIGNATURE_SEPARATOR)
+            .and_then(|(num, den)| Some((num.trim().parse().ok()?, den.trim().parse().ok()?)))
+            .filter(|(num, den)| *num > 0 && *den > 0);
+        if signature.is_none() {
+            self.push_error(ParseErrorCode::KshInvalidNumber, value);
+        }
+
+        signature
+    }
+
+    fn push_note(&mut self, beat: f64, column: u8, note_type: StepmaniaNoteType) {
+        self.notes
+            .push(StepmaniaPositionedNote::at_beat(beat, column, note_type));
+    }
+
+    /// Starts or ends the hold in the column, depending on whether the lane is held on this line
+    fn update_hold(&mut self, beat: f64, column: u8, held: bool) {
+        match (self.holds[column as usize], held) {
+            (false, true) => {
+                self.holds[column as usize] = true;
+                self.push_note(beat, column, StepmaniaNoteType::HoldHead);
+            }
+            (true, false) => {
+                self.holds[column as usize] = false;
+                self.push_note(beat, column, StepmaniaNoteType::Tail);
+            }
+            _ => {}
+        }
+    }
+
+    fn parse_chart_line(&mut self, beat: f64, value: &UnparsedPropertyValue) {
+        let lanes: Vec<Vec<char>> = value
+            .raw
+            .split(CHAR_LANE_SEPARATOR)
+            .map(|lane| lane.chars().collect())
+            .collect();
+        if lanes.len() < 3
+            || lanes[0].len() != BT_COUNT as usize
+            || lanes[1].len() != FX_COUNT as usize
+            || lanes[2].len() < LASER_COUNT as usize
+        {
+            self.push_error(ParseErrorCode::KshInvalidChartLine, value);
+            return;
+        }
+
+        for (idx, c) in lanes[0].iter().enumerate() {
+            let column = idx as u8;
+            self.update_hold(beat, column, *c == BT_LONG);
+            match *c {
+                BT_CHIP => self.push_note(beat, column, StepmaniaNoteType::Tap),
+                BT_LONG | NOTE_NONE => {}
+                _ => self.push_error(ParseErrorCode::KshInvalidChartLine, value),
+            }
+        }
+
+        for (idx, c) in lanes[1].iter().enumerate() {
+            let column = BT_COUNT + idx as u8;
+            // Legacy charts mark long FX with the effect character instead
+            let long = *c == FX_LONG || (*c != FX_CHIP && *c != NOTE_NONE);
+            self.update_hold(beat, column, long);
+            if *c == FX_CHIP {
+                self.push_note(beat, column, StepmaniaNoteType::Tap);
+            }
+        }
+
+        if !self.lasers {
+            return;
+        }
+
+        for (idx, c) in lanes[2].iter().take(LASER_COUNT as usize).enumerate() {
+            let column = BT_COUNT + FX_COUNT + idx as u8;
+            match *c {
+                LASER_NONE | LASER_CONNECTION => {}
+                _ => match LASER_POSITIONS.find(*c) {
+                    Some(pos) => self.push_note(beat, column, StepmaniaNoteType::Laser(pos as u8)),
+                    None => self.push_error(ParseErrorCode::KshInvalidChartLine, value),
+                },
+            }
+        }
+    }
+
+    fn parse_measure(&mut self, step: &mut StepmaniaFile, lines: &[KshLine]) {
+        // The time signature always applies to the whole measure
+        for line in lines.iter() {
+            if let KshLine::Option(_, key, value) = line {
+                if key == "beat" {
+                    if let Some((num, den)) = self.parse_to_time_signature(value) {
+                        self.numerator = num;
+                        self.denominator = den;
+                        step.time_signatures.push(StepmaniaTimedTimeSignature {
+                            beat: (self.measure_beat * TIME_FACTOR).round() as i64,
+                            numerator: num,
+                            denominator: den,
+                        });
+                    }
+                }
+            }
+        }
+
+        let measure_beats =
+            BEATS_PER_MEASURE as f64 * self.numerator as f64 / self.denominator as f64;
+        let line_count = lines
+            .iter()
+            .filter(|line| matches!(line, KshLine::Notes(..)))
+            .count()
+            .max(1);
+        let line_beats = measure_beats / line_count as f64;
+
+        let mut idx = 0;
+        for line in lines.iter() {
+            match line {
+                KshLine::Notes(value) => {
+                    self.parse_chart_line(self.measure_beat + idx as f64 * line_beats, value);
+                    idx += 1;
+                }
+                KshLine::Option(line_idx, key, value) => {
+                    let beat = self.measure_beat + *line_idx as f64 * line_beats;
+                    let beat_val = (beat * TIME_FACTOR).round() as i64;
+                    match key.as_str() {
+                        "t" => {
+                            if let Some(bpm) = self.parse_to_float(value) {
+                                step.bpms.push(StepmaniaTimedBPM {
+                                    beat: beat_val,
+                                    bpm: (bpm * TIME_FACTOR).round() as i64,
+                                });
+                            }
+                        }
+                        "stop" => {
+                            if let Some(rows) = self.parse_to_float(value) {
+                                let bpm = step.bpm_at(beat_val) as f64 / TIME_FACTOR;
+                                if bpm > 0.0 {
+                                    step.stops.push(StepmaniaTimedDuration {
+                                        beat: beat_val,
+                                        duration: (rows / STOP_ROWS_PER_BEAT * 60_000.0 / bpm)
+                                            .round()
+                                            as i64,
+                                    });
+                                }
+                            }
+                        }
+                        // Effects and other options have no equivalent
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        self.measure_beat += measure_beats;
+    }
+
+    fn parse_header(
+        &mut self,
+        step: &mut StepmaniaFile,
+        chart: &mut StepmaniaChart,
+        key: &str,
+        value: &UnparsedPropertyValue,
+    ) {
+        let raw = value.raw.as_ref();
+        match key {
+            "title" => step.title = to_option(raw),
+            "artist" => step.artist = to_option(raw),
+            "effect" => chart.credit = raw.trim().to_string(),
+            "jacket" => step.jacket = to_option(raw),
+            "bg" => step.background = to_option(raw),
+            "m" => step.music = raw.split(CHAR_MUSIC_SEPARATOR).next().and_then(to_option),
+            "difficulty" => chart.difficulty = difficulty_from_name(raw),
+            "level" => match raw.trim().parse::<u16>() {
+                Ok(level) => chart.meter = level,
+                Err(_) => self.push_error(ParseErrorCode::KshInvalidNumber, value),
+            },
+            "o" => step.offset = self.parse_to_float(value).map(|o| -o.round() as i64),
+            "po" => step.sample_start = self.parse_to_float(value).map(|v| v.round() as i64),
+            "plength" => step.sample_length = self.parse_to_float(value).map(|v| v.round() as i64),
+            "beat" => {
+                if let Some((num, den)) = self.parse_to_time_signature(value) {
+                    self.numerator = num;
+                    self.denominator = den;
+                    step.time_signatures.push(StepmaniaTimedTimeSignature {
+                        beat: 0,
+                        numerator: num,
+                        denominator: den,
+                    });
+                }
+            }
+            "t" => match raw.split_once(CHAR_RANGE_SEPARATOR) {
+                // A range is only displayed, the actual BPM is set in the measures
+                Some((min, max)) => {
+                    if let (Ok(min), Ok(max)) =
+                        (min.trim().parse::<f64>(), max.trim().parse::<f64>())
+                    {
+                        step.display_bpm = Some(StepmaniaBPMRange::from_bounds(
+                            (min * TIME_FACTOR).round() as i64,
+                            (max * TIME_FACTOR).round() as i64,
+                        ));
+                    } else {
+                        self.push_error(ParseErrorCode::KshInvalidNumber, value);
+                    }
+                }
+                None => {
+                    if let Some(bpm) = self.parse_to_float(value) {
+                        step.bpms.push(StepmaniaTimedBPM {
+                            beat: 0,
+                            bpm: (bpm * TIME_FACTOR).round() as i64,
+                        });
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+
+    pub fn parse_from_string(&mut self, input: &str) -> Result<StepmaniaFile> {
+        let mut step = StepmaniaFile::default();
+        let mut chart = StepmaniaChart::default();
+        let mut in_header = true;
+        let mut measure: Vec<KshLine> = vec![];
+        let mut line_idx = 0;
+
+        // Nothing of a previously parsed file is carried over
+        self.numerator = 4;
+        self.denominator = 4;
+        self.measure_beat = 0.0;
+        self.holds = vec![false; self.column_count() as usize];
+        self.notes.clear();
+
+        for (idx, line) in input.lines().enumerate() {
+            let line = line.trim_start_matches(BYTE_ORDER_MARK).trim_end();
+            if line.is_empty() || line.starts_with(COMMENT_START) {
+                continue;
+            }
+
+            let value = UnparsedPropertyValue {
+                raw: Cow::Borrowed(line),
+                line: idx + 1,
+                column: 1,
+                len: line.chars().count(),
+                span: Span::of(input, line),
+                ..Default::default()
+            };
+
+            if line == MEASURE_SEPARATOR {
+                if in_header {
+                    in_header = false;
+                } else {
+                    self.parse_measure(&mut step, &measure);
+                }
+                measure.clear();
+                line_idx = 0;
+                continue;
+            }
+
+            match line.split_once(CHAR_OPTION_SEPARATOR) {
+                Some((key, raw)) => {
+                    let key = key.trim().to_string();
+                    let value = UnparsedPropertyValue {
+                        raw: Cow::Borrowed(raw),
+                        column: key.len() + 2,
+                        len: raw.chars().count(),
+                        span: Span::of(input, raw),
+                        ..value
+                    };
+                    if in_header {
+                        self.parse_header(&mut step, &mut chart, &key, &value);
+                    } else {
+                        measure.push(KshLine::Option(line_idx, key, value));
+                    }
+                }
+                None if !in_header && line.contains(CHAR_LANE_SEPARATOR) => {
+                    measure.push(KshLine::Notes(value));
+                    line_idx += 1;
+                }
+                // Custom effect definitions and the like
+                None if line.starts_with('#') => {}
+                None => self.push_error(ParseErrorCode::KshInvalidChartLine, &value),
+            }
+        }
+
+        if !measure.is_empty() {
+            self.parse_measure(&mut step, &measure);
+        }
+
+        // Holds which are still open end with the chart
+        for column in 0..self.holds.len() {
+            self.update_hold(self.measure_beat, column as u8, false);
+        }
+
+        let column_count = self.column_count();
+        chart.step_style = if self.lasers {
+            StepmaniaStepStyle::Custom("kshoot-single".to_string())
+        } else {
+            StepmaniaStepStyle::DanceSolo
+        };
+        chart.data = StepmaniaNoteData::from_positioned_notes(column_count, &self.notes);
+        step.notes.push(chart);
+        step.bpms.sort_by_key(|bpm| bpm.beat);
+
+        Ok(step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    const SAMPLE: &str = "\u{feff}title=Sample Song
+artist=Sample Artist
+effect=PreFiXAUT
+jacket=jacket.png
+difficulty=extended
+level=16
+t=120-240
+m=song.ogg;song_f.ogg
+o=1500
+beat=4/4
+--
+t=120
+1000|00|0-
+0200|00|:-
+0200|02|o-
+0000|00|--
+--
+beat=3/4
+t=240
+0010|10|--
+0000|10|--
+stop=24
+0000|00|--
+--
+";
+
+    #[test]
+    fn it_should_parse_header() {
+        let mut parser = KshParser::new();
+        let res = parser.parse_from_string(SAMPLE);
+        assert!(res.is_ok());
+        assert_eq!(parser.errors.len(), 0);
+
+        let step = res.unwrap();
+        assert_eq!(step.title.as_deref(), Some("Sample Song"));
+        assert_eq!(step.music.as_deref(), Some("song.ogg"));
+        assert_eq!(step.offset, Some(-1500));
+
+        assert_eq!(
+            step.display_bpm,
+            Some(StepmaniaBPMRange::Range(120000, 240000))
+        );
+
+        let chart = &step.notes[0];
+        assert_eq!(chart.credit, "PreFiXAUT");
+        assert_eq!(chart.meter, 16);
+        assert!(matches!(chart.difficulty, StepmaniaDifficulty::Hard));
+    }
+
+    #[test]
+    fn it_should_parse_timing() {
+        let mut parser = KshParser::new();
+        let step = parser.parse_from_string(SAMPLE).unwrap();
+
+        assert_eq!(step.bpms.len(), 2);
+        assert_eq!(step.bpms[1].beat, 4000);
+        assert_eq!(step.bpms[1].bpm, 240000);
+        assert_eq!(step.time_signatures.len(), 2);
+        assert_eq!(step.time_signatures[1].beat, 4000);
+        assert_eq!(step.time_signatures[1].numerator, 3);
+        // Half a beat at 240 BPM
+        assert_eq!(step.stops.len(), 1);
+        assert_eq!(step.stops[0].beat, 6000);
+        assert_eq!(step.stops[0].duration, 125);
+    }
+
+    #[test]
+    fn it_should_parse_bt_and_fx_lanes() {
+        let mut parser = KshParser::new();
+        let step = parser.parse_from_string(SAMPLE).unwrap();
+
+        assert_eq!(step.notes[0].data.column_count, 6);
+        assert_eq!(
+            notes_of(&step.notes[0]),
+            vec![
+                (0.0, 0, StepmaniaNoteType::Tap),
+                (1.0, 1, StepmaniaNoteType::HoldHead),
+                (2.0, 5, StepmaniaNoteType::Tap),
+                (3.0, 1, StepmaniaNoteType::Tail),
+                (4.0, 2, StepmaniaNoteType::Tap),
+                (4.0, 4, StepmaniaNoteType::HoldHead),
+                (6.0, 4, StepmaniaNoteType::Tail),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_reuse_the_parser() {
+        let mut parser = KshParser::new();
+        let first = parser.parse_from_string(SAMPLE).unwrap();
+        let second = parser.parse_from_string(SAMPLE).unwrap();
+
+        assert_eq!(notes_of(&second.notes[0]), notes_of(&first.notes[0]));
+        assert_eq!(second.time_signatures, first.time_signatures);
+        assert_eq!(second.stops, first.stops);
+    }
+
+    #[test]
+    fn it_should_keep_lasers() {
+        let mut parser = KshParser::new();
+        parser.lasers = true;
+        let step = parser.parse_from_string(SAMPLE).unwrap();
+
+        assert_eq!(step.notes[0].data.column_count, 8);
+        let lasers: Vec<(f64, u8, StepmaniaNoteType)> = notes_of(&step.notes[0])
+            .into_iter()
+            .filter(|n| n.1 >= 6)
+            .collect();
+        assert_eq!(
+            lasers,
+            vec![
+                (0.0, 6, StepmaniaNoteType::Laser(0)),
+                (2.0, 6, StepmaniaNoteType::Laser(50)),
+            ]
+        );
+    }
+}
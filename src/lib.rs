@@ -1,6 +1,26 @@
+pub mod analysis;
+pub mod assets;
+pub mod catalog;
 pub mod common;
+pub mod diff;
+pub mod difficulty;
+pub mod dtx;
+pub mod dwi;
+pub mod ksh;
+pub mod lint;
+pub mod malody;
+pub mod patterns;
+pub mod quaver;
+/// Serialization of the chart model. The reports, like the lint results or the catalog,
+/// can always be serialized, regardless of the `serde` feature.
+#[cfg(feature = "serde")]
+pub mod serialize;
 pub mod stepmania;
+pub mod stepstyle;
+pub mod transform;
 
 #[cfg(test)]
-mod tests {
-}
+mod test_utils;
+
+#[cfg(test)]
+mod tests {}
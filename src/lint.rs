@@ -0,0 +1,750 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use super::assets::*;
+use super::common::*;
+use super::stepmania::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StepmaniaLintSeverity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StepmaniaLintRule {
+    /// A hold or roll head without a tail
+    UnterminatedHold,
+    /// A tail without a hold or roll head
+    OrphanTail,
+    /// A measure which is not divided into 4, 8, 12, 16, 24, 32, 48, 64 or 192 rows
+    NonStandardRowCount,
+    /// A row which has less or more notes than the chart has columns
+    RowLengthMismatch,
+    /// Multiple BPM changes at the same beat
+    DuplicateBpm,
+    /// A stop which is inside of a warp and therefore skipped
+    StopInWarp,
+    /// The sample starts after the song has already ended
+    SampleStartBeyondLength,
+    /// A referenced file which doesn't exist
+    MissingFile,
+}
+
+impl StepmaniaLintRule {
+    pub fn id(&self) -> &'static str {
+        match self {
+            StepmaniaLintRule::UnterminatedHold => "unterminated-hold",
+            StepmaniaLintRule::OrphanTail => "orphan-tail",
+            StepmaniaLintRule::NonStandardRowCount => "non-standard-row-count",
+            StepmaniaLintRule::RowLengthMismatch => "row-length-mismatch",
+            StepmaniaLintRule::DuplicateBpm => "duplicate-bpm",
+            StepmaniaLintRule::StopInWarp => "stop-in-warp",
+            StepmaniaLintRule::SampleStartBeyondLength => "sample-start-beyond-length",
+            StepmaniaLintRule::MissingFile => "missing-file",
+        }
+    }
+
+    pub fn severity(&self) -> StepmaniaLintSeverity {
+        match self {
+            StepmaniaLintRule::UnterminatedHold
+            | StepmaniaLintRule::OrphanTail
+            | StepmaniaLintRule::RowLengthMismatch
+            | StepmaniaLintRule::MissingFile => StepmaniaLintSeverity::Error,
+            StepmaniaLintRule::DuplicateBpm
+            | StepmaniaLintRule::StopInWarp
+            | StepmaniaLintRule::SampleStartBeyondLength => StepmaniaLintSeverity::Warning,
+            StepmaniaLintRule::NonStandardRowCount => StepmaniaLintSeverity::Info,
+        }
+    }
+}
+
+/// A single problem found in a file
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepmaniaLintIssue {
+    pub rule: StepmaniaLintRule,
+    pub severity: StepmaniaLintSeverity,
+    pub message: String,
+    /// Index of the chart the issue is in
+    pub chart: Option<usize>,
+    /// The beat the issue is at
+    pub beat: Option<f64>,
+    /// The line in the file, if the issue was found while parsing
+    pub line: Option<usize>,
+    /// If the issue can be fixed automatically
+    pub fixable: bool,
+}
+
+/// The changes to apply to a file to fix an issue
+#[derive(Debug, Clone)]
+enum StepmaniaLintFix {
+    SetNote {
+        chart: usize,
+        measure: usize,
+        index: usize,
+        note_type: StepmaniaNoteType,
+    },
+    /// Fills up the last row of the measure with empty notes
+    PadMeasure {
+        chart: usize,
+        measure: usize,
+    },
+    /// Snaps the notes of the chart to the standard row counts
+    SnapRows {
+        chart: usize,
+    },
+    RemoveBpm(usize),
+    RemoveStop(usize),
+    ClearSampleStart,
+}
+
+fn issue(
+    rule: StepmaniaLintRule,
+    message: String,
+    chart: Option<usize>,
+    beat: Option<f64>,
+    fix: Option<StepmaniaLintFix>,
+) -> (StepmaniaLintIssue, Option<StepmaniaLintFix>) {
+    (
+        StepmaniaLintIssue {
+            rule,
+            severity: rule.severity(),
+            message,
+            chart,
+            beat,
+            line: None,
+            fixable: fix.is_some(),
+        },
+        fix,
+    )
+}
+
+#[derive(Debug, Default)]
+pub struct StepmaniaLinter {
+    /// The directory of the song, to check if referenced files exist
+    pub song_dir: Option<PathBuf>,
+    /// Length of the song in ms. If not set, the time of the last note is used.
+    pub song_length: Option<i64>,
+}
+
+impl StepmaniaLinter {
+    pub fn new() -> StepmaniaLinter {
+        StepmaniaLinter {
+            ..Default::default()
+        }
+    }
+
+    /// Finds all problems of the file
+    pub fn lint(&self, step: &StepmaniaFile) -> Vec<StepmaniaLintIssue> {
+        self.check(step)
+            .into_iter()
+            .map(|(issue, _)| issue)
+            .collect()
+    }
+
+    /// Fixes all problems of the file which can be fixed automatically.
+    /// Returns the issues which have been fixed.
+    pub fn fix(&self, step: &mut StepmaniaFile) -> Vec<StepmaniaLintIssue> {
+        let mut fixed = vec![];
+        let mut fixes = vec![];
+        for (issue, fix) in self.check(step) {
+            if let Some(fix) = fix {
+                fixed.push(issue);
+                fixes.push(fix);
+            }
+        }
+
+        // The notes are changed by their index before the rows are snapped, which changes the layout.
+        // Removals are applied from the back, so that the indices stay valid.
+        fixes.sort_by_key(|fix| match fix {
+            StepmaniaLintFix::SetNote { .. } => (0, 0),
+            StepmaniaLintFix::PadMeasure { .. } => (1, 0),
+            StepmaniaLintFix::SnapRows { .. } => (2, 0),
+            StepmaniaLintFix::RemoveBpm(idx) | StepmaniaLintFix::RemoveStop(idx) => {
+                (3, usize::MAX - idx)
+            }
+            StepmaniaLintFix::ClearSampleStart => (3, 0),
+        });
+        for fix in fixes {
+            match fix {
+                StepmaniaLintFix::SetNote {
+                    chart,
+                    measure,
+                    index,
+                    note_type,
+                } => step.notes[chart].data.notes[measure][index].note_type = note_type,
+                StepmaniaLintFix::PadMeasure { chart, measure } => {
+                    let data = &mut step.notes[chart].data;
+                    let column_count = data.column_count as usize;
+                    let notes = &mut data.notes[measure];
+                    let len = notes.len().div_ceil(column_count) * column_count;
+                    notes.resize_with(len, StepmaniaNote::default);
+                }
+                StepmaniaLintFix::SnapRows { chart } => {
+                    let data = &mut step.notes[chart].data;
+                    let positioned: Vec<StepmaniaPositionedNote> = data
+                        .positioned_notes()
+                        .into_iter()
+                        .map(|note| StepmaniaPositionedNote {
+                            keysound: note.keysound,
+                            ..StepmaniaPositionedNote::at_beat(
+                                note.beat(),
+                                note.column,
+                                note.note_type,
+                            )
+                        })
+                        .collect();
+                    *data =
+                        StepmaniaNoteData::from_positioned_notes(data.column_count, &positioned);
+                }
+                StepmaniaLintFix::RemoveBpm(idx) => {
+                    step.bpms.remove(idx);
+                }
+                StepmaniaLintFix::RemoveStop(idx) => {
+                    step.stops.remove(idx);
+                }
+                StepmaniaLintFix::ClearSampleStart => step.sample_start = None,
+            }
+        }
+
+        fixed
+    }
+
+    /// Converts the errors of the parser which are also lint rules into issues.
+    /// The parser already corrects them in the parsed file.
+    pub fn issues_from_parse_errors(errors: &[ParseError]) -> Vec<StepmaniaLintIssue> {
+        errors
+            .iter()
+            .filter(|error| error.code == ParseErrorCode::StepmaniaInvalidRowLength)
+            .map(|error| StepmaniaLintIssue {
+                rule: StepmaniaLintRule::RowLengthMismatch,
+                severity: StepmaniaLintRule::RowLengthMismatch.severity(),
+                message: format!("row has {} notes", error.len),
+                chart: None,
+                beat: None,
+                line: Some(error.line),
+                fixable: true,
+            })
+            .collect()
+    }
+
+    /// The errors of the parser for content which the writer can't represent, like split timing
+    /// or unknown properties. A fixed file must not be written again if there are any, as they would be lost.
+    pub fn unwritable_content(errors: &[ParseError]) -> Vec<&ParseError> {
+        errors
+            .iter()
+            .filter(|error| {
+                matches!(
+                    error.code,
+                    ParseErrorCode::StepmaniaUnsupportedSplitTiming
+                        | ParseErrorCode::StepmaniaUnknownPropertyName
+                )
+            })
+            .collect()
+    }
+
+    fn check(&self, step: &StepmaniaFile) -> Vec<(StepmaniaLintIssue, Option<StepmaniaLintFix>)> {
+        let mut issues = vec![];
+        for (idx, chart) in step.notes.iter().enumerate() {
+            self.check_chart(idx, chart, &mut issues);
+        }
+        self.check_timing(step, &mut issues);
+        self.check_files(step, &mut issues);
+
+        issues
+    }
+
+    fn check_chart(
+        &self,
+        chart_idx: usize,
+        chart: &StepmaniaChart,
+        issues: &mut Vec<(StepmaniaLintIssue, Option<StepmaniaLintFix>)>,
+    ) {
+        let data = &chart.data;
+        let column_count = data.column_count as usize;
+        if column_count == 0 {
+            return;
+        }
+
+        let mut non_standard = false;
+        for (measure, notes) in data.notes.iter().enumerate() {
+            if notes.len() % column_count != 0 {
+                issues.push(issue(
+                    StepmaniaLintRule::RowLengthMismatch,
+                    format!(
+                        "measure {} has {} notes, which is not a multiple of {} columns",
+                        measure,
+                        notes.len(),
+                        column_count
+                    ),
+                    Some(chart_idx),
+                    Some((measure * BEATS_PER_MEASURE) as f64),
+                    Some(StepmaniaLintFix::PadMeasure {
+                        chart: chart_idx,
+                        measure,
+                    }),
+                ));
+                continue;
+            }
+
+            let row_count = data.row_count(measure);
+            if !STANDARD_ROW_COUNTS.contains(&row_count) {
+                issues.push(issue(
+                    StepmaniaLintRule::NonStandardRowCount,
+                    format!("measure {} has {} rows", measure, row_count),
+                    Some(chart_idx),
+                    Some((measure * BEATS_PER_MEASURE) as f64),
+                    // All measures are snapped at once
+                    Some(StepmaniaLintFix::SnapRows { chart: chart_idx }).filter(|_| !non_standard),
+                ));
+                non_standard = true;
+            }
+        }
+
+        // The holds which are open, with the measure, index and beat of their head
+        let mut open: HashMap<u8, (usize, usize, f64)> = HashMap::new();
+        for note in data.positioned_notes() {
+            let index = note.row * column_count + note.column as usize;
+            let beat = note.beat();
+            let head = open.remove(&note.column);
+
+            if note.note_type == StepmaniaNoteType::Tail {
+                if head.is_none() {
+                    issues.push(issue(
+                        StepmaniaLintRule::OrphanTail,
+                        format!("tail in column {} has no hold head", note.column),
+                        Some(chart_idx),
+                        Some(beat),
+                        Some(StepmaniaLintFix::SetNote {
+                            chart: chart_idx,
+                            measure: note.measure,
+                            index,
+                            note_type: StepmaniaNoteType::Empty,
+                        }),
+                    ));
+                }
+                continue;
+            }
+
+            if let Some(head) = head {
+                issues.push(StepmaniaLinter::unterminated_hold(
+                    chart_idx,
+                    note.column,
+                    head,
+                ));
+            }
+            if matches!(
+                note.note_type,
+                StepmaniaNoteType::HoldHead | StepmaniaNoteType::RollHead
+            ) {
+                open.insert(note.column, (note.measure, index, beat));
+            }
+        }
+
+        let mut open: Vec<(u8, (usize, usize, f64))> = open.into_iter().collect();
+        open.sort_by_key(|(column, _)| *column);
+        for (column, head) in open {
+            issues.push(StepmaniaLinter::unterminated_hold(chart_idx, column, head));
+        }
+    }
+
+    fn unterminated_hold(
+        chart: usize,
+        column: u8,
+        (measure, index, beat): (usize, usize, f64),
+    ) -> (StepmaniaLintIssue, Option<StepmaniaLintFix>) {
+        issue(
+            StepmaniaLintRule::UnterminatedHold,
+            format!("hold in column {} has no tail", column),
+            Some(chart),
+            Some(beat),
+            // Without knowing where it should end, it's turned into a regular note
+            Some(StepmaniaLintFix::SetNote {
+                chart,
+                measure,
+                index,
+                note_type: StepmaniaNoteType::Tap,
+            }),
+        )
+    }
+
+    fn check_timing(
+        &self,
+        step: &StepmaniaFile,
+        issues: &mut Vec<(StepmaniaLintIssue, Option<StepmaniaLintFix>)>,
+    ) {
+        // Stepmania uses the last BPM defined for a beat
+        for (idx, bpm) in step.bpms.iter().enumerate() {
+            if step.bpms[idx + 1..]
+                .iter()
+                .any(|other| other.beat == bpm.beat)
+            {
+                issues.push(issue(
+                    StepmaniaLintRule::DuplicateBpm,
+                    format!(
+                        "BPM {} is overridden by another BPM at the same beat",
+                        bpm.bpm as f64 / 1000.0
+                    ),
+                    None,
+                    Some(bpm.beat as f64 / 1000.0),
+                    Some(StepmaniaLintFix::RemoveBpm(idx)),
+                ));
+            }
+        }
+
+        for (idx, stop) in step.stops.iter().enumerate() {
+            let in_warp = step
+                .warps
+                .iter()
+                .any(|warp| stop.beat >= warp.beat && stop.beat < warp.end_beat);
+            if in_warp {
+                issues.push(issue(
+                    StepmaniaLintRule::StopInWarp,
+                    "stop is inside of a warp".to_string(),
+                    None,
+                    Some(stop.beat as f64 / 1000.0),
+                    Some(StepmaniaLintFix::RemoveStop(idx)),
+                ));
+            }
+        }
+
+        let song_length = self.song_length.or_else(|| {
+            step.notes
+                .iter()
+                .flat_map(|chart| chart.data.positioned_notes().last().map(|note| note.beat()))
+                .map(|beat| step.beat_to_ms(beat).round() as i64)
+                .max()
+        });
+        if let (Some(start), Some(length)) = (step.sample_start, song_length) {
+            if start > length {
+                issues.push(issue(
+                    StepmaniaLintRule::SampleStartBeyondLength,
+                    format!(
+                        "sample starts at {}s, but the song is only {}s long",
+                        start as f64 / 1000.0,
+                        length as f64 / 1000.0
+                    ),
+                    None,
+                    None,
+                    Some(StepmaniaLintFix::ClearSampleStart),
+                ));
+            }
+        }
+    }
+
+    fn check_files(
+        &self,
+        step: &StepmaniaFile,
+        issues: &mut Vec<(StepmaniaLintIssue, Option<StepmaniaLintFix>)>,
+    ) {
+        let Some(dir) = &self.song_dir else {
+            return;
+        };
+
+        let validator = StepmaniaAssetValidator::new(dir);
+        for (name, path) in step.referenced_files() {
+            if validator.resolve(&path).is_none() {
+                issues.push(issue(
+                    StepmaniaLintRule::MissingFile,
+                    format!(
+                        "file \"{}\" of #{} does not exist",
+                        path,
+                        name.to_uppercase()
+                    ),
+                    None,
+                    None,
+                    None,
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules_of(issues: &[StepmaniaLintIssue]) -> Vec<StepmaniaLintRule> {
+        issues.iter().map(|issue| issue.rule).collect()
+    }
+
+    fn parse(data: &str) -> StepmaniaFile {
+        let mut parser = StepmaniaParser::new();
+        parser.parse_from_string(data).unwrap()
+    }
+
+    #[test]
+    fn it_should_lint_and_fix_holds() {
+        let mut step = parse(
+            "
+#NOTES:
+     dance-single:
+     :
+     Easy:
+     1:
+     0,0,0,0,0:
+2003
+0000
+3000
+0000
+,
+0020
+0000
+0000
+0000
+;
+",
+        );
+        let linter = StepmaniaLinter::new();
+        let issues = linter.lint(&step);
+        assert_eq!(
+            rules_of(&issues),
+            vec![
+                StepmaniaLintRule::OrphanTail,
+                StepmaniaLintRule::UnterminatedHold
+            ]
+        );
+        assert_eq!(issues[0].beat, Some(0.0));
+        assert_eq!(issues[1].beat, Some(4.0));
+        assert_eq!(issues[1].severity, StepmaniaLintSeverity::Error);
+
+        let fixed = linter.fix(&mut step);
+        assert_eq!(fixed.len(), 2);
+        assert!(linter.lint(&step).is_empty());
+
+        let notes: Vec<(u8, StepmaniaNoteType)> = step.notes[0]
+            .data
+            .positioned_notes()
+            .iter()
+            .map(|n| (n.column, n.note_type))
+            .collect();
+        assert_eq!(
+            notes,
+            vec![
+                (0, StepmaniaNoteType::HoldHead),
+                (0, StepmaniaNoteType::Tail),
+                (2, StepmaniaNoteType::Tap),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_lint_and_fix_row_counts() {
+        let mut step = parse(
+            "
+#NOTES:
+     dance-single:
+     :
+     Easy:
+     1:
+     0,0,0,0,0:
+1000
+0000
+0100
+0000
+0010
+0000
+;
+",
+        );
+        step.notes[0].data.notes[0].pop();
+
+        let linter = StepmaniaLinter::new();
+        assert_eq!(
+            rules_of(&linter.lint(&step)),
+            vec![StepmaniaLintRule::RowLengthMismatch]
+        );
+        linter.fix(&mut step);
+
+        assert_eq!(
+            rules_of(&linter.lint(&step)),
+            vec![StepmaniaLintRule::NonStandardRowCount]
+        );
+        linter.fix(&mut step);
+
+        assert!(linter.lint(&step).is_empty());
+        assert_eq!(step.notes[0].data.row_count(0), 12);
+    }
+
+    #[test]
+    fn it_should_fix_notes_before_snapping_rows() {
+        let mut step = parse(
+            "
+#NOTES:
+     dance-single:
+     :
+     Easy:
+     1:
+     0,0,0,0,0:
+1000
+0000
+0000
+0000
+0000
+0300
+0000
+0000
+0000
+0001
+;
+",
+        );
+        let linter = StepmaniaLinter::new();
+        assert_eq!(
+            rules_of(&linter.lint(&step)),
+            vec![
+                StepmaniaLintRule::NonStandardRowCount,
+                StepmaniaLintRule::OrphanTail
+            ]
+        );
+
+        assert_eq!(linter.fix(&mut step).len(), 2);
+        assert!(linter.lint(&step).is_empty());
+
+        let notes: Vec<(u8, StepmaniaNoteType)> = step.notes[0]
+            .data
+            .positioned_notes()
+            .iter()
+            .map(|n| (n.column, n.note_type))
+            .collect();
+        assert_eq!(
+            notes,
+            vec![(0, StepmaniaNoteType::Tap), (3, StepmaniaNoteType::Tap)]
+        );
+    }
+
+    #[test]
+    fn it_should_keep_unrelated_content_when_fixing() {
+        let step = parse(
+            "
+#TITLE:Song;
+#ARTIST:Artist;
+#OFFSET:-0.25;
+#BPMS:0=120,8=180;
+#STOPS:4=0.5;
+#LABELS:0=Start;
+#BGCHANGES:0=bg.png=1.000=0=0=1;
+#NOTES:
+     dance-single:
+     Someone:
+     Easy:
+     1:
+     0,0,0,0,0:
+1003
+0000
+0000
+0000
+;
+",
+        );
+        let mut fixed = step.clone();
+        assert_eq!(StepmaniaLinter::new().fix(&mut fixed).len(), 1);
+
+        let written = StepmaniaWriter::new().write_to_string(&fixed).unwrap();
+        let mut parser = StepmaniaParser::new();
+        let read = parser.parse_from_string(&written).unwrap();
+        assert!(parser.errors.is_empty());
+        assert_eq!(read, fixed);
+        // Only the notes have changed
+        assert_eq!(
+            StepmaniaFile {
+                notes: vec![],
+                ..read
+            },
+            StepmaniaFile {
+                notes: vec![],
+                ..step
+            }
+        );
+    }
+
+    #[test]
+    fn it_should_detect_content_which_cant_be_written() {
+        let mut parser = StepmaniaParser::new();
+        parser
+            .parse_from_string(
+                "#VERSION:0.83;
+#UNKNOWNTAG:value;
+#NOTEDATA:;
+#STEPSTYPE:dance-single;
+#BPMS:0=150,16=300;
+#NOTES:
+0003
+;
+",
+            )
+            .unwrap();
+
+        let lines: Vec<usize> = StepmaniaLinter::unwritable_content(&parser.errors)
+            .iter()
+            .map(|error| error.line)
+            .collect();
+        assert_eq!(lines, vec![2, 5]);
+    }
+
+    #[test]
+    fn it_should_lint_and_fix_timing() {
+        let mut step = parse(
+            "
+#BPMS:0=120,4=150,4=160;
+#STOPS:2=1,8=0.5;
+#SAMPLESTART:30;
+#NOTES:
+     dance-single:
+     :
+     Easy:
+     1:
+     0,0,0,0,0:
+1000
+0000
+0000
+0000
+;
+",
+        );
+        step.warps.push(StepmaniaWarp {
+            beat: 7000,
+            end_beat: 9000,
+        });
+
+        let linter = StepmaniaLinter::new();
+        assert_eq!(
+            rules_of(&linter.lint(&step)),
+            vec![
+                StepmaniaLintRule::DuplicateBpm,
+                StepmaniaLintRule::StopInWarp,
+                StepmaniaLintRule::SampleStartBeyondLength,
+            ]
+        );
+
+        linter.fix(&mut step);
+        assert!(linter.lint(&step).is_empty());
+        assert_eq!(step.bpms.len(), 2);
+        assert_eq!(step.bpms[1].bpm, 160000);
+        assert_eq!(step.stops.len(), 1);
+        assert_eq!(step.sample_start, None);
+    }
+
+    #[test]
+    fn it_should_report_missing_files() {
+        let step = StepmaniaFile {
+            music: Some("definitely-missing.ogg".to_string()),
+            banner: Some("Cargo.toml".to_string()),
+            ..Default::default()
+        };
+        let linter = StepmaniaLinter {
+            song_dir: Some(PathBuf::from(env!("CARGO_MANIFEST_DIR"))),
+            ..Default::default()
+        };
+        let issues = linter.lint(&step);
+
+        assert_eq!(rules_of(&issues), vec![StepmaniaLintRule::MissingFile]);
+        assert!(issues[0].message.contains("definitely-missing.ogg"));
+        assert!(!issues[0].fixable);
+    }
+}
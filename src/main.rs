@@ -1,16 +1,521 @@
 use std::fs;
+#[cfg(feature = "serde")]
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
-mod common;
-mod stepmania;
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Serialize;
 
-use crate::stepmania::StepmaniaParser;
+use rconv::analysis::StepmaniaDensity;
+use rconv::assets::{StepmaniaAssetReport, StepmaniaAssetValidator};
+use rconv::catalog::StepmaniaCatalog;
+use rconv::common::ParseError;
+use rconv::diff::{StepmaniaChartStatus, StepmaniaDiff};
+use rconv::difficulty::StepmaniaMeterEstimate;
+use rconv::dtx::DtxParser;
+use rconv::dwi::DwiParser;
+use rconv::ksh::KshParser;
+use rconv::lint::{StepmaniaLintIssue, StepmaniaLintSeverity, StepmaniaLinter};
+use rconv::malody::MalodyParser;
+use rconv::patterns::StepmaniaPatterns;
+use rconv::quaver::QuaverParser;
+#[cfg(feature = "schema")]
+use rconv::serialize::stepmania_file_schema;
+#[cfg(feature = "serde")]
+use rconv::serialize::StepmaniaSerializationFormat;
+use rconv::stepmania::{StepmaniaFile, StepmaniaParser, StepmaniaWriter};
 
-fn main() {
-    let file_name = "test-files/sample1.sm";
-    let unparsed_file = fs::read_to_string(file_name).expect("cannot read file");
-    let mut parser = StepmaniaParser::new();
+#[derive(Debug, Parser)]
+#[command(
+    name = "rconv",
+    version,
+    about = "Converts and analyzes rhythm game charts"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Analyzes every chart of the file and prints the results as JSON
+    Analyze {
+        /// The chart file (.sm, .ssc, .dwi, .ksh, .dtx, .mc or .qua)
+        file: PathBuf,
+        /// Pretty-print the JSON output
+        #[arg(long)]
+        pretty: bool,
+    },
+    /// Checks the files referenced by the simfile against its directory and prints the results as JSON.
+    /// Exits with status 1 if files are missing.
+    Assets {
+        /// The chart file (.sm, .ssc, .dwi, .ksh, .dtx, .mc or .qua)
+        file: PathBuf,
+        /// Look for a banner, background, CD title and jacket by their file names if they're not set
+        #[arg(long)]
+        discover: bool,
+        /// Pretty-print the JSON output
+        #[arg(long)]
+        pretty: bool,
+    },
+    /// Reads the simfile of every song folder in the directory and prints a catalog of the songs
+    Scan {
+        /// The directory with the song packs
+        dir: PathBuf,
+        /// The format of the catalog
+        #[arg(long, value_enum, default_value_t = CatalogFormat::Json)]
+        format: CatalogFormat,
+        /// Pretty-print the JSON output
+        #[arg(long)]
+        pretty: bool,
+        /// Only read the metadata and the chart headers, without note counts and lengths
+        #[arg(long)]
+        skip_notes: bool,
+    },
+    /// Converts the chart file to a simfile, or to a serialized form of the chart model
+    #[cfg(feature = "serde")]
+    Convert {
+        /// The chart file (.sm, .ssc, .dwi, .ksh, .dtx, .mc or .qua)
+        file: PathBuf,
+        /// The format to convert to
+        #[arg(long, value_enum)]
+        to: ConvertFormat,
+        /// Pretty-print the JSON output
+        #[arg(long)]
+        pretty: bool,
+        /// Write to the file instead of printing the result
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Prints the JSON Schema of the chart model, as written by `convert --to json`
+    #[cfg(feature = "schema")]
+    Schema {
+        /// Pretty-print the JSON output
+        #[arg(long)]
+        pretty: bool,
+    },
+    /// Compares the content of two chart files, like their metadata, timing and notes.
+    /// Exits with status 1 if they differ.
+    Diff {
+        /// The old chart file (.sm, .ssc, .dwi, .ksh, .dtx, .mc or .qua)
+        old: PathBuf,
+        /// The new chart file
+        new: PathBuf,
+        /// Print the differences as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Checks the file for common mistakes.
+    /// Exits with status 1 if errors are found.
+    Lint {
+        /// The chart file (.sm, .ssc, .dwi, .ksh, .dtx, .mc or .qua)
+        file: PathBuf,
+        /// Fix the issues which can be fixed automatically and write the file back (.sm and .ssc only)
+        #[arg(long)]
+        fix: bool,
+        /// Print the issues as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum CatalogFormat {
+    Json,
+    Csv,
+}
+
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ConvertFormat {
+    Sm,
+    Json,
+    Yaml,
+    Msgpack,
+}
+
+/// Reads the file with the parser matching its extension
+fn read_file(path: &Path) -> Result<(StepmaniaFile, Vec<ParseError>)> {
+    let content = fs::read_to_string(path)?;
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    match extension.as_str() {
+        "sm" | "ssc" => {
+            let mut parser = StepmaniaParser::new();
+            let step = parser.parse_from_string(&content)?;
+            Ok((step, parser.errors))
+        }
+        "dwi" => {
+            let mut parser = DwiParser::new();
+            let step = parser.parse_from_string(&content)?;
+            Ok((step, parser.errors))
+        }
+        "ksh" => {
+            let mut parser = KshParser::new();
+            let step = parser.parse_from_string(&content)?;
+            Ok((step, parser.errors))
+        }
+        "dtx" => {
+            let mut parser = DtxParser::new();
+            let step = parser.parse_from_string(&content)?;
+            Ok((step, parser.errors))
+        }
+        "mc" => {
+            let mut parser = MalodyParser::new();
+            let step = parser.parse_from_string(&content)?;
+            Ok((step, parser.errors))
+        }
+        "qua" => {
+            let mut parser = QuaverParser::new();
+            let step = parser.parse_from_string(&content)?;
+            Ok((step, parser.errors))
+        }
+        _ => bail!("unsupported file type: {}", path.display()),
+    }
+}
+
+fn print_json<T: Serialize>(value: &T, pretty: bool) -> Result<()> {
+    let output = if pretty {
+        serde_json::to_string_pretty(value)?
+    } else {
+        serde_json::to_string(value)?
+    };
+    println!("{}", output);
+
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct ChartAnalysis {
+    step_style: String,
+    difficulty: String,
+    meter: u16,
+    credit: String,
+    density: StepmaniaDensity,
+    /// Only available for dance-single and dance-double charts
+    patterns: Option<StepmaniaPatterns>,
+    estimate: StepmaniaMeterEstimate,
+}
+
+fn analyze(file: &Path, pretty: bool) -> Result<()> {
+    let (step, _) = read_file(file)?;
+    let charts: Vec<ChartAnalysis> = step
+        .notes
+        .iter()
+        .map(|chart| {
+            let density = StepmaniaDensity::from_chart(&step, chart);
+            let patterns = StepmaniaPatterns::from_chart(chart).ok();
+            let estimate =
+                StepmaniaMeterEstimate::from_analysis(&step, chart, &density, patterns.as_ref());
+
+            ChartAnalysis {
+                step_style: chart.step_style.name().to_string(),
+                difficulty: chart.difficulty.name().to_string(),
+                meter: chart.meter,
+                credit: chart.credit.clone(),
+                density,
+                patterns,
+                estimate,
+            }
+        })
+        .collect();
+
+    print_json(&charts, pretty)
+}
+
+fn assets(file: &Path, discover: bool, pretty: bool) -> Result<bool> {
+    let (mut step, _) = read_file(file)?;
+    let validator = StepmaniaAssetValidator::for_simfile(file);
+
+    #[derive(Serialize)]
+    struct AssetReport {
+        discovered: Vec<&'static str>,
+        #[serde(flatten)]
+        report: StepmaniaAssetReport,
+    }
+    let discovered = if discover {
+        validator.discover(&mut step)?
+    } else {
+        vec![]
+    };
+    let report = validator.validate(&step)?;
+    let valid = report.is_valid();
+    print_json(&AssetReport { discovered, report }, pretty)?;
+
+    Ok(valid)
+}
+
+fn scan(dir: &Path, format: CatalogFormat, pretty: bool, skip_notes: bool) -> Result<()> {
+    let catalog = StepmaniaCatalog::scan(dir, skip_notes)?;
+    match format {
+        CatalogFormat::Json => print_json(&catalog, pretty),
+        CatalogFormat::Csv => {
+            print!("{}", catalog.to_csv()?);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn convert(file: &Path, to: ConvertFormat, pretty: bool, output: Option<&Path>) -> Result<()> {
+    let (step, _) = read_file(file)?;
+    let data = match to {
+        ConvertFormat::Sm => StepmaniaWriter::new().write_to_string(&step)?.into_bytes(),
+        ConvertFormat::Json => {
+            let mut data = step.serialize_to(StepmaniaSerializationFormat::Json, pretty)?;
+            data.push(b'\n');
+            data
+        }
+        ConvertFormat::Yaml => step.serialize_to(StepmaniaSerializationFormat::Yaml, pretty)?,
+        ConvertFormat::Msgpack => {
+            step.serialize_to(StepmaniaSerializationFormat::MessagePack, pretty)?
+        }
+    };
+
+    match output {
+        Some(path) => fs::write(path, data)?,
+        None => std::io::stdout().write_all(&data)?,
+    }
+    Ok(())
+}
+
+/// Prints the differences, returns true if there are none
+fn diff(old: &Path, new: &Path, json: bool) -> Result<bool> {
+    let (old_step, _) = read_file(old)?;
+    let (new_step, _) = read_file(new)?;
+    let diff = StepmaniaDiff::between(&old_step, &new_step);
+
+    if json {
+        print_json(&diff, false)?;
+        return Ok(diff.is_empty());
+    }
+
+    let value = |value: &Option<String>| match value {
+        Some(value) => format!("{:?}", value),
+        None => "none".to_string(),
+    };
+    let number = |value: Option<f64>| match value {
+        Some(value) => value.to_string(),
+        None => "none".to_string(),
+    };
+    for change in diff.metadata.iter() {
+        println!(
+            "{}: {} -> {}",
+            change.property,
+            value(&change.old),
+            value(&change.new)
+        );
+    }
+    for (name, changes) in [("bpm", &diff.bpms), ("stop", &diff.stops)] {
+        for change in changes.iter() {
+            println!(
+                "{} at beat {}: {} -> {}",
+                name,
+                change.beat,
+                number(change.old),
+                number(change.new)
+            );
+        }
+    }
+    for change in diff.timing.iter() {
+        println!(
+            "{} at beat {}: {} -> {}",
+            change.property,
+            change.beat,
+            value(&change.old),
+            value(&change.new)
+        );
+    }
+    if diff.other_changes {
+        println!("other content has changed, like background changes or keysounds");
+    }
+    for chart in diff.charts.iter() {
+        match chart.status {
+            StepmaniaChartStatus::Added => println!("{}: added", chart.chart),
+            StepmaniaChartStatus::Removed => println!("{}: removed", chart.chart),
+            StepmaniaChartStatus::Changed => println!("{}:", chart.chart),
+        }
+        for change in chart.changes.iter() {
+            println!(
+                "  {}: {} -> {}",
+                change.property,
+                value(&change.old),
+                value(&change.new)
+            );
+        }
+        for note in chart.added_notes.iter() {
+            println!(
+                "  + {} at beat {}, column {}",
+                note.note_type, note.beat, note.column
+            );
+        }
+        for note in chart.removed_notes.iter() {
+            println!(
+                "  - {} at beat {}, column {}",
+                note.note_type, note.beat, note.column
+            );
+        }
+        for note in chart.moved_notes.iter() {
+            println!(
+                "  ~ {} at beat {}, column {} -> beat {}, column {}",
+                note.note_type, note.from_beat, note.from_column, note.to_beat, note.to_column
+            );
+        }
+    }
+
+    Ok(diff.is_empty())
+}
+
+fn print_issue(issue: &StepmaniaLintIssue) {
+    let severity = match issue.severity {
+        StepmaniaLintSeverity::Info => "info",
+        StepmaniaLintSeverity::Warning => "warning",
+        StepmaniaLintSeverity::Error => "error",
+    };
+    let mut location = vec![];
+    if let Some(line) = issue.line {
+        location.push(format!("line {}", line));
+    }
+    if let Some(chart) = issue.chart {
+        location.push(format!("chart {}", chart));
+    }
+    if let Some(beat) = issue.beat {
+        location.push(format!("beat {}", beat));
+    }
+
+    if location.is_empty() {
+        println!("{}[{}]: {}", severity, issue.rule.id(), issue.message);
+    } else {
+        println!(
+            "{}[{}] {}: {}",
+            severity,
+            issue.rule.id(),
+            location.join(", "),
+            issue.message
+        );
+    }
+}
+
+fn lint(file: &Path, fix: bool, json: bool) -> Result<bool> {
+    let (mut step, errors) = read_file(file)?;
+    let linter = StepmaniaLinter {
+        song_dir: Some(StepmaniaAssetValidator::for_simfile(file).song_dir),
+        ..Default::default()
+    };
+
+    let mut fixed = vec![];
+    if fix {
+        let extension = file
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        if extension != "sm" && extension != "ssc" {
+            bail!("only .sm and .ssc files can be fixed: {}", file.display());
+        }
+
+        let unwritable = StepmaniaLinter::unwritable_content(&errors);
+        if !unwritable.is_empty() {
+            let lines: Vec<String> = unwritable
+                .iter()
+                .map(|error| error.line.to_string())
+                .collect();
+            bail!(
+                "{} can't be fixed, as writing it would drop the unsupported content at line {}",
+                file.display(),
+                lines.join(", ")
+            );
+        }
+
+        // Row length mismatches are already repaired while parsing
+        fixed = StepmaniaLinter::issues_from_parse_errors(&errors);
+        fixed.extend(linter.fix(&mut step));
+        if !fixed.is_empty() {
+            let mut writer = StepmaniaWriter::new();
+            fs::write(file, writer.write_to_string(&step)?)?;
+        }
+    }
+
+    let mut issues = if fix {
+        vec![]
+    } else {
+        StepmaniaLinter::issues_from_parse_errors(&errors)
+    };
+    issues.extend(linter.lint(&step));
+
+    if json {
+        #[derive(Serialize)]
+        struct LintReport<'a> {
+            issues: &'a [StepmaniaLintIssue],
+            fixed: &'a [StepmaniaLintIssue],
+        }
+        print_json(
+            &LintReport {
+                issues: &issues,
+                fixed: &fixed,
+            },
+            false,
+        )?;
+    } else {
+        for issue in issues.iter() {
+            print_issue(issue);
+        }
+        if !fixed.is_empty() {
+            println!("fixed {} issues", fixed.len());
+        }
+    }
+
+    Ok(issues
+        .iter()
+        .all(|issue| issue.severity != StepmaniaLintSeverity::Error))
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
 
-    let step = parser.parse_from_string(&unparsed_file);
-    
-    println!("{:?}, err: {:?}", step, parser.errors);
+    match cli.command {
+        Command::Analyze { file, pretty } => analyze(&file, pretty),
+        Command::Assets {
+            file,
+            discover,
+            pretty,
+        } => {
+            if !assets(&file, discover, pretty)? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Scan {
+            dir,
+            format,
+            pretty,
+            skip_notes,
+        } => scan(&dir, format, pretty, skip_notes),
+        #[cfg(feature = "serde")]
+        Command::Convert {
+            file,
+            to,
+            pretty,
+            output,
+        } => convert(&file, to, pretty, output.as_deref()),
+        #[cfg(feature = "schema")]
+        Command::Schema { pretty } => print_json(&stepmania_file_schema(), pretty),
+        Command::Diff { old, new, json } => {
+            if !diff(&old, &new, json)? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Lint { file, fix, json } => {
+            if !lint(&file, fix, json)? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+    }
 }
@@ -0,0 +1,561 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::common::*;
+use super::stepmania::*;
+use super::stepstyle::*;
+
+/// The only mode we support, which is the key-mode (4K, 6K, 7K, ...)
+const MODE_KEY: u8 = 0;
+/// The note type which marks the background music of the chart
+const NOTE_TYPE_SOUND: u8 = 1;
+/// Precision of the beats and BPM in the Stepmania model
+const TIME_FACTOR: i64 = 1000;
+
+/// A beat in Malody is a triplet of `[beat, numerator, denominator]`
+type MalodyBeat = [i64; 3];
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct MalodySong {
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    artist: String,
+    /// Title in the original language, if `title` is romanized
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    titleorg: Option<String>,
+    /// Artist in the original language, if `artist` is romanized
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    artistorg: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct MalodyModeExtension {
+    #[serde(default)]
+    column: u8,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct MalodyMeta {
+    #[serde(rename = "$ver", default)]
+    ver: u32,
+    #[serde(default)]
+    creator: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    background: String,
+    /// The difficulty name of the chart
+    #[serde(default)]
+    version: String,
+    #[serde(default)]
+    mode: u8,
+    #[serde(default)]
+    song: MalodySong,
+    #[serde(default)]
+    mode_ext: MalodyModeExtension,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MalodyTimingPoint {
+    beat: MalodyBeat,
+    bpm: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MalodyEffect {
+    beat: MalodyBeat,
+    scroll: f64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct MalodyNote {
+    beat: MalodyBeat,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    endbeat: Option<MalodyBeat>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    column: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sound: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vol: Option<u32>,
+    /// Offset of the sound in ms
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    offset: Option<i64>,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    note_type: Option<u8>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct MalodyChart {
+    #[serde(default)]
+    meta: MalodyMeta,
+    #[serde(default)]
+    time: Vec<MalodyTimingPoint>,
+    #[serde(default)]
+    effect: Vec<MalodyEffect>,
+    #[serde(default)]
+    note: Vec<MalodyNote>,
+}
+
+/// Converts a Stepmania beat (in 1000s) to a Malody beat triplet
+fn to_malody_beat(beat: i64) -> MalodyBeat {
+    let beat = beat.max(0);
+    let rest = (beat % TIME_FACTOR) as usize;
+    let div = gcd(rest, TIME_FACTOR as usize) as i64;
+
+    [beat / TIME_FACTOR, rest as i64 / div, TIME_FACTOR / div]
+}
+
+#[derive(Debug, Default)]
+pub struct MalodyParser {
+    // All errors for the Parse-Result
+    pub errors: Vec<ParseError>,
+}
+
+impl MalodyParser {
+    pub fn new() -> MalodyParser {
+        MalodyParser {
+            ..Default::default()
+        }
+    }
+
+    fn push_error(&mut self, code: ParseErrorCode) {
+        self.errors.push(ParseError {
+            code,
+            line: 0,
+            column: 0,
+            len: 0,
+            span: None,
+        });
+    }
+
+    /// Normalizes the beat triplet to `(beat, numerator, denominator)` with the numerator
+    /// being smaller than the denominator.
+    fn parse_beat(&mut self, beat: &MalodyBeat) -> Option<(usize, usize, usize)> {
+        let [whole, numerator, denominator] = *beat;
+        if whole < 0 || numerator < 0 || denominator <= 0 {
+            self.push_error(ParseErrorCode::MalodyInvalidBeat);
+            return None;
+        }
+
+        let (whole, numerator, denominator) =
+            (whole as usize, numerator as usize, denominator as usize);
+
+        Some((
+            whole + numerator / denominator,
+            numerator % denominator,
+            denominator,
+        ))
+    }
+
+    /// Converts the beat triplet to a Stepmania beat (in 1000s)
+    fn parse_to_timed_beat(&mut self, beat: &MalodyBeat) -> Option<i64> {
+        let (whole, numerator, denominator) = self.parse_beat(beat)?;
+        let factor = TIME_FACTOR as usize;
+
+        Some((whole * factor + (numerator * factor + denominator / 2) / denominator) as i64)
+    }
+
+    fn parse_to_positioned_note(
+        &mut self,
+        beat: &MalodyBeat,
+        column: u8,
+        note_type: StepmaniaNoteType,
+    ) -> Option<StepmaniaPositionedNote> {
+        let (whole, numerator, denominator) = self.parse_beat(beat)?;
+
+        Some(StepmaniaPositionedNote {
+            measure: whole / BEATS_PER_MEASURE,
+            row: (whole % BEATS_PER_MEASURE) * denominator + numerator,
+            row_count: BEATS_PER_MEASURE * denominator,
+            column,
+            note_type,
+            keysound: None,
+        })
+    }
+
+    pub fn parse_from_string(&mut self, input: &str) -> Result<StepmaniaFile> {
+        let mc: MalodyChart = match serde_json::from_str(input) {
+            Ok(mc) => mc,
+            Err(err) => {
+                self.errors.push(ParseError {
+                    code: ParseErrorCode::MalodyInvalidJson,
+                    line: err.line(),
+                    column: err.column(),
+                    len: 0,
+                    span: None,
+                });
+                bail!("invalid malody chart: {}", err);
+            }
+        };
+
+        if mc.meta.mode != MODE_KEY {
+            self.push_error(ParseErrorCode::MalodyUnsupportedMode);
+            bail!("unsupported malody mode {}", mc.meta.mode);
+        }
+
+        let mut step = StepmaniaFile {
+            background: Some(mc.meta.background).filter(|v| !v.is_empty()),
+            ..Default::default()
+        };
+
+        // Malody stores the romanized version in the regular fields
+        match mc.meta.song.titleorg {
+            Some(original) => {
+                step.title = Some(original);
+                step.title_translit = Some(mc.meta.song.title);
+            }
+            None => step.title = Some(mc.meta.song.title),
+        }
+        match mc.meta.song.artistorg {
+            Some(original) => {
+                step.artist = Some(original);
+                step.artist_translit = Some(mc.meta.song.artist);
+            }
+            None => step.artist = Some(mc.meta.song.artist),
+        }
+
+        for point in mc.time.iter() {
+            if let Some(beat) = self.parse_to_timed_beat(&point.beat) {
+                step.bpms.push(StepmaniaTimedBPM {
+                    beat,
+                    bpm: (point.bpm * TIME_FACTOR as f64).round() as i64,
+                });
+            }
+        }
+
+        for effect in mc.effect.iter() {
+            if let Some(beat) = self.parse_to_timed_beat(&effect.beat) {
+                step.scrolls.push(StepmaniaTimedScrollSpeedChange {
+                    beat,
+                    factor: effect.scroll as f32,
+                });
+            }
+        }
+
+        let column_count = match mc.meta.mode_ext.column {
+            0 => mc
+                .note
+                .iter()
+                .filter_map(|n| n.column)
+                .max()
+                .map_or(0, |c| c + 1),
+            count => count,
+        };
+        let mut positioned: Vec<StepmaniaPositionedNote> = vec![];
+
+        for note in mc.note.iter() {
+            // The sound note is the audio track of the chart
+            if note.note_type == Some(NOTE_TYPE_SOUND) || note.column.is_none() {
+                if let Some(sound) = &note.sound {
+                    step.music = Some(sound.clone());
+                    step.offset = Some(-note.offset.unwrap_or(0));
+                }
+                continue;
+            }
+
+            let column = note.column.unwrap_or(0);
+            if column >= column_count {
+                self.push_error(ParseErrorCode::MalodyInvalidColumn);
+                continue;
+            }
+
+            match &note.endbeat {
+                Some(end) => {
+                    let head = self.parse_to_positioned_note(
+                        &note.beat,
+                        column,
+                        StepmaniaNoteType::HoldHead,
+                    );
+                    let tail = self.parse_to_positioned_note(end, column, StepmaniaNoteType::Tail);
+                    if let (Some(head), Some(tail)) = (head, tail) {
+                        positioned.push(head);
+                        positioned.push(tail);
+                    }
+                }
+                None => {
+                    if let Some(tap) =
+                        self.parse_to_positioned_note(&note.beat, column, StepmaniaNoteType::Tap)
+                    {
+                        positioned.push(tap);
+                    }
+                }
+            }
+        }
+
+        // Key counts without an equivalent in Stepmania keep their columns with a custom step-style
+        let step_style = StepmaniaStepStyle::for_column_count(column_count)
+            .unwrap_or_else(|| StepmaniaStepStyle::Custom(format!("malody-{}k", column_count)));
+        step.notes.push(StepmaniaChart {
+            name: Some(mc.meta.version).filter(|v| !v.is_empty()),
+            step_style,
+            credit: mc.meta.creator,
+            data: StepmaniaNoteData::from_positioned_notes(column_count, &positioned),
+            ..Default::default()
+        });
+
+        Ok(step)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MalodyWriter {}
+
+impl MalodyWriter {
+    pub fn new() -> MalodyWriter {
+        MalodyWriter {}
+    }
+
+    /// Converts the chart of the file to a Malody key-mode chart.
+    /// Malody has no equivalent for mines, fakes, keysounds and rolls, which are therefore
+    /// either dropped or written as regular holds.
+    pub fn write_to_string(&mut self, step: &StepmaniaFile) -> Result<String> {
+        let chart = step
+            .notes
+            .first()
+            .ok_or_else(|| anyhow!("file does not contain a chart"))?;
+
+        let (title, titleorg) = match (&step.title, &step.title_translit) {
+            (Some(title), Some(translit)) => (translit.clone(), Some(title.clone())),
+            (title, _) => (title.clone().unwrap_or_default(), None),
+        };
+        let (artist, artistorg) = match (&step.artist, &step.artist_translit) {
+            (Some(artist), Some(translit)) => (translit.clone(), Some(artist.clone())),
+            (artist, _) => (artist.clone().unwrap_or_default(), None),
+        };
+
+        let mut mc = MalodyChart {
+            meta: MalodyMeta {
+                ver: 0,
+                creator: chart.credit.clone(),
+                background: step.background.clone().unwrap_or_default(),
+                version: chart
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("{:?}", chart.difficulty)),
+                mode: MODE_KEY,
+                song: MalodySong {
+                    title,
+                    artist,
+                    titleorg,
+                    artistorg,
+                },
+                mode_ext: MalodyModeExtension {
+                    column: chart.data.column_count,
+                },
+            },
+            time: step
+                .bpms
+                .iter()
+                .map(|bpm| MalodyTimingPoint {
+                    beat: to_malody_beat(bpm.beat),
+                    bpm: bpm.bpm as f64 / TIME_FACTOR as f64,
+                })
+                .collect(),
+            effect: step
+                .scrolls
+                .iter()
+                .map(|scroll| MalodyEffect {
+                    beat: to_malody_beat(scroll.beat),
+                    scroll: scroll.factor as f64,
+                })
+                .collect(),
+            note: vec![],
+        };
+
+        // Open hold heads per column, which are closed by the next tail
+        let mut heads: Vec<Option<MalodyBeat>> = vec![None; chart.data.column_count as usize];
+
+        for note in chart.data.positioned_notes() {
+            let (whole, numerator, denominator) = note.beat_fraction();
+            let beat = [whole as i64, numerator as i64, denominator as i64];
+            let column = note.column as usize;
+
+            match note.note_type {
+                StepmaniaNoteType::Tap | StepmaniaNoteType::Lift => mc.note.push(MalodyNote {
+                    beat,
+                    column: Some(note.column),
+                    ..Default::default()
+                }),
+                StepmaniaNoteType::HoldHead | StepmaniaNoteType::RollHead => {
+                    heads[column] = Some(beat);
+                }
+                StepmaniaNoteType::Tail => {
+                    if let Some(head) = heads[column].take() {
+                        mc.note.push(MalodyNote {
+                            beat: head,
+                            endbeat: Some(beat),
+                            column: Some(note.column),
+                            ..Default::default()
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(music) = &step.music {
+            mc.note.push(MalodyNote {
+                beat: [0, 0, 1],
+                sound: Some(music.clone()),
+                vol: Some(100),
+                offset: Some(-step.offset.unwrap_or(0)),
+                note_type: Some(NOTE_TYPE_SOUND),
+                ..Default::default()
+            });
+        }
+
+        Ok(serde_json::to_string(&mc)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = r#"{
+        "meta": {
+            "$ver": 0,
+            "creator": "PreFiXAUT",
+            "background": "bg.jpg",
+            "version": "4K Hard",
+            "mode": 0,
+            "song": { "title": "Zombie Circus", "artist": "Camellia", "titleorg": "ゾンビー・サーカス" },
+            "mode_ext": { "column": 4 }
+        },
+        "time": [{ "beat": [0, 0, 1], "bpm": 210.0 }, { "beat": [4, 1, 3], "bpm": 120.5 }],
+        "effect": [{ "beat": [2, 1, 2], "scroll": 0.5 }],
+        "note": [
+            { "beat": [0, 0, 1], "column": 0 },
+            { "beat": [0, 1, 4], "column": 3 },
+            { "beat": [1, 0, 1], "endbeat": [2, 1, 2], "column": 1 },
+            { "beat": [0, 0, 1], "sound": "song.ogg", "vol": 100, "offset": 246, "type": 1 }
+        ]
+    }"#;
+
+    #[test]
+    fn it_should_parse_meta() {
+        let mut parser = MalodyParser::new();
+        let res = parser.parse_from_string(SAMPLE);
+        assert!(res.is_ok());
+        assert_eq!(parser.errors.len(), 0);
+
+        let mut step = res.unwrap();
+        assert_eq!(step.title.unwrap(), "ゾンビー・サーカス");
+        assert_eq!(step.title_translit.unwrap(), "Zombie Circus");
+        assert_eq!(step.artist.unwrap(), "Camellia");
+        assert_eq!(step.background.unwrap(), "bg.jpg");
+        assert_eq!(step.music.unwrap(), "song.ogg");
+        assert_eq!(step.offset.unwrap(), -246);
+
+        let chart = step.notes.remove(0);
+        assert_eq!(chart.name.unwrap(), "4K Hard");
+        assert_eq!(chart.credit, "PreFiXAUT");
+        assert_eq!(chart.step_style, StepmaniaStepStyle::DanceSingle);
+    }
+
+    #[test]
+    fn it_should_parse_timing() {
+        let mut parser = MalodyParser::new();
+        let step = parser.parse_from_string(SAMPLE).unwrap();
+
+        assert_eq!(step.bpms.len(), 2);
+        assert_eq!(step.bpms[1].beat, 4333);
+        assert_eq!(step.bpms[1].bpm, 120500);
+        assert_eq!(step.scrolls.len(), 1);
+        assert_eq!(step.scrolls[0].beat, 2500);
+        assert_eq!(step.scrolls[0].factor, 0.5);
+    }
+
+    #[test]
+    fn it_should_parse_notes() {
+        let mut parser = MalodyParser::new();
+        let mut step = parser.parse_from_string(SAMPLE).unwrap();
+        let data = step.notes.remove(0).data;
+
+        assert_eq!(data.column_count, 4);
+        assert_eq!(data.row_count(0), 16);
+
+        let notes = data.positioned_notes();
+        let types: Vec<(f64, u8, StepmaniaNoteType)> = notes
+            .iter()
+            .map(|n| (n.beat(), n.column, n.note_type))
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                (0.0, 0, StepmaniaNoteType::Tap),
+                (0.25, 3, StepmaniaNoteType::Tap),
+                (1.0, 1, StepmaniaNoteType::HoldHead),
+                (2.5, 1, StepmaniaNoteType::Tail),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_keep_unknown_key_counts() {
+        let mut parser = MalodyParser::new();
+        let step = parser
+            .parse_from_string(
+                r#"{
+                    "meta": { "mode": 0, "mode_ext": { "column": 12 } },
+                    "time": [{ "beat": [0, 0, 1], "bpm": 120.0 }],
+                    "note": [{ "beat": [0, 0, 1], "column": 11 }]
+                }"#,
+            )
+            .unwrap();
+
+        let chart = &step.notes[0];
+        assert_eq!(
+            chart.step_style,
+            StepmaniaStepStyle::Custom("malody-12k".to_string())
+        );
+        assert_eq!(chart.data.column_count, 12);
+        assert_eq!(chart.data.positioned_notes()[0].column, 11);
+
+        // The columns are kept when it's written as a Stepmania file
+        let written = StepmaniaWriter::new().write_to_string(&step).unwrap();
+        let read = StepmaniaParser::new().parse_from_string(&written).unwrap();
+        assert_eq!(read.notes[0].step_style, chart.step_style);
+        assert_eq!(read.notes[0].data, chart.data);
+    }
+
+    #[test]
+    fn it_should_reject_other_modes() {
+        let mut parser = MalodyParser::new();
+        let res = parser.parse_from_string(r#"{ "meta": { "mode": 3 }, "time": [], "note": [] }"#);
+        assert!(res.is_err());
+        assert_eq!(parser.errors[0].code, ParseErrorCode::MalodyUnsupportedMode);
+    }
+
+    #[test]
+    fn it_should_write_parsed_chart() {
+        let mut parser = MalodyParser::new();
+        let mut step = parser.parse_from_string(SAMPLE).unwrap();
+        let written = MalodyWriter::new().write_to_string(&step).unwrap();
+
+        let mut parser = MalodyParser::new();
+        let mut reparsed = parser.parse_from_string(&written).unwrap();
+        assert_eq!(parser.errors.len(), 0);
+        assert_eq!(reparsed.title, Some("ゾンビー・サーカス".to_string()));
+        assert_eq!(reparsed.offset, Some(-246));
+        assert_eq!(reparsed.bpms[1].beat, 4333);
+
+        let original: Vec<(f64, u8)> = step
+            .notes
+            .remove(0)
+            .data
+            .positioned_notes()
+            .iter()
+            .map(|n| (n.beat(), n.column))
+            .collect();
+        let roundtrip: Vec<(f64, u8)> = reparsed
+            .notes
+            .remove(0)
+            .data
+            .positioned_notes()
+            .iter()
+            .map(|n| (n.beat(), n.column))
+            .collect();
+        assert_eq!(original, roundtrip);
+    }
+}
@@ -0,0 +1,447 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use super::stepmania::*;
+use super::stepstyle::*;
+
+/// Steps which are at most this many beats apart are part of the same stream
+const STREAM_GAP: f64 = 0.25;
+/// After a gap of this many beats, the footing starts anew
+const BREAK_GAP: f64 = 1.0;
+/// How many alternating steps between two columns make up a drill
+const DRILL_MIN_STEPS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StepmaniaPatternKind {
+    /// Two notes at the same time
+    Jump,
+    /// Three notes at the same time
+    Hand,
+    /// Four or more notes at the same time
+    Quad,
+    /// The same column hit again with the same foot
+    Jack,
+    /// A foot moving between up and down, passing the center
+    Candle,
+    /// A foot crossing over the other one
+    Crossover,
+    /// The same column hit again with the other foot during a stream
+    Footswitch,
+    /// Steps alternating between two columns
+    Drill,
+    /// The same foot hitting two different columns in a row
+    Doublestep,
+}
+
+/// A single occurrence of a pattern
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepmaniaPattern {
+    pub kind: StepmaniaPatternKind,
+    /// The beat where the pattern starts
+    pub beat: f64,
+    /// The columns involved in the pattern
+    pub columns: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Foot {
+    Left,
+    Right,
+}
+
+impl Foot {
+    fn other(&self) -> Foot {
+        match self {
+            Foot::Left => Foot::Right,
+            Foot::Right => Foot::Left,
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Foot::Left => 0,
+            Foot::Right => 1,
+        }
+    }
+}
+
+/// A row of notes which have to be hit at the same time
+struct Row {
+    beat: f64,
+    columns: Vec<u8>,
+}
+
+/// The patterns found in a dance chart.
+/// The footing is guessed by alternating feet, which is what most players do in streams.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct StepmaniaPatterns {
+    pub jumps: usize,
+    pub hands: usize,
+    pub quads: usize,
+    pub jacks: usize,
+    pub candles: usize,
+    pub crossovers: usize,
+    pub footswitches: usize,
+    pub drills: usize,
+    pub doublesteps: usize,
+    /// Every found pattern, ordered by beat
+    pub patterns: Vec<StepmaniaPattern>,
+}
+
+impl StepmaniaPatterns {
+    /// The amount of patterns of the kind
+    pub fn count(&self, kind: StepmaniaPatternKind) -> usize {
+        match kind {
+            StepmaniaPatternKind::Jump => self.jumps,
+            StepmaniaPatternKind::Hand => self.hands,
+            StepmaniaPatternKind::Quad => self.quads,
+            StepmaniaPatternKind::Jack => self.jacks,
+            StepmaniaPatternKind::Candle => self.candles,
+            StepmaniaPatternKind::Crossover => self.crossovers,
+            StepmaniaPatternKind::Footswitch => self.footswitches,
+            StepmaniaPatternKind::Drill => self.drills,
+            StepmaniaPatternKind::Doublestep => self.doublesteps,
+        }
+    }
+
+    fn push(&mut self, kind: StepmaniaPatternKind, beat: f64, columns: Vec<u8>) {
+        let count = match kind {
+            StepmaniaPatternKind::Jump => &mut self.jumps,
+            StepmaniaPatternKind::Hand => &mut self.hands,
+            StepmaniaPatternKind::Quad => &mut self.quads,
+            StepmaniaPatternKind::Jack => &mut self.jacks,
+            StepmaniaPatternKind::Candle => &mut self.candles,
+            StepmaniaPatternKind::Crossover => &mut self.crossovers,
+            StepmaniaPatternKind::Footswitch => &mut self.footswitches,
+            StepmaniaPatternKind::Drill => &mut self.drills,
+            StepmaniaPatternKind::Doublestep => &mut self.doublesteps,
+        };
+        *count += 1;
+        self.patterns.push(StepmaniaPattern {
+            kind,
+            beat,
+            columns,
+        });
+    }
+
+    /// Finds the patterns of a dance-single or dance-double chart
+    pub fn from_chart(chart: &StepmaniaChart) -> Result<StepmaniaPatterns> {
+        if !matches!(
+            chart.step_style,
+            StepmaniaStepStyle::DanceSingle | StepmaniaStepStyle::DanceDouble
+        ) {
+            bail!(
+                "patterns can only be found in dance-single and dance-double charts, not {}",
+                chart.step_style.name()
+            );
+        }
+        let lanes = chart.step_style.columns();
+
+        // Horizontal position of the columns, to know when feet cross
+        let positions: Vec<u8> = lanes
+            .iter()
+            .map(|column| {
+                column.pad * 3
+                    + match column.lane {
+                        StepmaniaLane::Left => 0,
+                        StepmaniaLane::Right => 2,
+                        _ => 1,
+                    }
+            })
+            .collect();
+        let is_vertical = |column: u8| {
+            matches!(
+                lanes[column as usize].lane,
+                StepmaniaLane::Up | StepmaniaLane::Down
+            )
+        };
+
+        let mut rows: Vec<Row> = vec![];
+        // The beat where the holds end, by their column and start beat
+        let mut hold_ends: HashMap<(u8, u64), f64> = HashMap::new();
+        let mut hold_starts: Vec<(f64, u8)> = vec![];
+        for note in chart.data.positioned_notes() {
+            let beat = note.beat();
+            match note.note_type {
+                StepmaniaNoteType::Tap | StepmaniaNoteType::Lift => {}
+                StepmaniaNoteType::HoldHead | StepmaniaNoteType::RollHead => {
+                    hold_starts.push((beat, note.column));
+                }
+                StepmaniaNoteType::Tail => {
+                    if let Some(idx) = hold_starts.iter().position(|(_, c)| *c == note.column) {
+                        let (start, column) = hold_starts.remove(idx);
+                        hold_ends.insert((column, start.to_bits()), beat);
+                    }
+                    continue;
+                }
+                _ => continue,
+            }
+
+            match rows.last_mut() {
+                Some(row) if (row.beat - beat).abs() < 0.0001 => row.columns.push(note.column),
+                _ => rows.push(Row {
+                    beat,
+                    columns: vec![note.column],
+                }),
+            }
+        }
+
+        let mut patterns = StepmaniaPatterns::default();
+        // The column each foot is on, and until which beat it's holding it
+        let mut feet: [Option<u8>; 2] = [None, None];
+        let mut holding: [Option<f64>; 2] = [None, None];
+        let mut last_foot: Option<Foot> = None;
+        let mut last_row: Option<&Row> = None;
+        let mut drill: (usize, f64) = (0, 0.0);
+
+        for (idx, row) in rows.iter().enumerate() {
+            let gap = last_row.map_or(f64::MAX, |last| row.beat - last.beat);
+            for foot in [Foot::Left, Foot::Right] {
+                if holding[foot.index()].is_some_and(|end| end <= row.beat) {
+                    holding[foot.index()] = None;
+                }
+            }
+
+            // Jacks are counted for every repeated column, unless there's a break in between
+            let repeated: Vec<u8> = match last_row {
+                Some(last) if gap <= BREAK_GAP => row
+                    .columns
+                    .iter()
+                    .filter(|c| last.columns.contains(c))
+                    .copied()
+                    .collect(),
+                _ => vec![],
+            };
+
+            if row.columns.len() > 1 {
+                let kind = match row.columns.len() {
+                    2 => StepmaniaPatternKind::Jump,
+                    3 => StepmaniaPatternKind::Hand,
+                    _ => StepmaniaPatternKind::Quad,
+                };
+                patterns.push(kind, row.beat, row.columns.clone());
+                if !repeated.is_empty() {
+                    patterns.push(StepmaniaPatternKind::Jack, row.beat, repeated);
+                }
+
+                // Both feet land on the outermost columns
+                let left = *row
+                    .columns
+                    .iter()
+                    .min_by_key(|c| positions[**c as usize])
+                    .unwrap();
+                let right = *row
+                    .columns
+                    .iter()
+                    .max_by_key(|c| positions[**c as usize])
+                    .unwrap();
+                feet = [Some(left), Some(right)];
+                for (foot, column) in [(Foot::Left, left), (Foot::Right, right)] {
+                    holding[foot.index()] = hold_ends.get(&(column, row.beat.to_bits())).copied();
+                }
+                last_foot = None;
+                drill = (0, row.beat);
+                last_row = Some(row);
+                continue;
+            }
+
+            let column = row.columns[0];
+            let position = positions[column as usize];
+            let in_stream = gap <= STREAM_GAP;
+
+            let nearest = |feet: &[Option<u8>; 2]| match feet {
+                [Some(left), Some(right)] => {
+                    let to_left = positions[*left as usize].abs_diff(position);
+                    let to_right = positions[*right as usize].abs_diff(position);
+                    if to_right < to_left {
+                        Foot::Right
+                    } else {
+                        Foot::Left
+                    }
+                }
+                _ => match lanes[column as usize].lane {
+                    StepmaniaLane::Right => Foot::Right,
+                    _ => Foot::Left,
+                },
+            };
+
+            let mut foot = match (holding, last_foot) {
+                ([Some(_), None], _) => Foot::Right,
+                ([None, Some(_)], _) => Foot::Left,
+                (_, Some(last)) if gap <= BREAK_GAP => {
+                    if repeated.contains(&column) && !(in_stream && is_vertical(column)) {
+                        // Jacks are hit with the same foot
+                        last
+                    } else {
+                        last.other()
+                    }
+                }
+                _ => nearest(&feet),
+            };
+
+            if repeated.contains(&column) {
+                let kind = if foot == last_foot.unwrap_or(foot) {
+                    StepmaniaPatternKind::Jack
+                } else {
+                    StepmaniaPatternKind::Footswitch
+                };
+                patterns.push(kind, row.beat, vec![column]);
+            } else if let Some(other) = feet[foot.other().index()] {
+                let other_position = positions[other as usize];
+                let crossing = match foot {
+                    Foot::Left => position > other_position,
+                    Foot::Right => position < other_position,
+                };
+                if crossing && holding[foot.other().index()].is_none() {
+                    if is_vertical(other) {
+                        patterns.push(
+                            StepmaniaPatternKind::Crossover,
+                            row.beat,
+                            vec![other, column],
+                        );
+                    } else if last_foot.is_some() && gap <= BREAK_GAP {
+                        // Nobody crosses over from the side arrows, so the other foot steps again
+                        patterns.push(
+                            StepmaniaPatternKind::Doublestep,
+                            row.beat,
+                            vec![feet[foot.index()].unwrap_or(column), column],
+                        );
+                        foot = foot.other();
+                    }
+                }
+            }
+
+            if let Some(previous) = feet[foot.index()] {
+                let same_pad = lanes[previous as usize].pad == lanes[column as usize].pad;
+                if same_pad && previous != column && is_vertical(previous) && is_vertical(column) {
+                    patterns.push(
+                        StepmaniaPatternKind::Candle,
+                        row.beat,
+                        vec![previous, column],
+                    );
+                }
+            }
+
+            // Drills alternate between two columns
+            let alternating = idx >= 2
+                && rows[idx - 2].columns == row.columns
+                && rows[idx - 1].columns.len() == 1
+                && rows[idx - 1].columns[0] != column
+                && gap <= BREAK_GAP;
+            if alternating {
+                if drill.0 == 0 {
+                    drill = (3, rows[idx - 2].beat);
+                } else {
+                    drill.0 += 1;
+                }
+                if drill.0 == DRILL_MIN_STEPS {
+                    let columns = vec![column, rows[idx - 1].columns[0]];
+                    patterns.push(StepmaniaPatternKind::Drill, drill.1, columns);
+                }
+            } else {
+                drill = (0, row.beat);
+            }
+
+            feet[foot.index()] = Some(column);
+            holding[foot.index()] = hold_ends.get(&(column, row.beat.to_bits())).copied();
+            last_foot = Some(foot);
+            last_row = Some(row);
+        }
+
+        patterns.patterns.sort_by(|a, b| a.beat.total_cmp(&b.beat));
+
+        Ok(patterns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn it_should_count_chords_and_jacks() {
+        let chart = chart_from(
+            StepmaniaStepStyle::DanceSingle,
+            &taps(chords(&[
+                (0.0, &[0, 3]),
+                (1.0, &[0, 1, 3]),
+                (2.0, &[0, 1, 2, 3]),
+                (4.0, &[0]),
+                (4.5, &[0]),
+                (5.0, &[0]),
+            ])),
+        );
+        let patterns = StepmaniaPatterns::from_chart(&chart).unwrap();
+
+        assert_eq!(patterns.jumps, 1);
+        assert_eq!(patterns.hands, 1);
+        assert_eq!(patterns.quads, 1);
+        // Two jacks within the chords and two on the left arrow
+        assert_eq!(patterns.jacks, 4);
+        assert_eq!(patterns.count(StepmaniaPatternKind::Jack), 4);
+    }
+
+    #[test]
+    fn it_should_find_crossovers_and_candles() {
+        // L D R: the left foot crosses over to the right arrow, and has to step
+        // back to the left arrow right after (a doublestep).
+        // Then the right foot moves from down to up and back to down.
+        let chart = chart_from(
+            StepmaniaStepStyle::DanceSingle,
+            &taps(chords(&[
+                (0.0, &[0]),
+                (0.5, &[1]),
+                (1.0, &[3]),
+                (2.0, &[0]),
+                (2.5, &[2]),
+                (3.0, &[0]),
+                (3.5, &[1]),
+            ])),
+        );
+        let patterns = StepmaniaPatterns::from_chart(&chart).unwrap();
+
+        assert_eq!(patterns.crossovers, 1);
+        assert_eq!(patterns.patterns[0].kind, StepmaniaPatternKind::Crossover);
+        assert_eq!(patterns.patterns[0].beat, 1.0);
+        assert_eq!(patterns.doublesteps, 1);
+        assert_eq!(patterns.patterns[1].kind, StepmaniaPatternKind::Doublestep);
+        assert_eq!(patterns.candles, 2);
+    }
+
+    #[test]
+    fn it_should_find_footswitches_and_drills() {
+        let chart = chart_from(
+            StepmaniaStepStyle::DanceSingle,
+            &taps(chords(&[
+                // L R L R L: a drill
+                (0.0, &[0]),
+                (0.25, &[3]),
+                (0.5, &[0]),
+                (0.75, &[3]),
+                (1.0, &[0]),
+                // D D in the stream: a footswitch
+                (1.25, &[1]),
+                (1.5, &[1]),
+                // R L: the stream continues with alternating feet
+                (1.75, &[3]),
+                (2.0, &[0]),
+            ])),
+        );
+        let patterns = StepmaniaPatterns::from_chart(&chart).unwrap();
+
+        assert_eq!(patterns.drills, 1);
+        assert_eq!(patterns.footswitches, 1);
+        assert_eq!(patterns.doublesteps, 0);
+        assert_eq!(patterns.jacks, 0);
+    }
+
+    #[test]
+    fn it_should_only_support_dance_charts() {
+        let chart = chart_from(StepmaniaStepStyle::PumpSingle, &taps([(0.0, 0)]));
+        assert!(StepmaniaPatterns::from_chart(&chart).is_err());
+    }
+}
@@ -0,0 +1,657 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::common::*;
+use super::stepmania::*;
+use super::stepstyle::*;
+
+/// Precision of the beats and BPM in the Stepmania model
+const TIME_FACTOR: f64 = 1000.0;
+const SIGNATURE_TRIPLE: &str = "Triple";
+const SIGNATURE_QUADRUPLE: &str = "Quadruple";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct QuaverTimingPoint {
+    #[serde(default)]
+    start_time: f64,
+    #[serde(default)]
+    bpm: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct QuaverSliderVelocity {
+    #[serde(default)]
+    start_time: f64,
+    #[serde(default)]
+    multiplier: f32,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct QuaverHitObject {
+    #[serde(default)]
+    start_time: i64,
+    lane: u8,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    end_time: i64,
+}
+
+fn is_zero(value: &i64) -> bool {
+    *value == 0
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct QuaverMap {
+    #[serde(default)]
+    audio_file: String,
+    #[serde(default)]
+    song_preview_time: i64,
+    #[serde(default)]
+    background_file: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    banner_file: String,
+    #[serde(default)]
+    mode: String,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    artist: String,
+    #[serde(default)]
+    source: String,
+    #[serde(default)]
+    creator: String,
+    #[serde(default)]
+    difficulty_name: String,
+    #[serde(default)]
+    genre: String,
+    #[serde(default)]
+    timing_points: Vec<QuaverTimingPoint>,
+    #[serde(default)]
+    slider_velocities: Vec<QuaverSliderVelocity>,
+    #[serde(default)]
+    hit_objects: Vec<QuaverHitObject>,
+}
+
+fn mode_to_columns(mode: &str) -> Option<u8> {
+    match mode {
+        "Keys4" => Some(4),
+        "Keys7" => Some(7),
+        _ => None,
+    }
+}
+
+fn columns_to_mode(columns: u8) -> Option<&'static str> {
+    match columns {
+        4 => Some("Keys4"),
+        7 => Some("Keys7"),
+        _ => None,
+    }
+}
+
+fn to_option(value: String) -> Option<String> {
+    Some(value).filter(|v| !v.is_empty())
+}
+
+#[derive(Debug, Default)]
+pub struct QuaverParser {
+    // All errors for the Parse-Result
+    pub errors: Vec<ParseError>,
+}
+
+impl QuaverParser {
+    pub fn new() -> QuaverParser {
+        QuaverParser {
+            ..Default::default()
+        }
+    }
+
+    fn push_error(&mut self, code: ParseErrorCode) {
+        self.errors.push(ParseError {
+            code,
+            line: 0,
+            column: 0,
+            len: 0,
+            span: None,
+        });
+    }
+
+    pub fn parse_from_string(&mut self, input: &str) -> Result<StepmaniaFile> {
+        let qua: QuaverMap = match serde_yaml::from_str(input) {
+            Ok(qua) => qua,
+            Err(err) => {
+                let location = err.location();
+                self.errors.push(ParseError {
+                    code: ParseErrorCode::QuaverInvalidYaml,
+                    line: location.as_ref().map_or(0, |l| l.line()),
+                    column: location.as_ref().map_or(0, |l| l.column()),
+                    len: 0,
+                    span: location.as_ref().map(|l| Span::new(l.index(), l.index())),
+                });
+                bail!("invalid quaver map: {}", err);
+            }
+        };
+
+        let column_count = match mode_to_columns(&qua.mode) {
+            Some(count) => count,
+            None => {
+                self.push_error(ParseErrorCode::QuaverUnsupportedMode);
+                bail!("unsupported quaver mode {}", qua.mode);
+            }
+        };
+
+        let mut step = StepmaniaFile {
+            title: to_option(qua.title),
+            artist: to_option(qua.artist),
+            genre: to_option(qua.genre),
+            origin: to_option(qua.source),
+            music: to_option(qua.audio_file),
+            background: to_option(qua.background_file),
+            banner: to_option(qua.banner_file),
+            sample_start: Some(qua.song_preview_time),
+            ..Default::default()
+        };
+
+        // Beat 0 is where the first timing point starts
+        let first_time = qua.timing_points.first().map_or(0.0, |tp| tp.start_time);
+        step.offset = Some(-first_time.round() as i64);
+
+        let mut beat = 0.0;
+        let mut previous: Option<&QuaverTimingPoint> = None;
+        // Points without a signature are in 4/4, like the start of the song
+        let mut numerator = 4;
+        for point in qua.timing_points.iter() {
+            if point.bpm <= 0.0 {
+                self.push_error(ParseErrorCode::QuaverInvalidTimingPoint);
+                continue;
+            }
+            if let Some(prev) = previous {
+                beat += (point.start_time - prev.start_time) * prev.bpm / 60_000.0;
+            }
+
+            let beat_val = (beat * TIME_FACTOR).round() as i64;
+            step.bpms.push(StepmaniaTimedBPM {
+                beat: beat_val,
+                bpm: (point.bpm * TIME_FACTOR).round() as i64,
+            });
+            let point_numerator = match point.signature.as_deref() {
+                Some(SIGNATURE_TRIPLE) => 3,
+                _ => 4,
+            };
+            if point_numerator != numerator {
+                step.time_signatures.push(StepmaniaTimedTimeSignature {
+                    beat: beat_val,
+                    numerator: point_numerator,
+                    denominator: 4,
+                });
+                numerator = point_numerator;
+            }
+            previous = Some(point);
+        }
+
+        // Without a BPM, the notes can't be placed on any beat
+        if step.bpms.is_empty() {
+            if qua.timing_points.is_empty() {
+                self.push_error(ParseErrorCode::QuaverInvalidTimingPoint);
+            }
+            bail!("map has no timing point with a positive BPM");
+        }
+
+        for sv in qua.slider_velocities.iter() {
+            step.scrolls.push(StepmaniaTimedScrollSpeedChange {
+                beat: (step.ms_to_beat(sv.start_time) * TIME_FACTOR).round() as i64,
+                factor: sv.multiplier,
+            });
+        }
+
+        let mut positioned: Vec<StepmaniaPositionedNote> = vec![];
+        for object in qua.hit_objects.iter() {
+            if object.lane == 0 || object.lane > column_count {
+                self.push_error(ParseErrorCode::QuaverInvalidLane);
+                continue;
+            }
+
+            let column = object.lane - 1;
+            let beat = step.ms_to_beat(object.start_time as f64);
+            if object.end_time > object.start_time {
+                let end = step.ms_to_beat(object.end_time as f64);
+                positioned.push(StepmaniaPositionedNote::at_beat(
+                    beat,
+                    column,
+                    StepmaniaNoteType::HoldHead,
+                ));
+                positioned.push(StepmaniaPositionedNote::at_beat(
+                    end,
+                    column,
+                    StepmaniaNoteType::Tail,
+                ));
+            } else {
+                positioned.push(StepmaniaPositionedNote::at_beat(
+                    beat,
+                    column,
+                    StepmaniaNoteType::Tap,
+                ));
+            }
+        }
+
+        step.notes.push(StepmaniaChart {
+            name: to_option(qua.difficulty_name),
+            step_style: if column_count == 7 {
+                StepmaniaStepStyle::Kb7Single
+            } else {
+                StepmaniaStepStyle::DanceSingle
+            },
+            credit: qua.creator,
+            data: StepmaniaNoteData::from_positioned_notes(column_count, &positioned),
+            ..Default::default()
+        });
+
+        Ok(step)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct QuaverWriter {
+    // All features which could not be converted exactly
+    pub warnings: Vec<ConversionWarning>,
+}
+
+impl QuaverWriter {
+    pub fn new() -> QuaverWriter {
+        QuaverWriter {
+            ..Default::default()
+        }
+    }
+
+    fn push_warning(&mut self, code: ConversionWarningCode, beat: i64) {
+        self.warnings.push(ConversionWarning { code, beat });
+    }
+
+    fn write_timing_points(&mut self, step: &StepmaniaFile) -> Vec<QuaverTimingPoint> {
+        let mut points: Vec<QuaverTimingPoint> = step
+            .bpms
+            .iter()
+            .map(|bpm| {
+                let signature = step
+                    .time_signatures
+                    .iter()
+                    .take_while(|sig| sig.beat <= bpm.beat)
+                    .last()
+                    .map(|sig| {
+                        if sig.numerator == 3 {
+                            SIGNATURE_TRIPLE
+                        } else {
+                            SIGNATURE_QUADRUPLE
+                        }
+                    });
+                QuaverTimingPoint {
+                    start_time: step.beat_to_ms(bpm.beat as f64 / TIME_FACTOR),
+                    bpm: bpm.bpm as f64 / TIME_FACTOR,
+                    signature: signature.map(|s| s.to_string()),
+                }
+            })
+            .collect();
+
+        // Stops can only be approximated by restarting the beat grid after them
+        for stop in step.stops.iter() {
+            self.push_warning(ConversionWarningCode::ApproximatedStop, stop.beat);
+            points.push(QuaverTimingPoint {
+                start_time: step.beat_to_ms(stop.beat as f64 / TIME_FACTOR) + stop.duration as f64,
+                bpm: step.bpm_at(stop.beat) as f64 / TIME_FACTOR,
+                signature: None,
+            });
+        }
+        // The time of the beat of a delay already includes the delay
+        for delay in step.delays.iter() {
+            self.push_warning(ConversionWarningCode::ApproximatedStop, delay.beat);
+            points.push(QuaverTimingPoint {
+                start_time: step.beat_to_ms(delay.beat as f64 / TIME_FACTOR),
+                bpm: step.bpm_at(delay.beat) as f64 / TIME_FACTOR,
+                signature: None,
+            });
+        }
+        points.sort_by(|a, b| a.start_time.total_cmp(&b.start_time));
+
+        points
+    }
+
+    /// Scrolls and speeds are both merged into the slider velocities, as these are multiplied
+    /// in Stepmania as well.
+    fn write_slider_velocities(&mut self, step: &StepmaniaFile) -> Vec<QuaverSliderVelocity> {
+        let mut beats: Vec<i64> = step
+            .scrolls
+            .iter()
+            .map(|s| s.beat)
+            .chain(step.speeds.iter().map(|s| s.beat))
+            .collect();
+        beats.sort();
+        beats.dedup();
+
+        for speed in step.speeds.iter().filter(|s| s.duration > 0) {
+            self.push_warning(ConversionWarningCode::ApproximatedSpeedChange, speed.beat);
+        }
+
+        beats
+            .iter()
+            .map(|beat| {
+                let scroll = step
+                    .scrolls
+                    .iter()
+                    .take_while(|s| s.beat <= *beat)
+                    .last()
+                    .map_or(1.0, |s| s.factor);
+                let speed = step
+                    .speeds
+                    .iter()
+                    .take_while(|s| s.beat <= *beat)
+                    .last()
+                    .map_or(1.0, |s| s.ratio);
+
+                QuaverSliderVelocity {
+                    start_time: step.beat_to_ms(*beat as f64 / TIME_FACTOR),
+                    multiplier: scroll * speed,
+                }
+            })
+            .collect()
+    }
+
+    /// Converts the chart of the file to a Quaver map.
+    /// Features which Quaver doesn't support are approximated or dropped and reported in the `warnings`.
+    pub fn write_to_string(&mut self, step: &StepmaniaFile) -> Result<String> {
+        let chart = step
+            .notes
+            .first()
+            .ok_or_else(|| anyhow!("file does not contain a chart"))?;
+        let mode = columns_to_mode(chart.data.column_count)
+            .ok_or_else(|| anyhow!("quaver has no mode for {} columns", chart.data.column_count))?;
+
+        for warp in step.warps.iter() {
+            self.push_warning(ConversionWarningCode::UnsupportedWarp, warp.beat);
+        }
+        for fake in step.fakes.iter() {
+            self.push_warning(ConversionWarningCode::UnsupportedFake, fake.beat);
+        }
+
+        let mut qua = QuaverMap {
+            audio_file: step.music.clone().unwrap_or_default(),
+            song_preview_time: step.sample_start.unwrap_or(0),
+            background_file: step.background.clone().unwrap_or_default(),
+            banner_file: step.banner.clone().unwrap_or_default(),
+            mode: mode.to_string(),
+            title: step
+                .title_translit
+                .clone()
+                .or(step.title.clone())
+                .unwrap_or_default(),
+            artist: step
+                .artist_translit
+                .clone()
+                .or(step.artist.clone())
+                .unwrap_or_default(),
+            source: step.origin.clone().unwrap_or_default(),
+            creator: chart.credit.clone(),
+            difficulty_name: chart
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("{:?}", chart.difficulty)),
+            genre: step.genre.clone().unwrap_or_default(),
+            timing_points: self.write_timing_points(step),
+            slider_velocities: self.write_slider_velocities(step),
+            hit_objects: vec![],
+        };
+
+        // Index of the open hold head per column, which is closed by the next tail
+        let mut heads: Vec<Option<usize>> = vec![None; chart.data.column_count as usize];
+
+        for note in chart.data.positioned_notes() {
+            let beat = note.beat();
+            let time = step.beat_to_ms(beat).round() as i64;
+            let column = note.column as usize;
+
+            match note.note_type {
+                StepmaniaNoteType::Tap
+                | StepmaniaNoteType::Lift
+                | StepmaniaNoteType::HoldHead
+                | StepmaniaNoteType::RollHead => {
+                    if matches!(
+                        note.note_type,
+                        StepmaniaNoteType::HoldHead | StepmaniaNoteType::RollHead
+                    ) {
+                        heads[column] = Some(qua.hit_objects.len());
+                    }
+                    qua.hit_objects.push(QuaverHitObject {
+                        start_time: time,
+                        lane: note.column + 1,
+                        end_time: 0,
+                    });
+                }
+                StepmaniaNoteType::Tail => {
+                    if let Some(idx) = heads[column].take() {
+                        qua.hit_objects[idx].end_time = time;
+                    }
+                }
+                StepmaniaNoteType::Fake => {
+                    self.push_warning(
+                        ConversionWarningCode::UnsupportedFake,
+                        (beat * TIME_FACTOR).round() as i64,
+                    );
+                    qua.hit_objects.push(QuaverHitObject {
+                        start_time: time,
+                        lane: note.column + 1,
+                        end_time: 0,
+                    });
+                }
+                _ => self.push_warning(
+                    ConversionWarningCode::UnsupportedNoteType,
+                    (beat * TIME_FACTOR).round() as i64,
+                ),
+            }
+        }
+
+        Ok(serde_yaml::to_string(&qua)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "
+AudioFile: audio.mp3
+SongPreviewTime: 5000
+BackgroundFile: bg.jpg
+Mode: Keys4
+Title: Zombie Circus
+Artist: Camellia
+Source: ''
+Creator: PreFiXAUT
+DifficultyName: Hard
+TimingPoints:
+- StartTime: 500
+  Bpm: 120
+- StartTime: 2500
+  Bpm: 240
+  Signature: Triple
+SliderVelocities:
+- StartTime: 1500
+  Multiplier: 0.5
+HitObjects:
+- StartTime: 500
+  Lane: 1
+- StartTime: 750
+  Lane: 4
+- StartTime: 1000
+  Lane: 2
+  EndTime: 2500
+";
+
+    #[test]
+    fn it_should_parse_meta() {
+        let mut parser = QuaverParser::new();
+        let res = parser.parse_from_string(SAMPLE);
+        assert!(res.is_ok());
+        assert_eq!(parser.errors.len(), 0);
+
+        let mut step = res.unwrap();
+        assert_eq!(step.title.unwrap(), "Zombie Circus");
+        assert_eq!(step.music.unwrap(), "audio.mp3");
+        assert_eq!(step.sample_start.unwrap(), 5000);
+        assert!(step.origin.is_none());
+
+        let chart = step.notes.remove(0);
+        assert_eq!(chart.name.unwrap(), "Hard");
+        assert_eq!(chart.credit, "PreFiXAUT");
+        assert_eq!(chart.data.column_count, 4);
+    }
+
+    #[test]
+    fn it_should_parse_timing() {
+        let mut parser = QuaverParser::new();
+        let step = parser.parse_from_string(SAMPLE).unwrap();
+
+        assert_eq!(step.offset.unwrap(), -500);
+        assert_eq!(step.bpms.len(), 2);
+        assert_eq!(step.bpms[1].beat, 4000);
+        assert_eq!(step.bpms[1].bpm, 240000);
+        assert_eq!(step.time_signatures.len(), 1);
+        assert_eq!(step.time_signatures[0].numerator, 3);
+        assert_eq!(step.scrolls.len(), 1);
+        assert_eq!(step.scrolls[0].beat, 2000);
+    }
+
+    #[test]
+    fn it_should_switch_back_to_quadruple() {
+        let mut parser = QuaverParser::new();
+        let step = parser
+            .parse_from_string(
+                "
+Mode: Keys4
+TimingPoints:
+- StartTime: 0
+  Bpm: 120
+  Signature: Triple
+- StartTime: 1500
+  Bpm: 120
+  Signature: Triple
+- StartTime: 3000
+  Bpm: 120
+HitObjects: []
+",
+            )
+            .unwrap();
+
+        let signatures: Vec<(i64, u8)> = step
+            .time_signatures
+            .iter()
+            .map(|sig| (sig.beat, sig.numerator))
+            .collect();
+        assert_eq!(signatures, vec![(0, 3), (6000, 4)]);
+    }
+
+    #[test]
+    fn it_should_reject_maps_without_timing_points() {
+        for timing_points in ["[]", "\n- StartTime: 0\n  Bpm: 0"] {
+            let mut parser = QuaverParser::new();
+            let res = parser.parse_from_string(&format!(
+                "Mode: Keys4\nTimingPoints: {}\nHitObjects:\n- StartTime: 500\n  Lane: 1\n",
+                timing_points
+            ));
+            assert!(res.is_err());
+            assert_eq!(parser.errors.len(), 1);
+            assert_eq!(
+                parser.errors[0].code,
+                ParseErrorCode::QuaverInvalidTimingPoint
+            );
+        }
+    }
+
+    #[test]
+    fn it_should_parse_notes() {
+        let mut parser = QuaverParser::new();
+        let mut step = parser.parse_from_string(SAMPLE).unwrap();
+        let notes: Vec<(f64, u8, StepmaniaNoteType)> = step
+            .notes
+            .remove(0)
+            .data
+            .positioned_notes()
+            .iter()
+            .map(|n| (n.beat(), n.column, n.note_type))
+            .collect();
+
+        assert_eq!(
+            notes,
+            vec![
+                (0.0, 0, StepmaniaNoteType::Tap),
+                (0.5, 3, StepmaniaNoteType::Tap),
+                (1.0, 1, StepmaniaNoteType::HoldHead),
+                (4.0, 1, StepmaniaNoteType::Tail),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_restart_the_grid_after_stops_and_delays() {
+        let step = StepmaniaFile {
+            bpms: vec![StepmaniaTimedBPM {
+                beat: 0,
+                bpm: 120000,
+            }],
+            stops: vec![StepmaniaTimedDuration {
+                beat: 4000,
+                duration: 500,
+            }],
+            delays: vec![StepmaniaTimedDuration {
+                beat: 8000,
+                duration: 250,
+            }],
+            ..Default::default()
+        };
+        let mut writer = QuaverWriter::new();
+        let times: Vec<f64> = writer
+            .write_timing_points(&step)
+            .iter()
+            .map(|point| point.start_time)
+            .collect();
+
+        assert_eq!(times, vec![0.0, 2500.0, 4750.0]);
+        assert_eq!(writer.warnings.len(), 2);
+    }
+
+    #[test]
+    fn it_should_write_with_warnings() {
+        let mut parser = QuaverParser::new();
+        let mut step = parser.parse_from_string(SAMPLE).unwrap();
+        step.warps.push(StepmaniaWarp {
+            beat: 2000,
+            end_beat: 2500,
+        });
+
+        let mut writer = QuaverWriter::new();
+        let written = writer.write_to_string(&step).unwrap();
+        assert_eq!(writer.warnings.len(), 1);
+        assert_eq!(
+            writer.warnings[0].code,
+            ConversionWarningCode::UnsupportedWarp
+        );
+
+        step.warps.clear();
+        let mut writer = QuaverWriter::new();
+        let written_clean = writer.write_to_string(&step).unwrap();
+        assert_eq!(writer.warnings.len(), 0);
+        assert_ne!(written, written_clean);
+
+        let mut parser = QuaverParser::new();
+        let mut reparsed = parser.parse_from_string(&written_clean).unwrap();
+        assert_eq!(parser.errors.len(), 0);
+        assert_eq!(reparsed.offset, step.offset);
+        assert_eq!(reparsed.bpms[1].beat, 4000);
+        assert_eq!(
+            reparsed.notes.remove(0).data.positioned_notes().len(),
+            step.notes.remove(0).data.positioned_notes().len()
+        );
+    }
+}
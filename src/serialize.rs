@@ -0,0 +1,144 @@
+use anyhow::Result;
+
+use super::stepmania::*;
+
+/// Version of the serialized model, which is increased with every incompatible change to it
+pub const STEPMANIA_MODEL_VERSION: u32 = 1;
+
+/// The formats the chart model can be serialized to.
+/// All times are in ms and all beats and BPMs in thousandths, like in the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepmaniaSerializationFormat {
+    Json,
+    Yaml,
+    /// MessagePack, with the field names kept as map keys
+    MessagePack,
+}
+
+impl StepmaniaFile {
+    /// Serializes the file, JSON is pretty-printed with `pretty`.
+    /// The spans of the source are left out.
+    pub fn serialize_to(
+        &self,
+        format: StepmaniaSerializationFormat,
+        pretty: bool,
+    ) -> Result<Vec<u8>> {
+        Ok(match format {
+            StepmaniaSerializationFormat::Json if pretty => serde_json::to_vec_pretty(self)?,
+            StepmaniaSerializationFormat::Json => serde_json::to_vec(self)?,
+            StepmaniaSerializationFormat::Yaml => serde_yaml::to_string(self)?.into_bytes(),
+            StepmaniaSerializationFormat::MessagePack => rmp_serde::to_vec_named(self)?,
+        })
+    }
+
+    pub fn deserialize_from(
+        data: &[u8],
+        format: StepmaniaSerializationFormat,
+    ) -> Result<StepmaniaFile> {
+        Ok(match format {
+            StepmaniaSerializationFormat::Json => serde_json::from_slice(data)?,
+            StepmaniaSerializationFormat::Yaml => serde_yaml::from_slice(data)?,
+            StepmaniaSerializationFormat::MessagePack => rmp_serde::from_slice(data)?,
+        })
+    }
+}
+
+/// The JSON Schema of the serialized `StepmaniaFile`, which all formats are converted to.
+/// Its `$id` contains the model version.
+#[cfg(feature = "schema")]
+pub fn stepmania_file_schema() -> serde_json::Value {
+    let mut schema = schemars::schema_for!(StepmaniaFile);
+    schema.insert(
+        "$id".to_string(),
+        format!("urn:rconv:stepmania-file:v{}", STEPMANIA_MODEL_VERSION).into(),
+    );
+    schema.to_value()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stepstyle::StepmaniaStepStyle;
+
+    const SIMFILE: &str = "
+#TITLE:Song;
+#BPMS:0=120,4=180;
+#STOPS:2=0.5;
+#BGCHANGES:0=bg.png=1.000=0=0=1;
+#NOTES:
+     dance-single:
+     Someone:
+     Hard:
+     9:
+     0,0,0,0,0:
+1[2]000
+0100
+0010
+0001
+,
+M000
+;
+";
+
+    #[test]
+    fn it_should_serialize_in_all_formats() {
+        let step = StepmaniaParser::new().parse_from_string(SIMFILE).unwrap();
+        for format in [
+            StepmaniaSerializationFormat::Json,
+            StepmaniaSerializationFormat::Yaml,
+            StepmaniaSerializationFormat::MessagePack,
+        ] {
+            let data = step.serialize_to(format, false).unwrap();
+            let read = StepmaniaFile::deserialize_from(&data, format).unwrap();
+            assert_eq!(read, step);
+            assert_eq!(read.title.as_deref(), Some("Song"));
+            assert_eq!(read.bpms.len(), 2);
+            assert_eq!(read.bpms[1].bpm, 180000);
+            assert_eq!(read.stops[0].duration, 500);
+            assert_eq!(read.background_changes.len(), 1);
+
+            let chart = &read.notes[0];
+            assert_eq!(chart.step_style, StepmaniaStepStyle::DanceSingle);
+            assert_eq!(chart.credit, "Someone");
+            assert_eq!(chart.data.notes.len(), 2);
+            assert_eq!(chart.data.notes[0][0].keysound, Some(2));
+            assert_eq!(chart.data.notes[1][0].note_type, StepmaniaNoteType::Mine);
+            // The same data is written again
+            assert_eq!(read.serialize_to(format, false).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn it_should_serialize_step_styles_by_name() {
+        let step = StepmaniaParser::new().parse_from_string(SIMFILE).unwrap();
+        let json = String::from_utf8(
+            step.serialize_to(StepmaniaSerializationFormat::Json, false)
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(json.contains("\"step_style\":\"dance-single\""));
+        assert!(json.contains("\"title\":\"Song\""));
+        assert!(!json.contains("spans"));
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn it_should_describe_the_serialized_model() {
+        let schema = stepmania_file_schema();
+        assert_eq!(schema["$id"], "urn:rconv:stepmania-file:v1");
+        assert_eq!(schema["title"], "StepmaniaFile");
+
+        // Every serialized field is described
+        let step = StepmaniaParser::new().parse_from_string(SIMFILE).unwrap();
+        let value = serde_json::to_value(&step).unwrap();
+        let properties = schema["properties"].as_object().unwrap();
+        for key in value.as_object().unwrap().keys() {
+            assert!(properties.contains_key(key), "{} is missing", key);
+        }
+        assert!(!properties.contains_key("spans"));
+
+        let chart = &schema["$defs"]["StepmaniaChart"]["properties"];
+        assert!(chart["meter"]["description"].is_string());
+        assert_eq!(schema["$defs"]["StepmaniaStepStyle"]["type"], "string");
+    }
+}
@@ -1,10 +1,17 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{BufRead, BufReader, Read};
+use std::ops::Range;
+use std::str::FromStr;
 
 use anyhow::Result;
 
 use super::common::*;
+use super::stepstyle::*;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaInstrumentTrack {
     /// The instrument name
     pub instrument: String,
@@ -12,7 +19,9 @@ pub struct StepmaniaInstrumentTrack {
     pub file: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaColor {
     pub red: u8,
     pub green: u8,
@@ -31,7 +40,9 @@ impl Default for StepmaniaColor {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaTimedVisualChange {
     /// At which beat the visual change should apply
     pub beat: i64,
@@ -53,7 +64,9 @@ pub struct StepmaniaTimedVisualChange {
     pub color2: StepmaniaColor,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaTimedDuration {
     /// At which beat the duration should apply
     pub beat: i64,
@@ -61,7 +74,9 @@ pub struct StepmaniaTimedDuration {
     pub duration: i64,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaTimedBPM {
     /// At which beat the bpm change should apply
     pub beat: i64,
@@ -69,7 +84,9 @@ pub struct StepmaniaTimedBPM {
     pub bpm: i64,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaTimedTimeSignature {
     /// At which beat the time signature should apply
     pub beat: i64,
@@ -79,7 +96,9 @@ pub struct StepmaniaTimedTimeSignature {
     pub denominator: u8,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaTimedNumber {
     /// At which beat the value should be applied
     pub beat: i64,
@@ -87,7 +106,9 @@ pub struct StepmaniaTimedNumber {
     pub value: i32,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaTimedComboChange {
     /// At which beat the combo change should apply
     pub beat: i64,
@@ -97,7 +118,9 @@ pub struct StepmaniaTimedComboChange {
     pub miss: u32,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaTimedSpeedChange {
     /// At which beat the time-speed change should apply
     pub beat: i64,
@@ -109,7 +132,9 @@ pub struct StepmaniaTimedSpeedChange {
     pub in_seconds: bool,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaTimedScrollSpeedChange {
     /// At which beat the scroll-speed change should apply
     pub beat: i64,
@@ -117,7 +142,9 @@ pub struct StepmaniaTimedScrollSpeedChange {
     pub factor: f32,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaTimedLabel {
     /// At which beat the label should appear
     pub beat: i64,
@@ -125,7 +152,9 @@ pub struct StepmaniaTimedLabel {
     pub label: String,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaNumberRange {
     /// Lower bounds
     pub min: i64,
@@ -133,7 +162,9 @@ pub struct StepmaniaNumberRange {
     pub max: i64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum StepmaniaBPMRange {
     /// A single BPM value
     Single(i64),
@@ -143,7 +174,36 @@ pub enum StepmaniaBPMRange {
     Random,
 }
 
-#[derive(Debug, Default)]
+impl StepmaniaBPMRange {
+    /// Creates a single BPM if both values are the same, or a range otherwise
+    pub fn from_bounds(min: i64, max: i64) -> Self {
+        if min == max {
+            StepmaniaBPMRange::Single(min)
+        } else {
+            StepmaniaBPMRange::Range(min, max)
+        }
+    }
+
+    /// The lowest displayed BPM, if it isn't random
+    pub fn min(&self) -> Option<i64> {
+        match self {
+            StepmaniaBPMRange::Single(bpm) | StepmaniaBPMRange::Range(bpm, _) => Some(*bpm),
+            StepmaniaBPMRange::Random => None,
+        }
+    }
+
+    /// The highest displayed BPM, if it isn't random
+    pub fn max(&self) -> Option<i64> {
+        match self {
+            StepmaniaBPMRange::Single(bpm) | StepmaniaBPMRange::Range(_, bpm) => Some(*bpm),
+            StepmaniaBPMRange::Random => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaWarp {
     /// At which beat the warp starts
     pub beat: i64,
@@ -151,7 +211,9 @@ pub struct StepmaniaWarp {
     pub end_beat: i64,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaRadarValues {
     pub stream: f32,
     pub voltage: f32,
@@ -160,7 +222,9 @@ pub struct StepmaniaRadarValues {
     pub chaos: f32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum StepmaniaMagnitude {
     /// The amount in %
     Percent(u16),
@@ -174,7 +238,9 @@ impl Default for StepmaniaMagnitude {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaAttackModifier {
     /// Name of the Modifier
     pub name: String,
@@ -187,7 +253,9 @@ pub struct StepmaniaAttackModifier {
     pub magnitude: StepmaniaMagnitude,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaNoteAttack {
     /// Duration of the attack in seconds (NOT BEATS)
     pub duration: i64,
@@ -195,7 +263,9 @@ pub struct StepmaniaNoteAttack {
     pub modifiers: Vec<StepmaniaAttackModifier>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaAttack {
     /// The start of the Attack in seconds (NOT BEATS)
     pub start: i64,
@@ -205,8 +275,11 @@ pub struct StepmaniaAttack {
     pub modifiers: Vec<StepmaniaAttackModifier>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum StepmaniaNoteType {
+    #[default]
     Empty,
     Tap,
     HoldHead,
@@ -216,12 +289,9 @@ pub enum StepmaniaNoteType {
     Keysound,
     Lift,
     Fake,
-}
-
-impl Default for StepmaniaNoteType {
-    fn default() -> Self {
-        StepmaniaNoteType::Empty
-    }
+    /// A laser point of KShoot charts, with the position from 0 (left) to 50 (right).
+    /// Consecutive points in the same column are connected.
+    Laser(u8),
 }
 
 impl StepmaniaNoteType {
@@ -239,43 +309,75 @@ impl StepmaniaNoteType {
             _ => StepmaniaNoteType::Empty,
         }
     }
+
+    /// The character of the note type in the note-data.
+    /// Lasers have no representation and are written as empty notes.
+    pub fn to_char(&self) -> char {
+        match self {
+            StepmaniaNoteType::Empty | StepmaniaNoteType::Laser(_) => NOTE_EMPTY,
+            StepmaniaNoteType::Tap => NOTE_TAP,
+            StepmaniaNoteType::HoldHead => NOTE_HOLD_HEAD,
+            StepmaniaNoteType::RollHead => NOTE_ROLL_HEAD,
+            StepmaniaNoteType::Tail => NOTE_TAIL,
+            StepmaniaNoteType::Mine => NOTE_MINE,
+            StepmaniaNoteType::Keysound => NOTE_KEYSOUND,
+            StepmaniaNoteType::Lift => NOTE_LIFT,
+            StepmaniaNoteType::Fake => NOTE_FAKE,
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum StepmaniaDifficulty {
     Beginner,
     Easy,
     Medium,
     Hard,
     Challenge,
+    #[default]
     Edit,
 }
 
-impl Default for StepmaniaDifficulty {
-    fn default() -> Self {
-        StepmaniaDifficulty::Edit
-    }
-}
-
 impl StepmaniaDifficulty {
-    pub fn from_str(str: &str) -> Self {
+    pub fn from_name(str: &str) -> Self {
         match str.to_lowercase().as_str() {
             "beginner" => StepmaniaDifficulty::Beginner,
             "easy" => StepmaniaDifficulty::Easy,
             "medium" => StepmaniaDifficulty::Medium,
             "hard" => StepmaniaDifficulty::Hard,
-            "challange" => StepmaniaDifficulty::Challenge,
+            "challenge" | "challange" => StepmaniaDifficulty::Challenge,
             _ => StepmaniaDifficulty::Edit,
         }
     }
+
+    #[deprecated(note = "use `StepmaniaDifficulty::from_name` instead")]
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(str: &str) -> Self {
+        StepmaniaDifficulty::from_name(str)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StepmaniaDifficulty::Beginner => "Beginner",
+            StepmaniaDifficulty::Easy => "Easy",
+            StepmaniaDifficulty::Medium => "Medium",
+            StepmaniaDifficulty::Hard => "Hard",
+            StepmaniaDifficulty::Challenge => "Challenge",
+            StepmaniaDifficulty::Edit => "Edit",
+        }
+    }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaChart {
     /// Name of the chart (SSC)
     pub name: Option<String>,
     /// The type of the game mode (i.E. dance-single, dance-double, ...)
-    pub step_style: String,
+    pub step_style: StepmaniaStepStyle,
     // Custom name for the step-style
     pub chart_style: Option<String>,
     /// The charter/creator
@@ -286,32 +388,268 @@ pub struct StepmaniaChart {
     pub meter: u16,
     /// Radar-Values which describe the skillsets
     pub radar_values: StepmaniaRadarValues,
+    /// Attacks to apply at specific times, only for this chart (SSC)
+    pub attacks: Vec<StepmaniaAttack>,
     /// The note-data
     pub data: StepmaniaNoteData,
+    /// Where the chart has been defined, if it has been parsed
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub spans: StepmaniaChartSpans,
 }
 
-#[derive(Debug, Default)]
+/// Where the values of a chart are defined in the source
+#[derive(Debug, Clone, Default)]
+pub struct StepmaniaChartSpans {
+    /// The values by their lowercase SSC property name, like "stepstype" or "meter".
+    /// The fields of SM charts use the same names.
+    pub properties: HashMap<String, Span>,
+    /// The notes, in the same layout as the note-data
+    pub notes: Vec<Vec<Span>>,
+}
+
+/// Where the values of a file are defined in the source
+#[derive(Debug, Clone, Default)]
+pub struct StepmaniaSpans {
+    /// The values of the properties by their lowercase name. Of repeated properties, the last one is kept.
+    pub properties: HashMap<String, Span>,
+    /// The entries of list properties like "bpms", in the same order as the parsed elements
+    pub entries: HashMap<String, Vec<Span>>,
+}
+
+/// The spans only refer to the source and aren't part of the content.
+/// They are always equal, so that files with the same content are equal regardless of their formatting.
+impl PartialEq for StepmaniaChartSpans {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl PartialEq for StepmaniaSpans {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaNoteData {
     pub column_count: u8,
     pub notes: Vec<Vec<StepmaniaNote>>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaNote {
     pub note_type: StepmaniaNoteType,
     pub keysound: Option<u32>,
     pub actions: Vec<StepmaniaNoteAttack>,
 }
 
+/// A single note resolved to its position in the note-data
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct StepmaniaPositionedNote {
+    /// Index of the measure the note is in
+    pub measure: usize,
+    /// Row inside of the measure
+    pub row: usize,
+    /// Total amount of rows the measure is divided into
+    pub row_count: usize,
+    /// The column/lane of the note
+    pub column: u8,
+    pub note_type: StepmaniaNoteType,
+    pub keysound: Option<u32>,
+}
+
+impl StepmaniaPositionedNote {
+    /// Creates a note at the beat, snapped to the closest 192nd note
+    pub fn at_beat(beat: f64, column: u8, note_type: StepmaniaNoteType) -> Self {
+        let rows_per_beat = MAX_ROWS_PER_MEASURE / BEATS_PER_MEASURE;
+        let total_rows = (beat.max(0.0) * rows_per_beat as f64).round() as usize;
+
+        StepmaniaPositionedNote {
+            measure: total_rows / MAX_ROWS_PER_MEASURE,
+            row: total_rows % MAX_ROWS_PER_MEASURE,
+            row_count: MAX_ROWS_PER_MEASURE,
+            column,
+            note_type,
+            keysound: None,
+        }
+    }
+
+    /// The beat the note is placed on
+    pub fn beat(&self) -> f64 {
+        (self.measure * BEATS_PER_MEASURE) as f64
+            + (self.row * BEATS_PER_MEASURE) as f64 / self.row_count as f64
+    }
+
+    /// The beat as exact fraction of `(beat, numerator, denominator)`
+    pub fn beat_fraction(&self) -> (usize, usize, usize) {
+        let numerator = (self.row * BEATS_PER_MEASURE) % self.row_count;
+        let whole =
+            self.measure * BEATS_PER_MEASURE + (self.row * BEATS_PER_MEASURE) / self.row_count;
+        let div = gcd(numerator, self.row_count);
+
+        (whole, numerator / div, self.row_count / div)
+    }
+}
+
+impl StepmaniaNoteData {
+    /// The amount of rows the measure is divided into
+    pub fn row_count(&self, measure: usize) -> usize {
+        match (self.notes.get(measure), self.column_count) {
+            (Some(notes), count) if count > 0 => notes.len() / count as usize,
+            _ => 0,
+        }
+    }
+
+    /// All non-empty notes with their resolved positions, ordered by measure, row and column
+    pub fn positioned_notes(&self) -> Vec<StepmaniaPositionedNote> {
+        let mut list = vec![];
+        if self.column_count == 0 {
+            return list;
+        }
+
+        for (measure, notes) in self.notes.iter().enumerate() {
+            let row_count = self.row_count(measure);
+            for (idx, note) in notes.iter().enumerate() {
+                if note.note_type == StepmaniaNoteType::Empty {
+                    continue;
+                }
+                list.push(StepmaniaPositionedNote {
+                    measure,
+                    row: idx / self.column_count as usize,
+                    row_count,
+                    column: (idx % self.column_count as usize) as u8,
+                    note_type: note.note_type,
+                    keysound: note.keysound,
+                });
+            }
+        }
+
+        list
+    }
+
+    /// Builds the note-data from positioned notes, using the smallest (preferably standard) row count
+    /// each measure needs.
+    /// Notes in columns outside of the `column_count` are dropped.
+    pub fn from_positioned_notes(
+        column_count: u8,
+        positioned: &[StepmaniaPositionedNote],
+    ) -> StepmaniaNoteData {
+        let mut data = StepmaniaNoteData {
+            column_count,
+            notes: vec![],
+        };
+        let measure_count = positioned.iter().map(|n| n.measure + 1).max().unwrap_or(0);
+        let mut row_counts = vec![MIN_ROWS_PER_MEASURE; measure_count];
+
+        for note in positioned.iter().filter(|n| n.row_count > 0) {
+            let div = gcd(note.row, note.row_count);
+            let needed = note.row_count / div;
+            let current = row_counts[note.measure];
+            row_counts[note.measure] = current / gcd(current, needed) * needed;
+        }
+
+        // Prefer the row counts Stepmania writes itself, when they fit
+        for row_count in row_counts.iter_mut() {
+            if let Some(standard) = STANDARD_ROW_COUNTS.iter().find(|c| *c % *row_count == 0) {
+                *row_count = *standard;
+            }
+        }
+
+        for row_count in row_counts.iter() {
+            let mut notes = vec![];
+            notes.resize_with(row_count * column_count as usize, StepmaniaNote::default);
+            data.notes.push(notes);
+        }
+
+        for note in positioned.iter() {
+            if note.column >= column_count || note.row_count == 0 {
+                continue;
+            }
+            let row = note.row * row_counts[note.measure] / note.row_count;
+            let idx = row * column_count as usize + note.column as usize;
+            data.notes[note.measure][idx] = StepmaniaNote {
+                note_type: note.note_type,
+                keysound: note.keysound,
+                actions: vec![],
+            };
+        }
+
+        data
+    }
+}
+
+pub(crate) fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a.max(1)
+    } else {
+        gcd(b, a % b)
+    }
+}
+
 #[derive(Debug, Default, Clone)]
-pub struct UnparsedPropertyValue {
-    pub raw: String,
+pub struct UnparsedPropertyValue<'a> {
+    pub raw: Cow<'a, str>,
     pub line: usize,
     pub column: usize,
     pub len: usize,
+    /// Where the value is in the source. For values with escape characters or quotes,
+    /// this includes the characters which have been removed from `raw`.
+    pub span: Span,
+    /// The offset in the source of every byte of `raw` and of its end, if characters have been removed from it.
+    /// Empty if `raw` is the same as the source at the start of the span.
+    pub offsets: Cow<'a, [usize]>,
 }
 
-#[derive(Debug, Default)]
+impl UnparsedPropertyValue<'_> {
+    /// The part of the value in the byte range of `raw`, which starts at the line and column
+    fn slice(&self, range: Range<usize>, line: usize, column: usize) -> UnparsedPropertyValue<'_> {
+        let raw = &self.raw[range.clone()];
+        let offsets: &[usize] = match self.offsets.is_empty() {
+            true => &[],
+            false => &self.offsets[range.start..=range.end],
+        };
+        UnparsedPropertyValue {
+            len: raw.chars().count(),
+            raw: Cow::Borrowed(raw),
+            line,
+            column,
+            span: self.source_span(range),
+            offsets: Cow::Borrowed(offsets),
+        }
+    }
+
+    /// The span without the whitespace around the value
+    fn trimmed_span(&self) -> Span {
+        let start = self.raw.len() - self.raw.trim_start().len();
+        let end = self.raw.trim_end().len().max(start);
+        self.source_span(start..end)
+    }
+
+    /// The span in the source of the byte range of `raw`
+    fn source_span(&self, range: Range<usize>) -> Span {
+        if self.offsets.is_empty() {
+            return Span::new(self.span.start + range.start, self.span.start + range.end);
+        }
+
+        let start = self.offsets[range.start];
+        let end = match range.is_empty() {
+            true => start,
+            // Right after the last byte, as escape characters may follow it
+            false => self.offsets[range.end - 1] + 1,
+        };
+        Span::new(start, end)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StepmaniaFile {
     /// Version of the SSC format
     pub version: Option<String>,
@@ -360,11 +698,16 @@ pub struct StepmaniaFile {
     /// Normally song length is determined by the longest chart.
     /// Required if your chart has only EDIT difficulties, as EDITs are not factored into song length calculation.
     pub last_second_hint: Option<i64>,
+    /// Length of the music file in ms, as cached by Stepmania
+    pub music_length: Option<i64>,
+    /// The beat of the first note of all charts, as cached by Stepmania
+    pub first_beat: Option<i64>,
+    /// The beat of the last note of all charts, as cached by Stepmania
+    pub last_beat: Option<i64>,
     /// The range of BPM the song has
-    // TODO: Change to StepmaniaBPMRange
-    pub display_bpm: Option<StepmaniaNumberRange>,
+    pub display_bpm: Option<StepmaniaBPMRange>,
     /// If the chart is selectable/should be hidden
-    pub selectable: bool,
+    pub selectable: Option<bool>,
     /// The different assignments of instruments and their audio file
     pub instrument_tracks: Vec<StepmaniaInstrumentTrack>,
     /// Transitions/Changes to the background layer 1
@@ -373,6 +716,8 @@ pub struct StepmaniaFile {
     pub background_changes2: Vec<StepmaniaTimedVisualChange>,
     /// Transitions/Changes to the background layer 3
     pub background_changes3: Vec<StepmaniaTimedVisualChange>,
+    /// Transitions/Changes to the background layers 4 and up, starting with layer 4
+    pub additional_background_changes: Vec<Vec<StepmaniaTimedVisualChange>>,
     /// Transitions/Changes to the animations layer
     pub animations: Vec<StepmaniaTimedVisualChange>,
     /// Transitions/Changes to the foreground layer
@@ -405,25 +750,248 @@ pub struct StepmaniaFile {
     pub warps: Vec<StepmaniaWarp>,
     /// The labels to display at specific times
     pub labels: Vec<StepmaniaTimedLabel>,
-    /// The SM chart content
-    // TODO: Change to vec, since SSC can define multiple charts in a file
-    pub notes: Option<StepmaniaChart>,
+    /// The charts of the file
+    pub notes: Vec<StepmaniaChart>,
+    /// Where the values have been defined, if the file has been parsed.
+    /// They only refer to the source, so they aren't serialized.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub spans: StepmaniaSpans,
+}
+
+impl StepmaniaFile {
+    /// The BPM (in 1000s) which is active at the beat (in 1000s)
+    pub fn bpm_at(&self, beat: i64) -> i64 {
+        self.bpms
+            .iter()
+            .take_while(|bpm| bpm.beat <= beat)
+            .last()
+            .or(self.bpms.first())
+            .map_or(0, |bpm| bpm.bpm)
+    }
+
+    /// Time in ms it takes to scroll from one beat to another, only respecting the BPM changes.
+    fn bpm_duration(&self, from: f64, to: f64) -> f64 {
+        let mut time = 0.0;
+
+        for (idx, bpm) in self.bpms.iter().enumerate() {
+            let start = if idx == 0 {
+                f64::MIN
+            } else {
+                bpm.beat as f64 / 1000.0
+            };
+            let end = self
+                .bpms
+                .get(idx + 1)
+                .map_or(f64::MAX, |next| next.beat as f64 / 1000.0);
+            let beats = to.min(end) - from.max(start);
+
+            // Negative BPMs are a legacy way of writing warps, which are handled separately
+            if beats > 0.0 && bpm.bpm > 0 {
+                time += beats * 60_000_000.0 / bpm.bpm as f64;
+            }
+        }
+
+        time
+    }
+
+    /// Converts the beat to the time in ms since the start of the music,
+    /// including the offset, stops, delays and warps.
+    pub fn beat_to_ms(&self, beat: f64) -> f64 {
+        let mut time = -(self.offset.unwrap_or(0) as f64);
+
+        if beat >= 0.0 {
+            time += self.bpm_duration(0.0, beat);
+        } else {
+            time -= self.bpm_duration(beat, 0.0);
+        }
+
+        // Notes on the beat of a stop are hit before it, and on the beat of a delay after it
+        for stop in self
+            .stops
+            .iter()
+            .filter(|stop| (stop.beat as f64 / 1000.0) < beat)
+        {
+            time += stop.duration as f64;
+        }
+        for delay in self
+            .delays
+            .iter()
+            .filter(|d| (d.beat as f64 / 1000.0) <= beat)
+        {
+            time += delay.duration as f64;
+        }
+        for warp in self.warps.iter() {
+            let start = warp.beat as f64 / 1000.0;
+            let end = (warp.end_beat as f64 / 1000.0).min(beat);
+            if end > start {
+                time -= self.bpm_duration(start, end);
+            }
+        }
+
+        time
+    }
+
+    /// Converts the time in ms since the start of the music to the beat.
+    /// Times which fall into a stop resolve to the beat of the stop.
+    pub fn ms_to_beat(&self, time: f64) -> f64 {
+        // The time is never decreasing with the beats, so it's safe to search for it
+        let mut low = -100_000.0;
+        let mut high = 100_000.0;
+
+        for _ in 0..100 {
+            let mid = (low + high) / 2.0;
+            if self.beat_to_ms(mid) < time {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+
+        high
+    }
+
+    /// All files the song references, with the name of the property they're referenced in.
+    /// Special values of visual changes (i.E. `-nosongbg-`) are not included.
+    pub fn referenced_files(&self) -> Vec<(&'static str, String)> {
+        let mut files = vec![];
+        let single = [
+            ("banner", &self.banner),
+            ("background", &self.background),
+            ("lyricspath", &self.lyrics_path),
+            ("cdtitle", &self.cd_title),
+            ("music", &self.music),
+            ("jacket", &self.jacket),
+            ("cdimage", &self.cd_image),
+            ("discimage", &self.disk_image),
+            ("preview", &self.preview),
+        ];
+        for (name, path) in single {
+            if let Some(path) = path.as_ref().filter(|p| !p.trim().is_empty()) {
+                files.push((name, path.clone()));
+            }
+        }
+
+        for track in self.instrument_tracks.iter() {
+            files.push(("instrumenttracks", track.file.clone()));
+        }
+        for keysound in self.keysounds.iter() {
+            files.push(("keysounds", keysound.clone()));
+        }
+
+        let changes = [
+            ("bgchanges", &self.background_changes),
+            ("bgchanges2", &self.background_changes2),
+            ("bgchanges3", &self.background_changes3),
+            ("animations", &self.animations),
+            ("fgchanges", &self.foreground_changes),
+        ];
+        let additional = self
+            .additional_background_changes
+            .iter()
+            .map(|list| ("bgchanges", list));
+        for (name, list) in changes.into_iter().chain(additional) {
+            for change in list.iter() {
+                for path in [&change.path, &change.file2] {
+                    let path = path.trim();
+                    if !path.is_empty() && !path.starts_with('-') {
+                        files.push((name, path.to_string()));
+                    }
+                }
+            }
+        }
+
+        files
+    }
+}
+
+/// The metadata of a song, as needed for a song selection
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct StepmaniaSongInfo {
+    pub title: Option<String>,
+    pub title_translit: Option<String>,
+    pub subtitle: Option<String>,
+    pub subtitle_translit: Option<String>,
+    pub artist: Option<String>,
+    pub artist_translit: Option<String>,
+    pub genre: Option<String>,
+    pub credit: Option<String>,
+    pub banner: Option<String>,
+    pub background: Option<String>,
+    pub jacket: Option<String>,
+    pub music: Option<String>,
+    /// Starting time for the sample/preview in ms
+    pub sample_start: Option<i64>,
+    /// Duration/length of the sample/preview in ms
+    pub sample_length: Option<i64>,
+    pub display_bpm: Option<StepmaniaBPMRange>,
+    /// The lowest BPM of the song. Warps are not counted as BPMs.
+    pub min_bpm: Option<i64>,
+    /// The highest BPM of the song
+    pub max_bpm: Option<i64>,
+    pub charts: Vec<StepmaniaChartInfo>,
+}
+
+/// The header of a chart, without its note-data
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct StepmaniaChartInfo {
+    pub name: Option<String>,
+    pub step_style: StepmaniaStepStyle,
+    pub difficulty: StepmaniaDifficulty,
+    pub meter: u16,
+    pub credit: String,
+}
+
+impl StepmaniaSongInfo {
+    pub fn from_step(step: &StepmaniaFile) -> StepmaniaSongInfo {
+        // Negative BPMs are warps, not actual BPMs
+        let bpms = step.bpms.iter().map(|bpm| bpm.bpm).filter(|bpm| *bpm > 0);
+
+        StepmaniaSongInfo {
+            title: step.title.clone(),
+            title_translit: step.title_translit.clone(),
+            subtitle: step.subtitle.clone(),
+            subtitle_translit: step.subtitle_translit.clone(),
+            artist: step.artist.clone(),
+            artist_translit: step.artist_translit.clone(),
+            genre: step.genre.clone(),
+            credit: step.credit.clone(),
+            banner: step.banner.clone(),
+            background: step.background.clone(),
+            jacket: step.jacket.clone(),
+            music: step.music.clone(),
+            sample_start: step.sample_start,
+            sample_length: step.sample_length,
+            display_bpm: step.display_bpm.clone(),
+            min_bpm: bpms.clone().min(),
+            max_bpm: bpms.max(),
+            charts: step
+                .notes
+                .iter()
+                .map(|chart| StepmaniaChartInfo {
+                    name: chart.name.clone(),
+                    step_style: chart.step_style.clone(),
+                    difficulty: chart.difficulty,
+                    meter: chart.meter,
+                    credit: chart.credit.clone(),
+                })
+                .collect(),
+        }
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct StepmaniaParser {
-    // The calculcated line we're currently on
-    line: usize,
-    // The calculcated column we're currently on
-    col: usize,
-    // The start-position in the buffer
-    start_pos: usize,
     // All errors for the Parse-Result
     pub errors: Vec<ParseError>,
-    // A map of the latest errors to update the length. Index is the error-code and gets cleaned on next valid char
-    latest_errors: HashMap<ParseErrorCode, ParseError>,
-    // The latest name/key we have to parse before hand.
-    latest_name: String,
+    /// If the note-data of the charts should be skipped, so only the metadata and the chart headers are read.
+    /// The charts are left without notes then.
+    pub skip_notes: bool,
+    // The spans of the entries which have been parsed by the latest value group
+    entry_spans: Vec<Span>,
 }
 
 #[derive(Debug, PartialEq, PartialOrd)]
@@ -450,19 +1018,24 @@ impl ChartParserState {
     }
 }
 
-#[derive(Debug)]
-enum ParserState {
-    Clean,
-    Name,
-    Value,
-    DoubleQuouteValue,
-    SingleQouoteValue,
-}
+/// Properties which may be defined multiple times, like the charts of a file
+const REPEATABLE_PROPERTIES: [&str; 3] = ["notes", "notes2", "notedata"];
 
 const CHAR_LINE_BREAK: char = '\n';
 const CHAR_PROPERTY_START: char = '#';
+const CHAR_COMMENT_START: char = '/';
 const CHAR_VALUE_START: char = ':';
 const CHAR_VALUE_END: char = ';';
+const CHAR_ESCAPE: char = '\\';
+const CHAR_DOUBLE_QUOTE: char = '"';
+const CHAR_SINGLE_QUOTE: char = '\'';
+/// Characters which have to be escaped in values
+const ESCAPED_CHARS: [char; 4] = [
+    CHAR_ESCAPE,
+    CHAR_VALUE_END,
+    CHAR_VALUE_START,
+    CHAR_PROPERTY_START,
+];
 const CHAR_OBJ_VAL_SEPARATOR: char = '=';
 const CHAR_OBJ_SEPARATOR: char = ',';
 const CHAR_COLOR_SEPARATOR: char = '^';
@@ -486,178 +1059,880 @@ const NOTE_LIFT: char = 'L';
 const NOTE_FAKE: char = 'F';
 
 const PRECISION_TIME: u8 = 3;
-const PRECISION_COLOR: u8 = 2;
 
-impl StepmaniaParser {
-    pub fn new() -> StepmaniaParser {
-        StepmaniaParser {
-            line: 1,
-            ..Default::default()
-        }
+/// The amount of beats in a single measure of note-data
+pub const BEATS_PER_MEASURE: usize = 4;
+/// The row counts of measures which Stepmania uses itself
+pub const STANDARD_ROW_COUNTS: [usize; 9] = [4, 8, 12, 16, 24, 32, 48, 64, 192];
+/// The smallest amount of rows a measure is written with
+pub const MIN_ROWS_PER_MEASURE: usize = 4;
+/// The highest amount of rows a measure is divided into (192nd notes)
+pub const MAX_ROWS_PER_MEASURE: usize = 192;
+
+/// A property of a simfile, borrowed from the input
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepmaniaToken<'a> {
+    /// The name as written, without the "#"
+    pub name: &'a str,
+    /// The value as written, including escape characters and quotes
+    pub raw: &'a str,
+    pub name_span: Span,
+    pub span: Span,
+    /// Line and column where the name starts
+    pub name_position: (usize, usize),
+    /// Line and column where the value starts
+    pub position: (usize, usize),
+}
+
+impl<'a> StepmaniaToken<'a> {
+    /// The value without escape characters and quotes. It is only copied if it contains any.
+    pub fn value(&self) -> Cow<'a, str> {
+        unescape_value(self.raw)
     }
 
-    fn update_read(&mut self, c: char) -> () {
+    /// The value with the offsets of its bytes in the source, like `UnparsedPropertyValue::offsets`
+    fn value_with_offsets(&self) -> (Cow<'a, str>, Vec<usize>) {
+        unescape_value_with_offsets(self.raw, self.span.start)
+    }
+}
+
+/// A position in the input of the tokenizer
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    line: usize,
+    column: usize,
+    offset: usize,
+}
+
+impl Location {
+    /// The location after the character at this one
+    fn after(self, c: char) -> Location {
         if c == CHAR_LINE_BREAK {
-            self.line += 1;
-            self.col = 0;
+            Location {
+                line: self.line + 1,
+                column: 1,
+                offset: self.offset + 1,
+            }
         } else {
-            self.col += 1;
+            Location {
+                line: self.line,
+                column: self.column + 1,
+                offset: self.offset + c.len_utf8(),
+            }
         }
     }
+}
 
-    fn create_error(&self, code: ParseErrorCode, pos: usize) -> ParseError {
-        ParseError {
-            code,
-            column: self.col,
-            line: self.line,
-            len: pos,
-        }
-    }
+/// What a character read by the `ValueScanner` is to the value
+#[derive(Debug, PartialEq)]
+enum ValueChar {
+    Whitespace,
+    Content,
+    /// The ";" ending the value, which isn't part of it
+    End,
+}
+
+/// Finds the end of a value, one character at a time
+#[derive(Debug, Default)]
+struct ValueScanner {
+    // If only whitespaces have been read since the last line break of the value
+    at_line_start: bool,
+    // If the previous character was an escape character
+    escaped: bool,
+    // If the value has any content other than whitespaces and quotes yet
+    has_content: bool,
+    quote: Option<char>,
+}
 
-    // Converts a stepmania color value to a hex value, where max is 255.
-    fn color_value_to_hex(color: i32) -> f32 {
-        return 255.0 * color as f32;
+impl ValueScanner {
+    /// Like Stepmania, a "#" at the start of a line always starts a new property, even without a ";"
+    fn is_property_start(&self, c: char) -> bool {
+        c == CHAR_PROPERTY_START && self.at_line_start && !self.escaped
     }
 
-    fn create_and_push_error(&mut self, code: ParseErrorCode, pos: usize) -> () {
-        // Create the appropiate error if it doesn't exist yet
-        if !self.latest_errors.contains_key(&code) {
-            let err = self.create_error(code, pos);
-            self.latest_errors.insert(code, err);
+    /// Reads the next character, `rest` is the input after it
+    fn read(&mut self, c: char, rest: &str) -> ValueChar {
+        let kind = if c.is_whitespace() {
+            ValueChar::Whitespace
+        } else {
+            ValueChar::Content
+        };
+
+        if self.escaped {
+            self.escaped = false;
+            self.has_content |= !c.is_whitespace();
+            self.at_line_start = false;
+            return ValueChar::Content;
         }
-    }
 
-    fn cleanup_error(&mut self, code: ParseErrorCode, pos: usize) -> () {
-        // If there has been an error before this, then update the length of the error and clear it from the map
-        if self.latest_errors.contains_key(&code) {
-            // Pops the error from the map
-            let mut err = self.latest_errors.remove(&code).unwrap();
-            // The previous current_pos was saved in err, therefore set it correctly here
-            err.len = pos - err.len;
-            // Now that the error is finished, push it into the errors vec
-            self.errors.push(err);
+        if let Some(quote) = self.quote {
+            if c == CHAR_ESCAPE {
+                self.escaped = true;
+            } else if c == quote {
+                self.quote = None;
+            } else {
+                self.has_content |= !c.is_whitespace();
+            }
+            return kind;
         }
-    }
 
-    fn parse_to_property_map(
-        &mut self,
-        input: &String,
-    ) -> Result<HashMap<String, UnparsedPropertyValue>> {
-        // The map which will hold the unparsed values indexed by their keys
-        let mut map: HashMap<String, UnparsedPropertyValue> = HashMap::new();
+        if c == CHAR_LINE_BREAK {
+            self.at_line_start = true;
+        } else if !c.is_whitespace() {
+            self.at_line_start = false;
+        }
 
-        // Parsing state "maschine"
-        let mut state = ParserState::Clean;
+        if c == CHAR_ESCAPE {
+            self.escaped = true;
+            return kind;
+        }
 
-        for (current_pos, c) in input.chars().enumerate() {
-            match state {
-                ParserState::Clean => {
-                    // Ignore whitespaces/new lines before the actual file contents
-                    if c.is_whitespace() {
-                        self.update_read(c);
-                        continue;
-                    }
+        // Quotes are only special if the value starts with them, and they are closed on the same line.
+        // Otherwise they are part of the value, like the apostrophe in "'Till I Collapse".
+        if (c == CHAR_DOUBLE_QUOTE || c == CHAR_SINGLE_QUOTE)
+            && !self.has_content
+            && has_closing_quote(rest.chars(), c)
+        {
+            self.quote = Some(c);
+            return kind;
+        }
 
-                    if c != CHAR_PROPERTY_START {
-                        self.create_and_push_error(
-                            ParseErrorCode::StepmaniaExpectedPropertyStart,
-                            current_pos,
-                        );
-                        self.update_read(c);
-                        continue;
-                    }
+        if c == CHAR_VALUE_END {
+            return ValueChar::End;
+        }
 
-                    self.cleanup_error(ParseErrorCode::StepmaniaExpectedPropertyStart, current_pos);
-                    state = ParserState::Name;
-                    self.start_pos = current_pos + 1;
-                    self.update_read(c);
-                    continue;
-                }
+        self.has_content |= !c.is_whitespace();
+        kind
+    }
+}
 
-                ParserState::Name => {
-                    if c.is_whitespace() {
-                        self.create_and_push_error(
-                            ParseErrorCode::StepmaniaInvalidPropertyName,
-                            current_pos,
-                        );
-                        self.update_read(c);
-                        continue;
-                    }
+/// Splits a simfile into its properties in a single pass, without copying the input.
+/// It recovers from all errors, which are collected in `errors`.
+#[derive(Debug)]
+pub struct StepmaniaTokenizer<'a> {
+    input: &'a str,
+    // Byte offset of the next character
+    pos: usize,
+    // Line and column of the next character
+    line: usize,
+    column: usize,
+    pub errors: Vec<ParseError>,
+}
 
-                    if c != CHAR_VALUE_START {
-                        self.update_read(c);
-                        continue;
-                    }
+impl<'a> StepmaniaTokenizer<'a> {
+    pub fn new(input: &'a str) -> StepmaniaTokenizer<'a> {
+        StepmaniaTokenizer {
+            input,
+            pos: 0,
+            line: 1,
+            column: 1,
+            errors: vec![],
+        }
+    }
 
-                    // Copy the name of the property into latest_name, since we need it later
-                    self.latest_name = input
-                        .chars()
-                        .skip(self.start_pos)
-                        .take(current_pos - self.start_pos)
-                        .collect::<String>()
-                        .to_lowercase();
-
-                    // Check if this property is a duplicate here, since this is the only place where we have proper
-                    // line/col info.
-                    if map.contains_key(&self.latest_name) {
-                        let mut err = self.create_error(
-                            ParseErrorCode::StepmaniaDuplicatePropertyName,
-                            self.start_pos,
-                        );
-                        err.len = self.latest_name.len();
-                        self.errors.push(err);
-                    }
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
 
-                    self.cleanup_error(ParseErrorCode::StepmaniaExpectedPropertyStart, current_pos);
-                    state = ParserState::Value;
-                    self.start_pos = current_pos + 1;
-                    self.update_read(c);
-                    continue;
+    fn advance(&mut self, c: char) {
+        self.pos += c.len_utf8();
+        if c == CHAR_LINE_BREAK {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+    }
+
+    fn location(&self) -> Location {
+        Location {
+            line: self.line,
+            column: self.column,
+            offset: self.pos,
+        }
+    }
+
+    fn push_error(&mut self, code: ParseErrorCode, start: Location, end: usize) {
+        let span = Span::new(start.offset, end);
+        self.errors.push(ParseError {
+            code,
+            line: start.line,
+            column: start.column,
+            len: span.slice(self.input).chars().count(),
+            span: Some(span),
+        });
+    }
+
+    /// Skips everything up to the next "#", and reports content which isn't a comment
+    fn skip_to_property(&mut self) -> bool {
+        let mut free_content: Option<Location> = None;
+        let mut content_end = 0;
+        while let Some(c) = self.peek() {
+            if c == CHAR_PROPERTY_START {
+                break;
+            }
+
+            if c == CHAR_COMMENT_START && self.input[self.pos + 1..].starts_with(CHAR_COMMENT_START)
+            {
+                // Comments always last until the end of the line
+                while let Some(c) = self.peek().filter(|c| *c != CHAR_LINE_BREAK) {
+                    self.advance(c);
                 }
+                continue;
+            }
 
-                ParserState::Value => {
-                    if c == CHAR_VALUE_START {
-                        self.create_and_push_error(
-                            ParseErrorCode::StepmaniaExpectedValueEnd,
-                            current_pos,
-                        );
-                        self.update_read(c);
-                        continue;
+            if !c.is_whitespace() {
+                free_content.get_or_insert(self.location());
+                content_end = self.pos + c.len_utf8();
+            }
+            self.advance(c);
+        }
+
+        if let Some(start) = free_content {
+            self.push_error(
+                ParseErrorCode::StepmaniaExpectedPropertyStart,
+                start,
+                content_end,
+            );
+        }
+
+        self.peek().is_some()
+    }
+
+    /// Reads the value after the ":", up to the ";".
+    /// Like Stepmania, a "#" at the start of a line always starts a new property, even if the ";" is missing.
+    fn read_value(&mut self) -> (Location, usize) {
+        let start = self.location();
+        // Right after the last character of the value, where the ";" is expected
+        let mut value_end = start;
+        let mut scanner = ValueScanner::default();
+
+        while let Some(c) = self.peek() {
+            if scanner.is_property_start(c) {
+                break;
+            }
+            self.advance(c);
+
+            match scanner.read(c, &self.input[self.pos..]) {
+                ValueChar::End => return (start, self.pos - 1),
+                ValueChar::Content => value_end = self.location(),
+                ValueChar::Whitespace => {}
+            }
+        }
+
+        // The value is still used, even if it isn't terminated
+        self.errors.push(ParseError {
+            code: ParseErrorCode::StepmaniaExpectedValueEnd,
+            line: value_end.line,
+            column: value_end.column,
+            len: 1,
+            span: Some(Span::new(value_end.offset, value_end.offset)),
+        });
+        (start, value_end.offset)
+    }
+}
+
+impl<'a> Iterator for StepmaniaTokenizer<'a> {
+    type Item = StepmaniaToken<'a>;
+
+    fn next(&mut self) -> Option<StepmaniaToken<'a>> {
+        if !self.skip_to_property() {
+            return None;
+        }
+        self.advance(CHAR_PROPERTY_START);
+
+        let name_start = self.location();
+        let mut has_whitespace = false;
+        loop {
+            match self.peek() {
+                Some(CHAR_VALUE_START) => break,
+                Some(c) => {
+                    has_whitespace |= c.is_whitespace();
+                    self.advance(c);
+                }
+                None => {
+                    self.push_error(ParseErrorCode::StepmaniaUnexpectedEOF, name_start, self.pos);
+                    return None;
+                }
+            }
+        }
+        if has_whitespace {
+            self.push_error(
+                ParseErrorCode::StepmaniaInvalidPropertyName,
+                name_start,
+                self.pos,
+            );
+        }
+        let name_span = Span::new(name_start.offset, self.pos);
+        self.advance(CHAR_VALUE_START);
+
+        let (start, end) = self.read_value();
+        let span = Span::new(start.offset, end);
+        Some(StepmaniaToken {
+            name: name_span.slice(self.input),
+            raw: span.slice(self.input),
+            name_span,
+            span,
+            name_position: (name_start.line, name_start.column),
+            position: (start.line, start.column),
+        })
+    }
+}
+
+/// An element of a simfile, as read by the `StepmaniaStreamParser`
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepmaniaEvent {
+    /// A property of the song, or of the current chart. The value is trimmed, and without escape characters
+    /// and quotes. The fields of SM charts use the SSC property names, like "stepstype" or "meter".
+    Property {
+        name: String,
+        value: String,
+        span: Span,
+    },
+    /// A new chart starts, at `#NOTES` in SM files and at `#NOTEDATA` in SSC files
+    ChartStart,
+    /// A measure of the current chart, with the notes of all rows one after another
+    Measure {
+        index: usize,
+        column_count: u8,
+        notes: Vec<StepmaniaNote>,
+        spans: Vec<Span>,
+    },
+    /// The current chart is complete. SSC charts end at the next `#NOTEDATA`, or at the end of the file.
+    ChartEnd,
+}
+
+/// The chart fields of a SM `#NOTES` value, which come before the measures
+const SM_CHART_FIELDS: [&str; 5] = ["stepstype", "credit", "difficulty", "meter", "radarvalues"];
+
+/// Content outside of a property
+#[derive(Debug)]
+struct FreeContent {
+    start: Location,
+    // Byte offset right after the last non-whitespace character
+    end: usize,
+    // Characters which have been read since the start, and up to the last non-whitespace character
+    chars: usize,
+    len: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StreamState {
+    Clean,
+    Comment,
+    Name,
+    Value,
+    Notes {
+        // The SM chart fields which haven't been read yet
+        fields: usize,
+        // Inside of an inline keysound or attack, which may contain ","
+        in_brackets: bool,
+    },
+}
+
+/// The chart which is being read by the `StepmaniaStreamParser`
+#[derive(Debug)]
+struct StreamChart {
+    // If it has been started by `#NOTEDATA`
+    is_ssc: bool,
+    column_count: u8,
+    measure: usize,
+}
+
+/// Reads a simfile as a sequence of events, like a pull parser. Neither the file nor the parsed model are
+/// held in memory, only the current line and the current property or measure.
+/// It follows the same rules as the `StepmaniaTokenizer`, and recovers from all errors, which are collected in `errors`.
+/// Other than the notes, the values aren't parsed, so only errors of the syntax and the notes are reported.
+pub struct StepmaniaStreamParser<R: Read> {
+    reader: BufReader<R>,
+    // The line which is being read
+    line: String,
+    // Location of the next character
+    location: Location,
+    state: StreamState,
+    // Where the current name, value, chart field or measure starts
+    start: Location,
+    // Right after the last character of the current value, where the ";" is expected
+    end: Location,
+    name: String,
+    // The current value, chart field or measure
+    text: String,
+    // Length of `text` up to `end`
+    text_len: usize,
+    scanner: ValueScanner,
+    free_content: Option<FreeContent>,
+    events: VecDeque<StepmaniaEvent>,
+    chart: Option<StreamChart>,
+    // All names which have been defined already, in the file or the current SSC chart
+    names: HashSet<String>,
+    // Parses the measures
+    parser: StepmaniaParser,
+    finished: bool,
+    pub errors: Vec<ParseError>,
+}
+
+impl<R: Read> StepmaniaStreamParser<R> {
+    pub fn new(reader: R) -> StepmaniaStreamParser<R> {
+        let location = Location {
+            line: 1,
+            column: 1,
+            offset: 0,
+        };
+        StepmaniaStreamParser {
+            reader: BufReader::new(reader),
+            line: String::new(),
+            location,
+            state: StreamState::Clean,
+            start: location,
+            end: location,
+            name: String::new(),
+            text: String::new(),
+            text_len: 0,
+            scanner: ValueScanner::default(),
+            free_content: None,
+            events: VecDeque::new(),
+            chart: None,
+            names: HashSet::new(),
+            parser: StepmaniaParser::new(),
+            finished: false,
+            errors: vec![],
+        }
+    }
+
+    /// Reads the next line, returns false at the end of the input
+    fn read_line(&mut self) -> Result<bool> {
+        let mut line = std::mem::take(&mut self.line);
+        line.clear();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(false);
+        }
+
+        let mut idx = 0;
+        while let Some(c) = line[idx..].chars().next() {
+            let next = idx + c.len_utf8();
+            if self.read_char(c, &line[next..]) {
+                self.location = self.location.after(c);
+                idx = next;
+            }
+        }
+        self.line = line;
+        Ok(true)
+    }
+
+    /// Reads the next character, `rest` is the line after it.
+    /// Returns false if it hasn't been consumed, and has to be read again in the new state.
+    fn read_char(&mut self, c: char, rest: &str) -> bool {
+        let after = self.location.after(c);
+        match self.state {
+            StreamState::Clean => {
+                if c == CHAR_PROPERTY_START {
+                    self.finish_free_content();
+                    self.name.clear();
+                    self.start = after;
+                    self.state = StreamState::Name;
+                } else if c == CHAR_COMMENT_START && rest.starts_with(CHAR_COMMENT_START) {
+                    // Comments always last until the end of the line
+                    self.state = StreamState::Comment;
+                } else {
+                    if !c.is_whitespace() {
+                        let start = self.location;
+                        let content = self.free_content.get_or_insert(FreeContent {
+                            start,
+                            end: 0,
+                            chars: 0,
+                            len: 0,
+                        });
+                        content.end = after.offset;
+                        content.len = content.chars + 1;
+                    }
+                    if let Some(content) = &mut self.free_content {
+                        content.chars += 1;
+                    }
+                }
+            }
+            StreamState::Comment => {
+                if c == CHAR_LINE_BREAK {
+                    self.state = StreamState::Clean;
+                    return false;
+                }
+            }
+            StreamState::Name => {
+                if c == CHAR_VALUE_START {
+                    self.start_value(after);
+                } else {
+                    self.name.push(c);
+                }
+            }
+            StreamState::Value | StreamState::Notes { .. } => {
+                if self.scanner.is_property_start(c) {
+                    self.push_value_end_error();
+                    self.finish_value(false);
+                    return false;
+                }
+                let kind = self.scanner.read(c, rest);
+                if kind == ValueChar::End {
+                    self.finish_value(true);
+                    return true;
+                }
+                if kind == ValueChar::Content {
+                    self.end = after;
+                }
+                match self.state {
+                    StreamState::Notes {
+                        fields,
+                        in_brackets,
+                    } => self.read_notes_char(c, after, fields, in_brackets),
+                    _ => {
+                        self.text.push(c);
+                        if kind == ValueChar::Content {
+                            self.text_len = self.text.len();
+                        }
                     }
+                }
+            }
+        }
+        true
+    }
 
-                    if c != CHAR_VALUE_END {
-                        self.update_read(c);
-                        continue;
+    /// Reads a character of a `#NOTES` value, which isn't the end of it
+    fn read_notes_char(
+        &mut self,
+        c: char,
+        after: Location,
+        mut fields: usize,
+        mut in_brackets: bool,
+    ) {
+        if fields > 0 && c == CHAR_NOTE_PROP_SEPARATOR {
+            let name = SM_CHART_FIELDS[SM_CHART_FIELDS.len() - fields];
+            let text = std::mem::take(&mut self.text);
+            self.push_property(name.to_string(), &text, self.start);
+            self.text = text;
+            self.text.clear();
+            self.start = after;
+            fields -= 1;
+        } else if fields == 0 && !in_brackets && c == CHAR_BEAT_SEPARATOR {
+            let text = std::mem::take(&mut self.text);
+            self.push_measure(&text, self.start, true);
+            self.text = text;
+            self.text.clear();
+            self.start = after;
+        } else {
+            match c {
+                CHAR_INLINE_ATTACK_START | CHAR_INLINE_KEYSOUND_START => in_brackets = true,
+                CHAR_INLINE_ATTACK_END | CHAR_INLINE_KEYSOUND_END => in_brackets = false,
+                _ => {}
+            }
+            self.text.push(c);
+        }
+        self.state = StreamState::Notes {
+            fields,
+            in_brackets,
+        };
+    }
+
+    /// Starts the value of the property after its name has been read
+    fn start_value(&mut self, start: Location) {
+        let name = std::mem::take(&mut self.name);
+        let name_start = self.start;
+        let name_span = Span::new(name_start.offset, name_start.offset + name.len());
+        if name.contains(char::is_whitespace) {
+            self.push_error(
+                ParseErrorCode::StepmaniaInvalidPropertyName,
+                name_start,
+                name_span,
+                name.chars().count(),
+            );
+        }
+
+        let name = name.to_lowercase();
+        // Every chart of a SSC file has its own set of properties
+        if name == "notedata" {
+            self.names.clear();
+        }
+        if !self.names.insert(name.clone()) && !REPEATABLE_PROPERTIES.contains(&name.as_str()) {
+            self.push_error(
+                ParseErrorCode::StepmaniaDuplicatePropertyName,
+                name_start,
+                name_span,
+                name.chars().count(),
+            );
+        }
+
+        self.state = match name.as_str() {
+            "notedata" => {
+                self.end_chart();
+                self.start_chart(true);
+                StreamState::Value
+            }
+            // In SSC files, the chart has been started by `#NOTEDATA` already
+            "notes" | "notes2" => match &self.chart {
+                Some(chart) if chart.is_ssc => StreamState::Notes {
+                    fields: 0,
+                    in_brackets: false,
+                },
+                _ => {
+                    self.end_chart();
+                    self.start_chart(false);
+                    StreamState::Notes {
+                        fields: SM_CHART_FIELDS.len(),
+                        in_brackets: false,
                     }
+                }
+            },
+            _ => StreamState::Value,
+        };
+        self.name = name;
+        self.start = start;
+        self.end = start;
+        self.text.clear();
+        self.text_len = 0;
+        self.scanner = ValueScanner::default();
+    }
 
-                    let len = current_pos - self.start_pos;
-                    let value = input.chars().skip(self.start_pos).take(len).collect();
-                    map.insert(
-                        self.latest_name.to_owned(),
-                        UnparsedPropertyValue {
-                            raw: value,
-                            line: self.line,
-                            column: self.col,
-                            len,
-                        },
-                    );
-
-                    state = ParserState::Clean;
-                    self.update_read(c);
-                    continue;
+    /// Emits the current value, if `terminated` is false its trailing whitespaces are left out
+    fn finish_value(&mut self, terminated: bool) {
+        let text = std::mem::take(&mut self.text);
+        match self.state {
+            StreamState::Notes { fields, .. } => {
+                // The last measure of a `#NOTES` value, SM charts end with it
+                if fields == 0 {
+                    self.push_measure(&text, self.start, false);
+                }
+                if self.chart.as_ref().is_some_and(|chart| !chart.is_ssc) {
+                    self.end_chart();
                 }
+            }
+            _ => {
+                let raw = if terminated {
+                    &text
+                } else {
+                    &text[..self.text_len]
+                };
+                let name = std::mem::take(&mut self.name);
+                self.push_property(name, raw, self.start);
+            }
+        }
+        self.text = text;
+        self.state = StreamState::Clean;
+    }
 
-                _ => {
-                    // TODO: Add quoute handling
+    fn start_chart(&mut self, is_ssc: bool) {
+        self.chart = Some(StreamChart {
+            is_ssc,
+            column_count: 0,
+            measure: 0,
+        });
+        self.events.push_back(StepmaniaEvent::ChartStart);
+    }
+
+    fn end_chart(&mut self) {
+        if self.chart.take().is_some() {
+            self.events.push_back(StepmaniaEvent::ChartEnd);
+        }
+    }
+
+    /// Emits the property with the raw value, which starts at `start`
+    fn push_property(&mut self, name: String, raw: &str, start: Location) {
+        // The value of `#NOTEDATA` is always empty
+        if name == "notedata" {
+            return;
+        }
+
+        let (value, offsets) = unescape_value_with_offsets(raw, start.offset);
+        let unparsed = UnparsedPropertyValue {
+            raw: value,
+            span: Span::new(start.offset, start.offset + raw.len()),
+            offsets: Cow::Owned(offsets),
+            ..Default::default()
+        };
+        let span = unparsed.trimmed_span();
+        let value = unparsed.raw.trim().to_string();
+        if let Some(chart) = self
+            .chart
+            .as_mut()
+            .filter(|chart| name == "stepstype" && chart.measure == 0)
+        {
+            chart.column_count = StepmaniaStepStyle::from_name(&value)
+                .column_count()
+                .unwrap_or(0);
+        }
+        self.events
+            .push_back(StepmaniaEvent::Property { name, value, span });
+    }
+
+    /// Parses and emits a measure. Empty measures are only emitted if they are followed by a ",".
+    fn push_measure(&mut self, text: &str, start: Location, separated: bool) {
+        let Some(chart) = self.chart.as_mut() else {
+            return;
+        };
+
+        let value = UnparsedPropertyValue {
+            raw: Cow::Borrowed(text),
+            line: start.line,
+            column: start.column,
+            len: text.chars().count(),
+            span: Span::new(start.offset, start.offset + text.len()),
+            ..Default::default()
+        };
+        let template = StepmaniaChart {
+            data: StepmaniaNoteData {
+                column_count: chart.column_count,
+                notes: vec![],
+            },
+            ..Default::default()
+        };
+        let Some(parsed) =
+            self.parser
+                .parse_to_chart_from_state(value, template, ChartParserState::Notes)
+        else {
+            return;
+        };
+        self.errors.append(&mut self.parser.errors);
+
+        // The column count is taken from the first row, if the step-style is unknown
+        chart.column_count = parsed.data.column_count;
+        let measure = parsed.data.notes.into_iter().zip(parsed.spans.notes).next();
+        let (notes, spans) = match measure {
+            Some(measure) => measure,
+            None if separated => (vec![], vec![]),
+            None => return,
+        };
+        self.events.push_back(StepmaniaEvent::Measure {
+            index: chart.measure,
+            column_count: chart.column_count,
+            notes,
+            spans,
+        });
+        chart.measure += 1;
+    }
+
+    fn finish_free_content(&mut self) {
+        if let Some(content) = self.free_content.take() {
+            self.push_error(
+                ParseErrorCode::StepmaniaExpectedPropertyStart,
+                content.start,
+                Span::new(content.start.offset, content.end),
+                content.len,
+            );
+        }
+    }
+
+    /// Handles the end of the input
+    fn finish(&mut self) {
+        match self.state {
+            StreamState::Clean | StreamState::Comment => self.finish_free_content(),
+            StreamState::Name => self.push_error(
+                ParseErrorCode::StepmaniaUnexpectedEOF,
+                self.start,
+                Span::new(self.start.offset, self.start.offset + self.name.len()),
+                self.name.chars().count(),
+            ),
+            // The value is still used, even if it isn't terminated
+            StreamState::Value | StreamState::Notes { .. } => {
+                self.push_value_end_error();
+                self.finish_value(false);
+            }
+        }
+        self.end_chart();
+        self.finished = true;
+    }
+
+    fn push_error(&mut self, code: ParseErrorCode, start: Location, span: Span, len: usize) {
+        self.errors.push(ParseError {
+            code,
+            line: start.line,
+            column: start.column,
+            len,
+            span: Some(span),
+        });
+    }
+
+    fn push_value_end_error(&mut self) {
+        let end = self.end;
+        let span = Span::new(end.offset, end.offset);
+        self.push_error(ParseErrorCode::StepmaniaExpectedValueEnd, end, span, 1);
+    }
+}
+
+impl<R: Read> Iterator for StepmaniaStreamParser<R> {
+    type Item = Result<StepmaniaEvent>;
+
+    fn next(&mut self) -> Option<Result<StepmaniaEvent>> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Some(Ok(event));
+            }
+            if self.finished {
+                return None;
+            }
+            match self.read_line() {
+                Ok(true) => {}
+                Ok(false) => self.finish(),
+                Err(err) => {
+                    self.finished = true;
+                    return Some(Err(err));
                 }
             }
         }
+    }
+}
+
+impl StepmaniaParser {
+    pub fn new() -> StepmaniaParser {
+        StepmaniaParser {
+            ..Default::default()
+        }
+    }
+
+    fn parse_to_property_list<'a>(
+        &mut self,
+        input: &'a str,
+    ) -> Vec<(String, UnparsedPropertyValue<'a>)> {
+        // The unparsed values with their keys, in the order they are defined in
+        let mut list: Vec<(String, UnparsedPropertyValue)> = vec![];
+        // All names which have been defined already
+        let mut names: HashSet<String> = HashSet::new();
+
+        let mut tokenizer = StepmaniaTokenizer::new(input);
+        let mut errors = vec![];
+        for token in tokenizer.by_ref() {
+            let name = token.name.to_lowercase();
+
+            // Every chart of a SSC file has its own set of properties
+            if name == "notedata" {
+                names.clear();
+            }
+
+            if !names.insert(name.clone()) && !REPEATABLE_PROPERTIES.contains(&name.as_str()) {
+                errors.push(ParseError {
+                    code: ParseErrorCode::StepmaniaDuplicatePropertyName,
+                    line: token.name_position.0,
+                    column: token.name_position.1,
+                    len: token.name.chars().count(),
+                    span: Some(token.name_span),
+                });
+            }
 
-        if matches!(state, ParserState::Value) {
-            self.create_and_push_error(ParseErrorCode::StepmaniaExpectedValueEnd, input.len());
+            let (raw, offsets) = token.value_with_offsets();
+            list.push((
+                name,
+                UnparsedPropertyValue {
+                    raw,
+                    line: token.position.0,
+                    column: token.position.1,
+                    len: token.raw.chars().count(),
+                    span: token.span,
+                    offsets: Cow::Owned(offsets),
+                },
+            ));
         }
 
-        Ok(map)
+        // Keep the errors in the order they appear in
+        errors.append(&mut tokenizer.errors);
+        errors.sort_by_key(|err| err.span.map(|span| span.start));
+        self.errors.append(&mut errors);
+
+        list
     }
 
     /// The numbers that we handle in SM files are typically always timings or time related.
@@ -687,6 +1962,24 @@ impl StepmaniaParser {
                     line: value.line,
                     column: value.column,
                     len: value.len,
+                    span: Some(value.trimmed_span()),
+                });
+                None
+            }
+        }
+    }
+
+    /// Parses a plain integer or float value, which unlike `parse_to_number` isn't scaled.
+    fn parse_to_primitive<T: FromStr>(&mut self, value: UnparsedPropertyValue) -> Option<T> {
+        match value.raw.trim().parse::<T>() {
+            Ok(val) => Some(val),
+            Err(_) => {
+                self.errors.push(ParseError {
+                    code: ParseErrorCode::StepmaniaInvalidNumber,
+                    line: value.line,
+                    column: value.column,
+                    len: value.len,
+                    span: Some(value.trimmed_span()),
                 });
                 None
             }
@@ -698,113 +1991,91 @@ impl StepmaniaParser {
         value: UnparsedPropertyValue,
         precision: u8,
     ) -> Option<StepmaniaNumberRange> {
-        let split_idx = value.raw.find("-");
+        // Stepmania separates the range with ":", but "-" is commonly used as well
+        let split_idx = value.raw.find(':').or_else(|| value.raw.find('-'));
         if let Some(idx) = split_idx {
-            let min: String = value.raw.chars().take(idx).collect();
-            let max: String = value.raw.chars().skip(idx + 1).collect();
-
-            let min_val = self.parse_to_number(
-                UnparsedPropertyValue {
-                    len: min.len(),
-                    raw: min,
-                    line: value.line,
-                    column: value.column,
-                },
-                precision,
-            );
-            let max_val = self.parse_to_number(
-                UnparsedPropertyValue {
-                    len: max.len(),
-                    raw: max,
-                    line: value.line,
-                    column: value.column,
-                },
-                precision,
+            let min = value.slice(0..idx, value.line, value.column);
+            let max = value.slice(
+                idx + 1..value.raw.len(),
+                value.line,
+                value.column + min.len + 1,
             );
 
-            if min_val.is_some() && max_val.is_some() {
-                return Some(StepmaniaNumberRange {
-                    min: min_val.unwrap(),
-                    max: max_val.unwrap(),
-                });
+            let min_val = self.parse_to_number(min, precision);
+            let max_val = self.parse_to_number(max, precision);
+
+            if let (Some(min), Some(max)) = (min_val, max_val) {
+                return Some(StepmaniaNumberRange { min, max });
             }
 
             None
         } else {
-            return self
-                .parse_to_number(value, precision)
-                .map(|v| StepmaniaNumberRange { min: v, max: v });
+            self.parse_to_number(value, precision)
+                .map(|v| StepmaniaNumberRange { min: v, max: v })
         }
     }
 
     /// Parses "a=b=c=d=e=f,1=2=3=4=5=6" to "[[a,b,c,d,e,f], [1,2,3,4,5,6]]"
-    fn parse_to_value_entries(
+    fn parse_to_value_entries<'a>(
         &mut self,
-        value: &UnparsedPropertyValue,
+        value: &'a UnparsedPropertyValue,
         groups: bool,
-    ) -> Vec<Vec<UnparsedPropertyValue>> {
+    ) -> Vec<Vec<UnparsedPropertyValue<'a>>> {
         let mut list: Vec<Vec<UnparsedPropertyValue>> = vec![];
         let mut latest_obj: Vec<UnparsedPropertyValue> = vec![];
         let mut has_latest = false;
 
+        // The position of the next character, and of the first non-whitespace character of the entry
         let mut line = value.line;
         let mut column = value.column;
+        let mut entry_start: Option<(usize, usize)> = None;
+        // Byte offset in the value where the current entry starts
         let mut start_pos: usize = 0;
 
-        for (current_pos, c) in value.raw.chars().enumerate() {
+        for (current_pos, c) in value.raw.char_indices() {
             if c == CHAR_OBJ_VAL_SEPARATOR && groups {
-                let len = current_pos - start_pos;
-                latest_obj.push(UnparsedPropertyValue {
-                    raw: value.raw.chars().skip(start_pos).take(len).collect(),
-                    line,
-                    column,
-                    len,
-                });
-                column += 1;
+                let (entry_line, entry_column) = entry_start.unwrap_or((line, column));
+                latest_obj.push(value.slice(start_pos..current_pos, entry_line, entry_column));
                 has_latest = true;
                 start_pos = current_pos + 1;
+                entry_start = None;
+                column += 1;
                 continue;
             }
 
             if c == CHAR_OBJ_SEPARATOR {
-                let len = current_pos - start_pos;
-
-                if len > 0 {
-                    latest_obj.push(UnparsedPropertyValue {
-                        raw: value.raw.chars().skip(start_pos).take(len).collect(),
-                        line,
-                        column,
-                        len,
-                    });
+                if current_pos > start_pos {
+                    let (entry_line, entry_column) = entry_start.unwrap_or((line, column));
+                    latest_obj.push(value.slice(start_pos..current_pos, entry_line, entry_column));
                 }
 
                 list.push(latest_obj);
                 latest_obj = vec![];
                 has_latest = false;
-                column += 1;
                 start_pos = current_pos + 1;
+                entry_start = None;
+                column += 1;
                 continue;
             }
 
+            if entry_start.is_none() && !c.is_whitespace() {
+                entry_start = Some((line, column));
+            }
+
             if c == CHAR_LINE_BREAK {
                 line += 1;
-                column = 0;
+                column = 1;
                 continue;
             }
 
             column += 1;
         }
 
-        if has_latest {
-            // Finish any potentially lingering objects
-            let len = value.raw.len() - start_pos;
-            if len > 0 {
-                latest_obj.push(UnparsedPropertyValue {
-                    raw: value.raw.chars().skip(start_pos).take(len).collect(),
-                    line,
-                    column,
-                    len,
-                });
+        // Finish any potentially lingering objects
+        if has_latest || entry_start.is_some() {
+            if value.raw.len() > start_pos {
+                let (line, column) = entry_start.unwrap_or((line, column));
+                latest_obj.push(value.slice(start_pos..value.raw.len(), line, column));
             }
             list.push(latest_obj);
         }
@@ -813,7 +2084,7 @@ impl StepmaniaParser {
     }
 
     fn parse_to_bool(&mut self, val: UnparsedPropertyValue) -> bool {
-        return match val.raw.as_str() {
+        match val.raw.as_ref() {
             "0" => false,
             "1" => true,
             _ => {
@@ -822,10 +2093,11 @@ impl StepmaniaParser {
                     line: val.line,
                     column: val.column,
                     len: val.len,
+                    span: Some(val.trimmed_span()),
                 });
                 false
             }
-        };
+        }
     }
 
     fn parse_to_color_channel(&mut self, value: UnparsedPropertyValue) -> Option<u8> {
@@ -840,6 +2112,7 @@ impl StepmaniaParser {
                     line: value.line,
                     column: value.column,
                     len: value.len,
+                    span: Some(value.trimmed_span()),
                 });
                 None
             }
@@ -847,30 +2120,36 @@ impl StepmaniaParser {
     }
 
     fn parse_to_color(&mut self, value: UnparsedPropertyValue) -> StepmaniaColor {
+        // Byte offset, line and column where the current channel starts
         let mut start_pos = 0;
+        let mut start = (value.line, value.column);
         let mut line = value.line;
         let mut column = value.column;
         let mut color = StepmaniaColor::default();
         let mut color_pos = 0;
 
-        for (current_pos, c) in value.raw.chars().enumerate() {
+        let channels = value
+            .raw
+            .char_indices()
+            .chain(std::iter::once((value.raw.len(), CHAR_COLOR_SEPARATOR)));
+        for (current_pos, c) in channels {
             if c != CHAR_COLOR_SEPARATOR {
                 if c == CHAR_LINE_BREAK {
                     line += 1;
-                    column = 0;
+                    column = 1;
                     continue;
                 }
                 column += 1;
                 continue;
             }
 
-            let len = current_pos - start_pos;
-            if let Some(cv) = self.parse_to_color_channel(UnparsedPropertyValue {
-                raw: value.raw.chars().skip(start_pos).take(len).collect(),
-                line,
-                column,
-                len,
-            }) {
+            // A value without any separator is not a color
+            if current_pos == value.raw.len() && start_pos == 0 {
+                break;
+            }
+
+            let channel = value.slice(start_pos..current_pos, start.0, start.1);
+            if let Some(cv) = self.parse_to_color_channel(channel) {
                 match color_pos {
                     0 => color.red = cv,
                     1 => color.green = cv,
@@ -883,31 +2162,14 @@ impl StepmaniaParser {
             start_pos = current_pos + 1;
             color_pos += 1;
             column += 1;
-        }
-
-        if start_pos > 0 {
-            let len = value.raw.len() - start_pos + 1;
-            if let Some(cv) = self.parse_to_color_channel(UnparsedPropertyValue {
-                raw: value.raw.chars().skip(start_pos).take(len).collect(),
-                line,
-                column,
-                len,
-            }) {
-                match color_pos {
-                    0 => color.red = cv,
-                    1 => color.green = cv,
-                    2 => color.blue = cv,
-                    3 => color.alpha = cv,
-                    _ => {}
-                }
-            }
+            start = (line, column);
         }
 
         color
     }
 
-    fn add_value_count_error(&mut self, entry: &Vec<UnparsedPropertyValue>) {
-        let first = entry.get(0).unwrap();
+    fn add_value_count_error(&mut self, entry: &[UnparsedPropertyValue]) {
+        let first = entry.first().unwrap();
         let mut total_len = entry.len() - 1;
         for p in entry.iter() {
             total_len += p.len;
@@ -917,33 +2179,42 @@ impl StepmaniaParser {
             line: first.line,
             column: first.column,
             len: total_len,
+            span: Some(group_span(entry)),
         });
     }
 
-    fn parse_value_group<T, F>(
+    fn parse_value_group<'a, T, F>(
         &mut self,
-        value: &UnparsedPropertyValue,
+        value: &'a UnparsedPropertyValue,
         min: usize,
         max: usize,
         mut mapper_fn: F,
     ) -> Vec<T>
     where
-        F: FnMut(&mut Self, Vec<UnparsedPropertyValue>) -> Option<T>,
+        F: FnMut(&mut Self, Vec<UnparsedPropertyValue<'a>>) -> Option<T>,
     {
         let mut list: Vec<T> = vec![];
 
-        for group in self.parse_to_value_entries(&value, true) {
+        for group in self.parse_to_value_entries(value, true) {
             let len = group.len();
-            if len < min {
+            // Empty groups come from trailing separators and are not an error
+            if len == 0 {
                 continue;
             }
 
-            if len < max {
+            if len < min {
+                self.add_value_count_error(&group);
+                continue;
+            }
+
+            if len > max {
                 self.add_value_count_error(&group);
             }
 
+            let span = group_span(&group);
             if let Some(val) = mapper_fn(self, group) {
                 list.push(val);
+                self.entry_spans.push(span);
             }
         }
 
@@ -975,7 +2246,7 @@ impl StepmaniaParser {
             }
         }
         if len > 1 {
-            bg.path = entry.remove(0).raw;
+            bg.path = entry.remove(0).raw.into_owned();
         }
         if len > 2 {
             let fp = entry.remove(0);
@@ -986,6 +2257,7 @@ impl StepmaniaParser {
                     line: fp.line,
                     column: fp.column,
                     len: fp.len,
+                    span: Some(fp.trimmed_span()),
                 }),
             }
         }
@@ -999,13 +2271,13 @@ impl StepmaniaParser {
             bg.stretch_no_loop = self.parse_to_bool(entry.remove(0));
         }
         if len > 6 {
-            bg.effect = entry.remove(0).raw;
+            bg.effect = entry.remove(0).raw.into_owned();
         }
         if len > 7 {
-            bg.file2 = entry.remove(0).raw;
+            bg.file2 = entry.remove(0).raw.into_owned();
         }
         if len > 8 {
-            bg.transition = entry.remove(0).raw;
+            bg.transition = entry.remove(0).raw.into_owned();
         }
         if len > 9 {
             bg.color1 = self.parse_to_color(entry.remove(0));
@@ -1020,7 +2292,7 @@ impl StepmaniaParser {
     fn parse_to_string_list(&mut self, value: UnparsedPropertyValue) -> Vec<String> {
         self.parse_to_value_entries(&value, false)
             .iter()
-            .filter_map(|entry| entry.get(0).map(|v| v.raw.clone().trim().to_string()))
+            .filter_map(|entry| entry.first().map(|v| v.raw.clone().trim().to_string()))
             .collect()
     }
 
@@ -1029,7 +2301,7 @@ impl StepmaniaParser {
         group: Vec<UnparsedPropertyValue>,
     ) -> Option<StepmaniaInstrumentTrack> {
         Some(StepmaniaInstrumentTrack {
-            instrument: group.get(0).unwrap().raw.clone().trim().to_string(),
+            instrument: group.first().unwrap().raw.clone().trim().to_string(),
             file: group.get(1).unwrap().raw.clone().trim().to_string(),
         })
     }
@@ -1045,10 +2317,20 @@ impl StepmaniaParser {
             return None;
         }
 
-        return Some(StepmaniaTimedDuration {
+        Some(StepmaniaTimedDuration {
             beat: beat.unwrap(),
             duration: duration.unwrap(),
-        });
+        })
+    }
+
+    fn parse_to_warp(&mut self, mut entry: Vec<UnparsedPropertyValue>) -> Option<StepmaniaWarp> {
+        let beat = self.parse_to_number(entry.remove(0), PRECISION_TIME)?;
+        let length = self.parse_to_number(entry.remove(0), PRECISION_TIME)?;
+
+        Some(StepmaniaWarp {
+            beat,
+            end_beat: beat + length,
+        })
     }
 
     fn parse_to_timed_bpm(
@@ -1062,10 +2344,10 @@ impl StepmaniaParser {
             return None;
         }
 
-        return Some(StepmaniaTimedBPM {
+        Some(StepmaniaTimedBPM {
             beat: beat.unwrap(),
             bpm: bpm.unwrap(),
-        });
+        })
     }
 
     fn parse_to_timed_time_signature(
@@ -1073,18 +2355,18 @@ impl StepmaniaParser {
         mut entry: Vec<UnparsedPropertyValue>,
     ) -> Option<StepmaniaTimedTimeSignature> {
         let beat = self.parse_to_number(entry.remove(0), PRECISION_TIME);
-        let numerator = entry.remove(0).raw.trim().parse::<u8>();
-        let denominator = entry.remove(0).raw.trim().parse::<u8>();
+        let numerator = self.parse_to_primitive::<u8>(entry.remove(0));
+        let denominator = self.parse_to_primitive::<u8>(entry.remove(0));
 
-        if beat.is_none() || numerator.is_err() || denominator.is_err() {
+        if beat.is_none() || numerator.is_none() || denominator.is_none() {
             return None;
         }
 
-        return Some(StepmaniaTimedTimeSignature {
+        Some(StepmaniaTimedTimeSignature {
             beat: beat.unwrap(),
             numerator: numerator.unwrap(),
             denominator: denominator.unwrap(),
-        });
+        })
     }
 
     fn parse_to_timed_number(
@@ -1092,16 +2374,16 @@ impl StepmaniaParser {
         mut entry: Vec<UnparsedPropertyValue>,
     ) -> Option<StepmaniaTimedNumber> {
         let beat = self.parse_to_number(entry.remove(0), PRECISION_TIME);
-        let value = entry.remove(0).raw.trim().parse::<i32>();
+        let value = self.parse_to_primitive::<i32>(entry.remove(0));
 
-        if beat.is_none() || value.is_err() {
+        if beat.is_none() || value.is_none() {
             return None;
         }
 
-        return Some(StepmaniaTimedNumber {
+        Some(StepmaniaTimedNumber {
             beat: beat.unwrap(),
             value: value.unwrap(),
-        });
+        })
     }
 
     fn parse_to_timed_combo_change(
@@ -1109,18 +2391,22 @@ impl StepmaniaParser {
         mut entry: Vec<UnparsedPropertyValue>,
     ) -> Option<StepmaniaTimedComboChange> {
         let beat = self.parse_to_number(entry.remove(0), PRECISION_TIME);
-        let hit = entry.remove(0).raw.trim().parse::<u32>();
-        let miss = entry.remove(0).raw.trim().parse::<u32>();
+        let hit = self.parse_to_primitive::<u32>(entry.remove(0));
+        // Like in Stepmania, misses count as much as hits if not set
+        let miss = match entry.is_empty() {
+            true => hit,
+            false => self.parse_to_primitive::<u32>(entry.remove(0)),
+        };
 
-        if beat.is_none() || hit.is_err() || miss.is_err() {
+        if beat.is_none() || hit.is_none() || miss.is_none() {
             return None;
         }
 
-        return Some(StepmaniaTimedComboChange {
+        Some(StepmaniaTimedComboChange {
             beat: beat.unwrap(),
             hit: hit.unwrap(),
             miss: miss.unwrap(),
-        });
+        })
     }
 
     fn parse_to_timed_speed_change(
@@ -1128,20 +2414,24 @@ impl StepmaniaParser {
         mut entry: Vec<UnparsedPropertyValue>,
     ) -> Option<StepmaniaTimedSpeedChange> {
         let beat = self.parse_to_number(entry.remove(0), PRECISION_TIME);
-        let ratio = entry.remove(0).raw.trim().parse::<f32>();
+        let ratio = self.parse_to_primitive::<f32>(entry.remove(0));
         let duration = self.parse_to_number(entry.remove(0), PRECISION_TIME);
-        let in_seconds = entry.remove(0).raw.trim().parse::<u32>();
+        // The duration is in beats if the unit isn't set
+        let in_seconds = match entry.is_empty() {
+            true => Some(0),
+            false => self.parse_to_primitive::<u32>(entry.remove(0)),
+        };
 
-        if beat.is_none() || ratio.is_err() || duration.is_none() || in_seconds.is_err() {
+        if beat.is_none() || ratio.is_none() || duration.is_none() || in_seconds.is_none() {
             return None;
         }
 
-        return Some(StepmaniaTimedSpeedChange {
+        Some(StepmaniaTimedSpeedChange {
             beat: beat.unwrap(),
             ratio: ratio.unwrap(),
             duration: duration.unwrap(),
             in_seconds: in_seconds.unwrap() == 1,
-        });
+        })
     }
 
     fn parse_to_timed_scroll_speed_change(
@@ -1149,37 +2439,33 @@ impl StepmaniaParser {
         mut entry: Vec<UnparsedPropertyValue>,
     ) -> Option<StepmaniaTimedScrollSpeedChange> {
         let beat = self.parse_to_number(entry.remove(0), PRECISION_TIME);
-        let factor = entry.remove(0).raw.trim().parse::<f32>();
+        let factor = self.parse_to_primitive::<f32>(entry.remove(0));
 
-        if beat.is_none() || factor.is_err() {
+        if beat.is_none() || factor.is_none() {
             return None;
         }
 
-        return Some(StepmaniaTimedScrollSpeedChange {
+        Some(StepmaniaTimedScrollSpeedChange {
             beat: beat.unwrap(),
             factor: factor.unwrap(),
-        });
+        })
     }
 
     fn parse_to_timed_label(
         &mut self,
         mut entry: Vec<UnparsedPropertyValue>,
     ) -> Option<StepmaniaTimedLabel> {
-        let beat = self.parse_to_number(entry.remove(0), PRECISION_TIME);
-
-        if beat.is_none() {
-            return None;
-        }
+        let beat = self.parse_to_number(entry.remove(0), PRECISION_TIME)?;
 
-        return Some(StepmaniaTimedLabel {
-            beat: beat.unwrap(),
+        Some(StepmaniaTimedLabel {
+            beat,
             label: entry.remove(0).raw.trim().to_string(),
-        });
+        })
     }
 
     fn parse_attack_modifiers(
         &mut self,
-        value: UnparsedPropertyValue,
+        _value: UnparsedPropertyValue,
     ) -> Vec<StepmaniaAttackModifier> {
         vec![]
     }
@@ -1195,39 +2481,28 @@ impl StepmaniaParser {
         let mut len_val: i64 = 0;
         let mut element_idx = 0;
 
-        // Positions
+        // Positions of the next character, and byte offset, line and column where the segment starts
         let mut current_line = value.line;
-        let mut current_pos = 0;
+        let mut current_column = value.column;
         let mut start_line = value.line;
+        let mut start_column = value.column;
         let mut start_pos = 0;
 
-        for c in value.raw.chars() {
+        // The trailing separator finishes the last segment, as it is not terminated otherwise
+        let chars = value.raw.char_indices().chain(std::iter::once((
+            value.raw.len(),
+            CHAR_ATTACK_VALUE_SEPARATOR,
+        )));
+        for (current_pos, c) in chars {
             if c == CHAR_ATTACK_KEY_SEPARATOR {
-                let len: usize = current_pos - start_pos;
-                segment_name = value
-                    .raw
-                    .chars()
-                    .skip(start_pos)
-                    .take(len)
-                    .collect::<String>()
-                    .trim()
-                    .to_lowercase();
+                segment_name = value.raw[start_pos..current_pos].trim().to_lowercase();
                 start_line = current_line;
+                start_column = current_column + 1;
                 start_pos = current_pos + 1;
             } else if c == CHAR_ATTACK_VALUE_SEPARATOR {
-                let len: usize = current_pos - start_pos;
-                let tmp_value = value
-                    .raw
-                    .chars()
-                    .skip(start_pos)
-                    .take(len)
-                    .collect::<String>();
-                let tmp_unparsed = UnparsedPropertyValue {
-                    line: start_line,
-                    column: start_pos,
-                    len,
-                    raw: tmp_value,
-                };
+                let tmp_unparsed = value.slice(start_pos..current_pos, start_line, start_column);
+                let len = tmp_unparsed.len;
+                let span = Some(tmp_unparsed.trimmed_span());
 
                 match (element_idx, segment_name.as_str()) {
                     (_, "time") => {
@@ -1235,8 +2510,9 @@ impl StepmaniaParser {
                             self.errors.push(ParseError {
                                 code: ParseErrorCode::StepmaniaInvalidAttackValueOrder,
                                 line: start_line,
-                                column: start_pos,
+                                column: start_column,
                                 len,
+                                span,
                             });
                             // Reset to make the next steps not screw up completely.
                             element_idx = 0;
@@ -1267,85 +2543,32 @@ impl StepmaniaParser {
                     _ => self.errors.push(ParseError {
                         code: ParseErrorCode::StepmaniaInvalidAttackValue,
                         line: start_line,
-                        column: start_pos,
+                        column: start_column,
                         len,
+                        span,
                     }),
                 }
 
                 start_line = current_line;
+                start_column = current_column + 1;
                 start_pos = current_pos + 1;
                 element_idx = (element_idx + 1) % 3;
             }
 
             if c == CHAR_LINE_BREAK {
                 current_line += 1;
+                current_column = 1;
+            } else {
+                current_column += 1;
             }
-
-            current_pos += 1;
-        }
-
-        // TODO: Find a way to prevent copy-paste here
-        let len: usize = value.raw.len() - start_pos;
-        let tmp_value = value
-            .raw
-            .chars()
-            .skip(start_pos)
-            .take(len)
-            .collect::<String>();
-        let tmp_unparsed = UnparsedPropertyValue {
-            line: start_line,
-            column: start_pos,
-            len,
-            raw: tmp_value,
-        };
-
-        match (element_idx, segment_name.as_str()) {
-            (_, "time") => {
-                if element_idx != 0 {
-                    self.errors.push(ParseError {
-                        code: ParseErrorCode::StepmaniaInvalidAttackValueOrder,
-                        line: start_line,
-                        column: start_pos,
-                        len,
-                    });
-                    // Reset to make the next steps not screw up completely.
-                    element_idx = 0;
-                }
-
-                if let Some(time) = self.parse_to_number(tmp_unparsed, PRECISION_TIME) {
-                    start_val = time;
-                }
-            }
-            (1, "end") | (1, "len") => {
-                if let Some(val) = self.parse_to_number(tmp_unparsed, PRECISION_TIME) {
-                    len_val = if segment_name.as_str() == "len" {
-                        val
-                    } else {
-                        val - start_val
-                    };
-                }
-            }
-            (2, "mods") => {
-                list.push(StepmaniaAttack {
-                    start: start_val,
-                    duration: len_val,
-                    modifiers: self.parse_attack_modifiers(tmp_unparsed),
-                });
-            }
-            _ => self.errors.push(ParseError {
-                code: ParseErrorCode::StepmaniaInvalidAttackValue,
-                line: start_line,
-                column: start_pos,
-                len,
-            }),
         }
 
-        return list;
+        list
     }
 
     fn parse_to_radio_values(
         &mut self,
-        input: UnparsedPropertyValue,
+        _input: UnparsedPropertyValue,
     ) -> Option<StepmaniaRadarValues> {
         Some(StepmaniaRadarValues {
             ..Default::default()
@@ -1353,16 +2576,38 @@ impl StepmaniaParser {
     }
 
     fn parse_to_chart(&mut self, input: UnparsedPropertyValue) -> Option<StepmaniaChart> {
-        let mut state = ChartParserState::Type;
+        let chart = StepmaniaChart {
+            ..Default::default()
+        };
+        self.parse_to_chart_from_state(input, chart, ChartParserState::Type)
+    }
+
+    /// Parses the notes of a SSC chart, which only contain the note-data
+    fn parse_to_ssc_notes(
+        &mut self,
+        input: UnparsedPropertyValue,
+        chart: StepmaniaChart,
+    ) -> Option<StepmaniaChart> {
+        self.parse_to_chart_from_state(input, chart, ChartParserState::Notes)
+    }
+
+    fn parse_to_chart_from_state(
+        &mut self,
+        input: UnparsedPropertyValue,
+        mut chart: StepmaniaChart,
+        mut state: ChartParserState,
+    ) -> Option<StepmaniaChart> {
+        // Byte offset in the value, line and column where the current field starts
         let mut start_idx: usize = 0;
+        let mut start = (input.line, input.column);
         let mut line = input.line;
         let mut col = input.column;
-        let mut chart = StepmaniaChart {
-            ..Default::default()
-        };
         let mut current_beat_notes: Vec<StepmaniaNote> = vec![];
+        let mut current_beat_spans: Vec<Span> = vec![];
+        // Index in the current measure where the current row starts
+        let mut row_start: usize = 0;
 
-        for (idx, c) in input.raw.chars().enumerate() {
+        for (idx, c) in input.raw.char_indices() {
             match state {
                 ChartParserState::Type
                 | ChartParserState::Credits
@@ -1379,47 +2624,59 @@ impl StepmaniaParser {
                         continue;
                     }
 
-                    let str = input
-                        .raw
-                        .chars()
-                        .skip(start_idx)
-                        .take(idx)
-                        .collect::<String>()
-                        .trim()
-                        .to_owned();
-                    match state {
-                        ChartParserState::Type => chart.step_style = str,
-                        ChartParserState::Credits => chart.credit = str,
+                    let field = input.slice(start_idx..idx, start.0, start.1);
+                    let str = field.raw.trim().to_owned();
+                    let name = match state {
+                        ChartParserState::Type => {
+                            chart.step_style = StepmaniaStepStyle::from_name(&str);
+                            chart.data.column_count = chart.step_style.column_count().unwrap_or(0);
+                            "stepstype"
+                        }
+                        ChartParserState::Credits => {
+                            chart.credit = str;
+                            "credit"
+                        }
                         ChartParserState::Difficulty => {
-                            chart.difficulty = StepmaniaDifficulty::from_str(&str)
+                            chart.difficulty = StepmaniaDifficulty::from_name(&str);
+                            "difficulty"
                         }
-                        ChartParserState::Rating => match str.parse::<u16>() {
-                            Ok(rating) => chart.meter = rating,
-                            Err(_) => self.errors.push(ParseError {
-                                code: ParseErrorCode::StepmaniaInvalidNumber,
-                                column: col,
-                                line: line,
-                                len: str.len(),
-                            }),
-                        },
-                        ChartParserState::RadioValues => {
-                            if let Some(val) = self.parse_to_radio_values(UnparsedPropertyValue {
-                                len: str.len(),
-                                raw: str,
-                                column: col,
-                                line: line,
-                            }) {
-                                chart.radar_values = val;
+                        ChartParserState::Rating => {
+                            match str.parse::<u16>() {
+                                Ok(rating) => chart.meter = rating,
+                                Err(_) => self.errors.push(ParseError {
+                                    code: ParseErrorCode::StepmaniaInvalidNumber,
+                                    column: field.column,
+                                    line: field.line,
+                                    len: field.len,
+                                    span: Some(field.trimmed_span()),
+                                }),
                             }
+                            "meter"
                         }
-                        _ => {
-                            // Never happens
+                        _ => "radarvalues",
+                    };
+                    chart
+                        .spans
+                        .properties
+                        .insert(name.to_string(), field.trimmed_span());
+                    if state == ChartParserState::RadioValues {
+                        if let Some(val) = self.parse_to_radio_values(field) {
+                            chart.radar_values = val;
                         }
+                        let notes = input.slice(idx + 1..input.raw.len(), line, col + 1);
+                        chart
+                            .spans
+                            .properties
+                            .insert("notes".to_string(), notes.trimmed_span());
                     }
 
                     col += 1;
                     start_idx = idx + 1;
+                    start = (line, col);
                     state = state.next();
+                    if state == ChartParserState::Notes && self.skip_notes {
+                        return Some(chart);
+                    }
                 }
                 ChartParserState::InlineAttack => {
                     if c == CHAR_INLINE_ATTACK_END {
@@ -1431,10 +2688,24 @@ impl StepmaniaParser {
                 ChartParserState::InlineKeysound => {
                     if c == CHAR_INLINE_KEYSOUND_END {
                         state = ChartParserState::Notes;
+                        let keysound = input.slice(start_idx..idx, start.0, start.1);
+                        match (
+                            keysound.raw.trim().parse::<u32>(),
+                            current_beat_notes.last_mut(),
+                        ) {
+                            (Ok(index), Some(note)) => note.keysound = Some(index),
+                            _ => self.errors.push(ParseError {
+                                code: ParseErrorCode::StepmaniaInvalidNumber,
+                                column: keysound.column,
+                                line: keysound.line,
+                                len: keysound.len,
+                                span: Some(keysound.trimmed_span()),
+                            }),
+                        }
                         col += 1;
-                        // TOOD: Do parsing
                         continue;
                     }
+                    col += 1;
                 }
                 ChartParserState::Notes => match c {
                     CHAR_INLINE_ATTACK_START => {
@@ -1445,11 +2716,23 @@ impl StepmaniaParser {
                     CHAR_INLINE_KEYSOUND_START => {
                         state = ChartParserState::InlineKeysound;
                         col += 1;
+                        start_idx = idx + 1;
+                        start = (line, col);
                         continue;
                     }
                     CHAR_BEAT_SEPARATOR => {
+                        self.finish_row(
+                            &mut chart.data,
+                            &mut current_beat_notes,
+                            &mut current_beat_spans,
+                            row_start,
+                            line,
+                        );
                         chart.data.notes.push(current_beat_notes);
+                        chart.spans.notes.push(current_beat_spans);
                         current_beat_notes = vec![];
+                        current_beat_spans = vec![];
+                        row_start = 0;
                         col += 1;
                     }
                     // All valid note types
@@ -1460,19 +2743,19 @@ impl StepmaniaParser {
                             actions: vec![],
                             keysound: None,
                         });
+                        let offset = input.span.start + idx;
+                        current_beat_spans.push(Span::new(offset, offset + c.len_utf8()));
                         col += 1;
                     }
-                    // We need to find the column count and we do this not based on the type for now,
-                    // but based on the note count in the first line.
                     CHAR_LINE_BREAK => {
-                        if chart.data.column_count == 0 && current_beat_notes.len() > 0 {
-                            match u8::try_from(current_beat_notes.len()) {
-                                Ok(col) => chart.data.column_count = col,
-                                Err(_) => {
-                                    // TODO: Handle error
-                                }
-                            }
-                        }
+                        self.finish_row(
+                            &mut chart.data,
+                            &mut current_beat_notes,
+                            &mut current_beat_spans,
+                            row_start,
+                            line,
+                        );
+                        row_start = current_beat_notes.len();
                         col = 1;
                         line += 1;
                     }
@@ -1487,19 +2770,157 @@ impl StepmaniaParser {
             }
         }
 
-        if current_beat_notes.len() > 0 {
-            chart.data.notes.push(current_beat_notes)
+        self.finish_row(
+            &mut chart.data,
+            &mut current_beat_notes,
+            &mut current_beat_spans,
+            row_start,
+            line,
+        );
+        if !current_beat_notes.is_empty() {
+            chart.data.notes.push(current_beat_notes);
+            chart.spans.notes.push(current_beat_spans);
+        }
+
+        Some(chart)
+    }
+
+    /// Validates the length of the last row of the measure, which starts at `row_start`.
+    /// The column count is taken from the step-style, or if it's unknown, from the first row.
+    /// Rows which don't match are cut off or filled with empty notes, which have empty spans.
+    fn finish_row(
+        &mut self,
+        data: &mut StepmaniaNoteData,
+        notes: &mut Vec<StepmaniaNote>,
+        spans: &mut Vec<Span>,
+        row_start: usize,
+        line: usize,
+    ) {
+        let len = notes.len() - row_start;
+        if len == 0 {
+            return;
+        }
+
+        if data.column_count == 0 {
+            match u8::try_from(len) {
+                Ok(count) => data.column_count = count,
+                Err(_) => data.column_count = u8::MAX,
+            }
+        }
+
+        let column_count = data.column_count as usize;
+        if len != column_count {
+            let row = spans[row_start].merge(spans[spans.len() - 1]);
+            self.errors.push(ParseError {
+                code: ParseErrorCode::StepmaniaInvalidRowLength,
+                line,
+                column: 1,
+                len,
+                span: Some(row),
+            });
+            notes.resize_with(row_start + column_count, StepmaniaNote::default);
+            spans.resize(row_start + column_count, Span::new(row.end, row.end));
+        }
+    }
+
+    /// Parses a property of a SSC chart, which starts with `#NOTEDATA`
+    fn parse_ssc_chart_property(
+        &mut self,
+        name: &str,
+        value: UnparsedPropertyValue,
+        chart: &mut StepmaniaChart,
+    ) {
+        chart
+            .spans
+            .properties
+            .insert(name.to_string(), value.trimmed_span());
+        match name {
+            "chartname" => chart.name = Some(value.raw.trim().to_string()),
+            "chartstyle" => chart.chart_style = Some(value.raw.trim().to_string()),
+            "stepstype" => {
+                chart.step_style = StepmaniaStepStyle::from_name(value.raw.trim());
+                // Unknown step-styles take the column count from the first row of the notes,
+                // which is kept if they have been defined already
+                if chart.data.notes.is_empty() {
+                    chart.data.column_count = chart.step_style.column_count().unwrap_or(0);
+                }
+            }
+            // Older SSC files use the description for the credit
+            "description" if chart.credit.is_empty() => chart.credit = value.raw.trim().to_string(),
+            "credit" => chart.credit = value.raw.trim().to_string(),
+            "difficulty" => chart.difficulty = StepmaniaDifficulty::from_name(value.raw.trim()),
+            "meter" => match value.raw.trim().parse::<u16>() {
+                Ok(meter) => chart.meter = meter,
+                Err(_) => self.errors.push(ParseError {
+                    code: ParseErrorCode::StepmaniaInvalidNumber,
+                    line: value.line,
+                    column: value.column,
+                    len: value.len,
+                    span: Some(value.trimmed_span()),
+                }),
+            },
+            "radarvalues" => {
+                if let Some(radar) = self.parse_to_radio_values(value) {
+                    chart.radar_values = radar;
+                }
+            }
+            "attacks" => chart.attacks = self.parse_attacks(value),
+            "notes" | "notes2" if !self.skip_notes => {
+                if let Some(parsed) = self.parse_to_ssc_notes(value, std::mem::take(chart)) {
+                    *chart = parsed;
+                }
+            }
+            "notes" | "notes2" => {}
+            // The timing of a single chart (split timing) is not supported,
+            // the timing of the song is used for all charts.
+            "offset" | "bpms" | "stops" | "delays" | "warps" | "timesignatures" | "tickcounts"
+            | "combos" | "speeds" | "scrolls" | "fakes" | "labels" => {
+                self.errors.push(ParseError {
+                    code: ParseErrorCode::StepmaniaUnsupportedSplitTiming,
+                    line: value.line,
+                    column: value.column,
+                    len: value.len,
+                    span: Some(value.trimmed_span()),
+                })
+            }
+            _ => self.push_unknown_property(&value),
         }
+    }
 
-        return Some(chart);
+    fn push_unknown_property(&mut self, value: &UnparsedPropertyValue) {
+        self.errors.push(ParseError {
+            code: ParseErrorCode::StepmaniaUnknownPropertyName,
+            line: value.line,
+            column: value.column,
+            len: value.len,
+            span: Some(value.trimmed_span()),
+        });
     }
 
-    pub fn parse_from_string(&mut self, input: &String) -> Result<StepmaniaFile> {
+    pub fn parse_from_string(&mut self, input: &str) -> Result<StepmaniaFile> {
         let mut step: StepmaniaFile = StepmaniaFile::default();
 
-        let result = self.parse_to_property_map(input);
+        let result = self.parse_to_property_list(input);
+
+        // In SSC files, all properties after a `#NOTEDATA` belong to that chart
+        let mut in_ssc_chart = false;
+
+        for (name, value) in result {
+            if name == "notedata" {
+                step.notes.push(StepmaniaChart {
+                    ..Default::default()
+                });
+                in_ssc_chart = true;
+                continue;
+            }
+            if let Some(chart) = step.notes.last_mut().filter(|_| in_ssc_chart) {
+                self.parse_ssc_chart_property(&name, value, chart);
+                continue;
+            }
 
-        for (name, value) in result.unwrap() {
+            step.spans
+                .properties
+                .insert(name.clone(), value.trimmed_span());
             match name.as_str() {
                 // Simple string values
                 "version" => step.version = Some(value.raw.trim().to_string()),
@@ -1508,7 +2929,7 @@ impl StepmaniaParser {
                 "subtitle" => step.subtitle = Some(value.raw.trim().to_string()),
                 "subtitletranslit" => step.subtitle_translit = Some(value.raw.trim().to_string()),
                 "artist" => step.artist = Some(value.raw.trim().to_string()),
-                "artisttranslist" => step.artist_translit = Some(value.raw.trim().to_string()),
+                "artisttranslit" => step.artist_translit = Some(value.raw.trim().to_string()),
                 "genre" => step.genre = Some(value.raw.trim().to_string()),
                 "credit" => step.credit = Some(value.raw.trim().to_string()),
                 "banner" => step.banner = Some(value.raw.trim().to_string()),
@@ -1519,7 +2940,7 @@ impl StepmaniaParser {
                 "origin" => step.origin = Some(value.raw.trim().to_string()),
                 "jacket" => step.jacket = Some(value.raw.trim().to_string()),
                 "cdimage" => step.cd_image = Some(value.raw.trim().to_string()),
-                "diskimage" => step.disk_image = Some(value.raw.trim().to_string()),
+                "discimage" | "diskimage" => step.disk_image = Some(value.raw.trim().to_string()),
                 "preview" => step.preview = Some(value.raw.trim().to_string()),
 
                 // Simple inline match
@@ -1527,10 +2948,8 @@ impl StepmaniaParser {
                     match value.raw.to_lowercase().trim() {
                         "yes"
                         // backwards compatibility
-                        | "roulette" | "es" | "omes" | "1" => {
-
-                        }
-                        _ => step.selectable = false,
+                        | "roulette" | "es" | "omes" | "1" => step.selectable = Some(true),
+                        _ => step.selectable = Some(false),
                     }
                 }
 
@@ -1539,9 +2958,19 @@ impl StepmaniaParser {
                 "samplelength" => step.sample_length = self.parse_to_number(value, PRECISION_TIME),
                 "offset" => step.offset = self.parse_to_number(value, PRECISION_TIME),
                 "displaybpm" => {
-                    step.display_bpm = self.parse_to_number_range(value, PRECISION_TIME)
+                    step.display_bpm = if value.raw.trim() == "*" {
+                        Some(StepmaniaBPMRange::Random)
+                    } else {
+                        self.parse_to_number_range(value, PRECISION_TIME)
+                            .map(|range| StepmaniaBPMRange::from_bounds(range.min, range.max))
+                    }
                 }
-                "lastsecondhint" => step.last_second_hint = self.parse_to_number(value, PRECISION_TIME),
+                "lastsecondhint" => {
+                    step.last_second_hint = self.parse_to_number(value, PRECISION_TIME)
+                }
+                "musiclength" => step.music_length = self.parse_to_number(value, PRECISION_TIME),
+                "firstbeat" => step.first_beat = self.parse_to_number(value, PRECISION_TIME),
+                "lastbeat" => step.last_beat = self.parse_to_number(value, PRECISION_TIME),
 
                 // visual changes
                 "bgchanges" => {
@@ -1567,6 +2996,21 @@ impl StepmaniaParser {
                         tmp.parse_to_visual_change(group)
                     })
                 }
+                // Background layers 4 and up
+                layer if layer.starts_with("bgchanges") => {
+                    if let Ok(layer) = layer["bgchanges".len()..].parse::<usize>() {
+                        if layer >= 4 {
+                            let changes = self.parse_value_group(&value, 1, 11, |tmp, group| {
+                                tmp.parse_to_visual_change(group)
+                            });
+                            let layers = &mut step.additional_background_changes;
+                            if layers.len() < layer - 3 {
+                                layers.resize_with(layer - 3, Vec::new);
+                            }
+                            layers[layer - 4] = changes;
+                        }
+                    }
+                }
                 "animations" => {
                     step.animations = self.parse_value_group(&value, 1, 11, |tmp, group| {
                         tmp.parse_to_visual_change(group)
@@ -1577,7 +3021,7 @@ impl StepmaniaParser {
                 "keysounds" => step.keysounds = self.parse_to_string_list(value),
 
                 // Instrument Tracks
-                "instrumenttracks" => {
+                "instrumenttracks" | "instrumenttrack" => {
                     step.instrument_tracks = self.parse_value_group(&value, 2, 2, |tmp, group| {
                         tmp.parse_to_instrument_track(group)
                     })
@@ -1600,6 +3044,12 @@ impl StepmaniaParser {
                     })
                 }
 
+                // Warps
+                "warps" => {
+                    step.warps =
+                        self.parse_value_group(&value, 2, 2, |tmp, group| tmp.parse_to_warp(group))
+                }
+
                 // Timed BPMs
                 "bpms" => {
                     step.bpms = self
@@ -1623,16 +3073,16 @@ impl StepmaniaParser {
                 // Attacks
                 "attacks" => step.attacks = self.parse_attacks(value),
 
-                // Combo changes
+                // Combo changes, of which the misses are optional
                 "combos" => {
-                    step.combos = self.parse_value_group(&value, 3, 3, |tmp, group| {
+                    step.combos = self.parse_value_group(&value, 2, 3, |tmp, group| {
                         tmp.parse_to_timed_combo_change(group)
                     })
                 }
 
-                // Speed changes
+                // Speed changes, of which the unit of the duration is optional
                 "speeds" => {
-                    step.speeds = self.parse_value_group(&value, 4, 4, |tmp, group| {
+                    step.speeds = self.parse_value_group(&value, 3, 4, |tmp, group| {
                         tmp.parse_to_timed_speed_change(group)
                     })
                 }
@@ -1652,24 +3102,470 @@ impl StepmaniaParser {
                 }
 
                 // Notes
-                "notes" => {
-                    step.notes = self.parse_to_chart(value);
+                "notes" | "notes2" => {
+                    if let Some(chart) = self.parse_to_chart(value) {
+                        step.notes.push(chart);
+                    }
                 }
 
-                // Unhandled keys are not recognised, and should be marked as correct warning/error
-                _ => {
-                    //     self.errors.push(ParseError {
-                    //     code: ERROR_STEPMANIA_UNKNOWN_PROPERTY_NAME,
-                    //     line: value.line,
-                    //     column: value.column,
-                    //     len: value.len,
-                    // })
-                }
+                // Unhandled keys are not recognised, and are dropped when the file is written again
+                _ => self.push_unknown_property(&value),
+            }
+
+            if !self.entry_spans.is_empty() {
+                step.spans
+                    .entries
+                    .insert(name, std::mem::take(&mut self.entry_spans));
             }
         }
 
         Ok(step)
     }
+
+    /// Reads only the metadata of the song and the headers of its charts, skipping all note-data
+    pub fn parse_song_info(&mut self, input: &str) -> Result<StepmaniaSongInfo> {
+        let skip_notes = std::mem::replace(&mut self.skip_notes, true);
+        let step = self.parse_from_string(input);
+        self.skip_notes = skip_notes;
+        Ok(StepmaniaSongInfo::from_step(&step?))
+    }
+}
+
+/// The span from the first to the last value of the group
+fn group_span(group: &[UnparsedPropertyValue]) -> Span {
+    let first = group.first().map(|value| value.trimmed_span());
+    let last = group.last().map(|value| value.trimmed_span());
+    first
+        .zip(last)
+        .map(|(first, last)| first.merge(last))
+        .unwrap_or_default()
+}
+
+/// Removes the escape characters and quotes of the value, the same way the tokenizer handles them
+fn unescape_value(raw: &str) -> Cow<'_, str> {
+    unescape_value_with_offsets(raw, 0).0
+}
+
+/// Like `unescape_value`, but also returns the offset in the source of every byte of the value and of its end,
+/// for a value which starts at `start`. The offsets are empty if nothing has been removed.
+fn unescape_value_with_offsets(raw: &str, start: usize) -> (Cow<'_, str>, Vec<usize>) {
+    if !raw.contains([CHAR_ESCAPE, CHAR_DOUBLE_QUOTE, CHAR_SINGLE_QUOTE]) {
+        return (Cow::Borrowed(raw), vec![]);
+    }
+
+    let mut value = String::with_capacity(raw.len());
+    let mut offsets = Vec::with_capacity(raw.len() + 1);
+    // Right after the last character which has been kept
+    let mut end = start;
+    let mut escaped = false;
+    let mut quote: Option<char> = None;
+    for (idx, c) in raw.char_indices() {
+        let keep = if escaped {
+            escaped = false;
+            true
+        } else if c == CHAR_ESCAPE {
+            escaped = true;
+            false
+        } else if quote == Some(c) {
+            quote = None;
+            false
+        } else if quote.is_none()
+            && (c == CHAR_DOUBLE_QUOTE || c == CHAR_SINGLE_QUOTE)
+            && value.trim().is_empty()
+            && has_closing_quote(raw[idx + 1..].chars(), c)
+        {
+            quote = Some(c);
+            false
+        } else {
+            true
+        };
+
+        if keep {
+            value.push(c);
+            offsets.extend(start + idx..start + idx + c.len_utf8());
+            end = start + idx + c.len_utf8();
+        }
+    }
+    offsets.push(end);
+
+    (Cow::Owned(value), offsets)
+}
+
+/// If the quote is closed before the end of the line
+fn has_closing_quote(rest: impl Iterator<Item = char>, quote: char) -> bool {
+    let mut escaped = false;
+    for c in rest {
+        if c == CHAR_LINE_BREAK {
+            return false;
+        }
+        if escaped {
+            escaped = false;
+        } else if c == CHAR_ESCAPE {
+            escaped = true;
+        } else if c == quote {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Escapes all characters of the value which would otherwise end the value or property
+fn escape_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        // A quote at the start of the value would be read as quoting the value
+        let is_leading_quote =
+            (c == CHAR_DOUBLE_QUOTE || c == CHAR_SINGLE_QUOTE) && escaped.trim().is_empty();
+        if ESCAPED_CHARS.contains(&c) || is_leading_quote {
+            escaped.push(CHAR_ESCAPE);
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Formats a number which has been parsed with the `PRECISION_TIME`
+fn format_number(value: i64) -> String {
+    let sign = if value < 0 { "-" } else { "" };
+    format!("{}{}.{:03}", sign, value.abs() / 1000, value.abs() % 1000)
+}
+
+/// Formats the radar values like the RADARVALUES property
+pub(crate) fn format_radar_values(radar: &StepmaniaRadarValues) -> String {
+    format!(
+        "{:.3},{:.3},{:.3},{:.3},{:.3}",
+        radar.stream, radar.voltage, radar.air, radar.freeze, radar.chaos
+    )
+}
+
+/// Formats an attack like the entries of the ATTACKS property
+pub(crate) fn format_attack(attack: &StepmaniaAttack) -> String {
+    let mods = attack
+        .modifiers
+        .iter()
+        .map(|modifier| {
+            let mut parts: Vec<String> = vec![];
+            if let Some(rate) = modifier.approach_rate {
+                parts.push(format!("*{}", rate));
+            }
+            if let Some(player) = &modifier.player {
+                parts.push(player.clone());
+            }
+            match modifier.magnitude {
+                StepmaniaMagnitude::Percent(percent) => parts.push(format!("{}%", percent)),
+                StepmaniaMagnitude::Amount(amount) => parts.push(format_number(amount)),
+            }
+            parts.push(modifier.name.clone());
+            parts.join(" ")
+        })
+        .collect::<Vec<String>>()
+        .join(",");
+
+    format!(
+        "TIME={}:LEN={}:MODS={}",
+        format_number(attack.start),
+        format_number(attack.duration),
+        mods
+    )
+}
+
+fn format_color(color: &StepmaniaColor) -> String {
+    [color.red, color.green, color.blue, color.alpha]
+        .iter()
+        .map(|channel| format!("{:.3}", *channel as f32 / 255.0))
+        .collect::<Vec<String>>()
+        .join(&CHAR_COLOR_SEPARATOR.to_string())
+}
+
+fn format_bool(value: bool) -> &'static str {
+    if value {
+        "1"
+    } else {
+        "0"
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct StepmaniaWriter {
+    output: String,
+}
+
+impl StepmaniaWriter {
+    pub fn new() -> StepmaniaWriter {
+        StepmaniaWriter {
+            ..Default::default()
+        }
+    }
+
+    fn write_property(&mut self, name: &str, value: &str) {
+        self.output.push(CHAR_PROPERTY_START);
+        self.output.push_str(name);
+        self.output.push(CHAR_VALUE_START);
+        self.output.push_str(value);
+        self.output.push(CHAR_VALUE_END);
+        self.output.push(CHAR_LINE_BREAK);
+    }
+
+    /// Writes the text if it's set, escaping all special characters
+    fn write_optional(&mut self, name: &str, value: &Option<String>) {
+        if let Some(val) = value {
+            self.write_property(name, &escape_value(val));
+        }
+    }
+
+    /// Writes the list of entries, which are joined with the object separator
+    fn write_list<T, F>(&mut self, name: &str, list: &[T], mapper_fn: F)
+    where
+        F: Fn(&T) -> Vec<String>,
+    {
+        if list.is_empty() {
+            return;
+        }
+
+        let value = list
+            .iter()
+            .map(|entry| mapper_fn(entry).join(&CHAR_OBJ_VAL_SEPARATOR.to_string()))
+            .collect::<Vec<String>>()
+            .join(&format!("{}{}", CHAR_OBJ_SEPARATOR, CHAR_LINE_BREAK));
+        self.write_property(name, &value);
+    }
+
+    fn write_visual_changes(&mut self, name: &str, list: &[StepmaniaTimedVisualChange]) {
+        self.write_list(name, list, |change| {
+            vec![
+                format_number(change.beat),
+                escape_value(&change.path),
+                format!("{}.000", change.play_rate),
+                format_bool(change.crossfade).to_string(),
+                format_bool(change.stretch_rewind).to_string(),
+                format_bool(change.stretch_no_loop).to_string(),
+                change.effect.clone(),
+                escape_value(&change.file2),
+                change.transition.clone(),
+                format_color(&change.color1),
+                format_color(&change.color2),
+            ]
+        });
+    }
+
+    fn write_attacks(&mut self, attacks: &[StepmaniaAttack]) {
+        if attacks.is_empty() {
+            return;
+        }
+
+        let value = attacks
+            .iter()
+            .map(format_attack)
+            .collect::<Vec<String>>()
+            .join(&format!(
+                "{}{}",
+                CHAR_ATTACK_VALUE_SEPARATOR, CHAR_LINE_BREAK
+            ));
+        self.write_property("ATTACKS", &value);
+    }
+
+    fn format_note_data(data: &StepmaniaNoteData) -> String {
+        let mut value = String::new();
+        let column_count = data.column_count.max(1) as usize;
+        for (idx, measure) in data.notes.iter().enumerate() {
+            if idx > 0 {
+                value.push_str(&format!("\n{}", CHAR_BEAT_SEPARATOR));
+            }
+            for (note_idx, note) in measure.iter().enumerate() {
+                if note_idx % column_count == 0 {
+                    value.push(CHAR_LINE_BREAK);
+                }
+                value.push(note.note_type.to_char());
+                if let Some(keysound) = note.keysound {
+                    value.push_str(&format!(
+                        "{}{}{}",
+                        CHAR_INLINE_KEYSOUND_START, keysound, CHAR_INLINE_KEYSOUND_END
+                    ));
+                }
+            }
+        }
+        value.push(CHAR_LINE_BREAK);
+
+        value
+    }
+
+    fn write_chart(&mut self, chart: &StepmaniaChart) {
+        let header = [
+            chart.step_style.name().to_string(),
+            escape_value(&chart.credit),
+            chart.difficulty.name().to_string(),
+            chart.meter.to_string(),
+            format_radar_values(&chart.radar_values),
+        ];
+
+        let mut value = String::new();
+        for field in header.iter() {
+            value.push_str(&format!("\n     {}{}", field, CHAR_NOTE_PROP_SEPARATOR));
+        }
+        value.push_str(&StepmaniaWriter::format_note_data(&chart.data));
+
+        self.output.push_str(&format!(
+            "//---------------{} - {}----------------\n",
+            chart.step_style.name(),
+            chart.credit
+        ));
+        self.write_property("NOTES", &value);
+    }
+
+    /// Writes the chart in the SSC format, where every field is a separate property
+    fn write_ssc_chart(&mut self, chart: &StepmaniaChart) {
+        self.write_property("NOTEDATA", "");
+        self.write_optional("CHARTNAME", &chart.name);
+        self.write_property("STEPSTYPE", chart.step_style.name());
+        self.write_optional("CHARTSTYLE", &chart.chart_style);
+        self.write_property("CREDIT", &escape_value(&chart.credit));
+        self.write_property("DIFFICULTY", chart.difficulty.name());
+        self.write_property("METER", &chart.meter.to_string());
+        self.write_property("RADARVALUES", &format_radar_values(&chart.radar_values));
+        self.write_attacks(&chart.attacks);
+        self.write_property("NOTES", &StepmaniaWriter::format_note_data(&chart.data));
+    }
+
+    pub fn write_to_string(&mut self, step: &StepmaniaFile) -> Result<String> {
+        self.output = String::new();
+
+        self.write_optional("VERSION", &step.version);
+        self.write_optional("TITLE", &step.title);
+        self.write_optional("SUBTITLE", &step.subtitle);
+        self.write_optional("ARTIST", &step.artist);
+        self.write_optional("TITLETRANSLIT", &step.title_translit);
+        self.write_optional("SUBTITLETRANSLIT", &step.subtitle_translit);
+        self.write_optional("ARTISTTRANSLIT", &step.artist_translit);
+        self.write_optional("GENRE", &step.genre);
+        self.write_optional("ORIGIN", &step.origin);
+        self.write_optional("CREDIT", &step.credit);
+        self.write_optional("BANNER", &step.banner);
+        self.write_optional("BACKGROUND", &step.background);
+        self.write_optional("PREVIEW", &step.preview);
+        self.write_optional("JACKET", &step.jacket);
+        self.write_optional("CDIMAGE", &step.cd_image);
+        self.write_optional("DISCIMAGE", &step.disk_image);
+        self.write_optional("LYRICSPATH", &step.lyrics_path);
+        self.write_optional("CDTITLE", &step.cd_title);
+        self.write_optional("MUSIC", &step.music);
+        self.write_list("INSTRUMENTTRACKS", &step.instrument_tracks, |track| {
+            vec![escape_value(&track.instrument), escape_value(&track.file)]
+        });
+
+        if let Some(offset) = step.offset {
+            self.write_property("OFFSET", &format_number(offset));
+        }
+        if let Some(start) = step.sample_start {
+            self.write_property("SAMPLESTART", &format_number(start));
+        }
+        if let Some(length) = step.sample_length {
+            self.write_property("SAMPLELENGTH", &format_number(length));
+        }
+        if let Some(selectable) = step.selectable {
+            self.write_property("SELECTABLE", if selectable { "YES" } else { "NO" });
+        }
+        match &step.display_bpm {
+            Some(StepmaniaBPMRange::Single(bpm)) => {
+                self.write_property("DISPLAYBPM", &format_number(*bpm))
+            }
+            Some(StepmaniaBPMRange::Range(min, max)) => self.write_property(
+                "DISPLAYBPM",
+                &format!("{}:{}", format_number(*min), format_number(*max)),
+            ),
+            Some(StepmaniaBPMRange::Random) => self.write_property("DISPLAYBPM", "*"),
+            None => {}
+        }
+
+        self.write_list("BPMS", &step.bpms, |bpm| {
+            vec![format_number(bpm.beat), format_number(bpm.bpm)]
+        });
+        self.write_list("STOPS", &step.stops, |stop| {
+            vec![format_number(stop.beat), format_number(stop.duration)]
+        });
+        self.write_list("DELAYS", &step.delays, |delay| {
+            vec![format_number(delay.beat), format_number(delay.duration)]
+        });
+        self.write_list("WARPS", &step.warps, |warp| {
+            vec![
+                format_number(warp.beat),
+                format_number(warp.end_beat - warp.beat),
+            ]
+        });
+        self.write_list("TIMESIGNATURES", &step.time_signatures, |sig| {
+            vec![
+                format_number(sig.beat),
+                sig.numerator.to_string(),
+                sig.denominator.to_string(),
+            ]
+        });
+        self.write_list("TICKCOUNTS", &step.tick_counts, |tick| {
+            vec![format_number(tick.beat), tick.value.to_string()]
+        });
+        self.write_list("COMBOS", &step.combos, |combo| {
+            vec![
+                format_number(combo.beat),
+                combo.hit.to_string(),
+                combo.miss.to_string(),
+            ]
+        });
+        self.write_list("SPEEDS", &step.speeds, |speed| {
+            vec![
+                format_number(speed.beat),
+                format!("{:.3}", speed.ratio),
+                format_number(speed.duration),
+                format_bool(speed.in_seconds).to_string(),
+            ]
+        });
+        self.write_list("SCROLLS", &step.scrolls, |scroll| {
+            vec![format_number(scroll.beat), format!("{:.3}", scroll.factor)]
+        });
+        self.write_list("FAKES", &step.fakes, |fake| {
+            vec![format_number(fake.beat), format_number(fake.duration)]
+        });
+        self.write_list("LABELS", &step.labels, |label| {
+            vec![format_number(label.beat), escape_value(&label.label)]
+        });
+        if let Some(hint) = step.last_second_hint {
+            self.write_property("LASTSECONDHINT", &format_number(hint));
+        }
+        if let Some(length) = step.music_length {
+            self.write_property("MUSICLENGTH", &format_number(length));
+        }
+        if let Some(beat) = step.first_beat {
+            self.write_property("FIRSTBEAT", &format_number(beat));
+        }
+        if let Some(beat) = step.last_beat {
+            self.write_property("LASTBEAT", &format_number(beat));
+        }
+
+        self.write_visual_changes("BGCHANGES", &step.background_changes);
+        self.write_visual_changes("BGCHANGES2", &step.background_changes2);
+        self.write_visual_changes("BGCHANGES3", &step.background_changes3);
+        for (idx, changes) in step.additional_background_changes.iter().enumerate() {
+            self.write_visual_changes(&format!("BGCHANGES{}", idx + 4), changes);
+        }
+        self.write_visual_changes("ANIMATIONS", &step.animations);
+        self.write_visual_changes("FGCHANGES", &step.foreground_changes);
+
+        if !step.keysounds.is_empty() {
+            let keysounds: Vec<String> = step.keysounds.iter().map(|k| escape_value(k)).collect();
+            self.write_property("KEYSOUNDS", &keysounds.join(","));
+        }
+        self.write_attacks(&step.attacks);
+
+        // Only SSC files have a version
+        for chart in step.notes.iter() {
+            if step.version.is_some() {
+                self.write_ssc_chart(chart);
+            } else {
+                self.write_chart(chart);
+            }
+        }
+
+        Ok(std::mem::take(&mut self.output))
+    }
 }
 
 #[cfg(test)]
@@ -1682,7 +3578,7 @@ mod tests {
         let data = "
 #TITLE:ゾンビー・サーカス;
 ";
-        let res = parser.parse_from_string(&data.to_string());
+        let res = parser.parse_from_string(data);
         assert!(res.is_ok());
         assert_eq!(parser.errors.len(), 0);
 
@@ -1697,7 +3593,7 @@ mod tests {
         let data = "
 #TITLETRANSLIT:  hello world! ;
 ";
-        let res = parser.parse_from_string(&data.to_string());
+        let res = parser.parse_from_string(data);
         assert!(res.is_ok());
         assert_eq!(parser.errors.len(), 0);
 
@@ -1713,7 +3609,7 @@ mod tests {
 #SAMPLESTART:1.333  ;
 #SAMPLELENGTH: 83;
 ";
-        let res = parser.parse_from_string(&data.to_string());
+        let res = parser.parse_from_string(data);
         assert!(res.is_ok());
         assert_eq!(parser.errors.len(), 0);
 
@@ -1730,15 +3626,234 @@ mod tests {
         let data = "
 #DISPLAYBPM:66.6668423 -240;
 ";
-        let res = parser.parse_from_string(&data.to_string());
+        let res = parser.parse_from_string(data);
         assert!(res.is_ok());
         assert_eq!(parser.errors.len(), 0);
 
         let chart = res.unwrap();
-        assert!(chart.display_bpm.is_some());
-        let bpm = chart.display_bpm.unwrap();
-        assert_eq!(bpm.min, 66666);
-        assert_eq!(bpm.max, 240000);
+        assert_eq!(
+            chart.display_bpm,
+            Some(StepmaniaBPMRange::Range(66666, 240000))
+        );
+    }
+
+    #[test]
+    fn it_should_parse_random_display_bpm() {
+        let mut parser = StepmaniaParser::new();
+        let data = "
+#DISPLAYBPM:*;
+";
+        let step = parser.parse_from_string(data).unwrap();
+        assert_eq!(parser.errors.len(), 0);
+        assert_eq!(step.display_bpm, Some(StepmaniaBPMRange::Random));
+    }
+
+    #[test]
+    fn it_should_parse_genre() {
+        let mut parser = StepmaniaParser::new();
+        let data = "
+#GENRE: Eurobeat ;
+";
+        let step = parser.parse_from_string(data).unwrap();
+        assert_eq!(parser.errors.len(), 0);
+        assert_eq!(step.genre.as_deref(), Some("Eurobeat"));
+    }
+
+    #[test]
+    fn it_should_parse_artist_translit() {
+        let mut parser = StepmaniaParser::new();
+        let data = "
+#ARTISTTRANSLIT:Zombie Circus;
+";
+        let step = parser.parse_from_string(data).unwrap();
+        assert_eq!(parser.errors.len(), 0);
+        assert_eq!(step.artist_translit.as_deref(), Some("Zombie Circus"));
+    }
+
+    #[test]
+    fn it_should_parse_disc_image() {
+        let mut parser = StepmaniaParser::new();
+        let data = "
+#DISCIMAGE:disc.png;
+";
+        let step = parser.parse_from_string(data).unwrap();
+        assert_eq!(parser.errors.len(), 0);
+        assert_eq!(step.disk_image.as_deref(), Some("disc.png"));
+    }
+
+    #[test]
+    fn it_should_parse_music_length() {
+        let mut parser = StepmaniaParser::new();
+        let data = "
+#MUSICLENGTH:104.523;
+";
+        let step = parser.parse_from_string(data).unwrap();
+        assert_eq!(parser.errors.len(), 0);
+        assert_eq!(step.music_length, Some(104523));
+    }
+
+    #[test]
+    fn it_should_parse_first_and_last_beat() {
+        let mut parser = StepmaniaParser::new();
+        let data = "
+#FIRSTBEAT:4.000;
+#LASTBEAT:291.500;
+";
+        let step = parser.parse_from_string(data).unwrap();
+        assert_eq!(parser.errors.len(), 0);
+        assert_eq!(step.first_beat, Some(4000));
+        assert_eq!(step.last_beat, Some(291500));
+    }
+
+    #[test]
+    fn it_should_parse_warps() {
+        let mut parser = StepmaniaParser::new();
+        let data = "
+#WARPS:16.000=2.500,
+64=0.25;
+";
+        let step = parser.parse_from_string(data).unwrap();
+        assert_eq!(parser.errors.len(), 0);
+        assert_eq!(step.warps.len(), 2);
+        assert_eq!(step.warps[0].beat, 16000);
+        assert_eq!(step.warps[0].end_beat, 18500);
+        assert_eq!(step.warps[1].beat, 64000);
+        assert_eq!(step.warps[1].end_beat, 64250);
+    }
+
+    #[test]
+    fn it_should_parse_additional_bg_changes() {
+        let mut parser = StepmaniaParser::new();
+        let data = "
+#BGCHANGES3:0=layer3.png=1.000=0=0=0;
+#BGCHANGES5:8=layer5.png=1.000=0=0=0;
+";
+        let step = parser.parse_from_string(data).unwrap();
+        assert_eq!(step.background_changes3[0].path, "layer3.png");
+        assert_eq!(step.additional_background_changes.len(), 2);
+        assert!(step.additional_background_changes[0].is_empty());
+        assert_eq!(step.additional_background_changes[1][0].beat, 8000);
+        assert_eq!(step.additional_background_changes[1][0].path, "layer5.png");
+    }
+
+    #[test]
+    fn it_should_parse_song_info_without_notes() {
+        let mut parser = StepmaniaParser::new();
+        let data = "
+#TITLE:Song;
+#ARTIST:Artist;
+#DISPLAYBPM:120:180;
+#BPMS:0=150,4=-60,8=180;
+#NOTES:
+     dance-double:
+     Someone:
+     Hard:
+     11:
+     0,0,0,0,0:
+10X0
+;
+#NOTEDATA:;
+#CHARTNAME:Mild;
+#STEPSTYPE:dance-single;
+#DIFFICULTY:Easy;
+#METER:3;
+#NOTES:
+10X0
+;
+";
+        let info = parser.parse_song_info(data).unwrap();
+        // The invalid rows of the notes haven't been read
+        assert_eq!(parser.errors.len(), 0);
+        assert!(!parser.skip_notes);
+        assert_eq!(info.title.as_deref(), Some("Song"));
+        assert_eq!(info.artist.as_deref(), Some("Artist"));
+        assert_eq!(
+            info.display_bpm,
+            Some(StepmaniaBPMRange::Range(120000, 180000))
+        );
+        assert_eq!(info.min_bpm, Some(150000));
+        assert_eq!(info.max_bpm, Some(180000));
+
+        assert_eq!(info.charts.len(), 2);
+        assert_eq!(info.charts[0].step_style, StepmaniaStepStyle::DanceDouble);
+        assert_eq!(info.charts[0].credit, "Someone");
+        assert_eq!(info.charts[0].difficulty, StepmaniaDifficulty::Hard);
+        assert_eq!(info.charts[0].meter, 11);
+        assert_eq!(info.charts[1].name.as_deref(), Some("Mild"));
+        assert_eq!(info.charts[1].difficulty, StepmaniaDifficulty::Easy);
+        assert_eq!(info.charts[1].meter, 3);
+
+        parser.skip_notes = true;
+        let step = parser.parse_from_string(data).unwrap();
+        assert!(step.notes.iter().all(|chart| chart.data.notes.is_empty()));
+        assert_eq!(parser.errors.len(), 0);
+
+        parser.skip_notes = false;
+        parser.parse_from_string(data).unwrap();
+        assert_eq!(parser.errors.len(), 2);
+    }
+
+    #[test]
+    fn it_should_parse_ssc_charts() {
+        let mut parser = StepmaniaParser::new();
+        let data = "
+#VERSION:0.83;
+#TITLE:Song;
+#BPMS:0=120;
+#NOTEDATA:;
+#CHARTNAME:Mild;
+#STEPSTYPE:dance-single;
+#DESCRIPTION:Old credit;
+#DIFFICULTY:Hard;
+#METER:9;
+#RADARVALUES:0,0,0,0,0;
+#ATTACKS:TIME=1.500:LEN=2.000:MODS=*2 50% drunk;
+#BPMS:0=240;
+#NOTES:
+1000
+0100
+0010
+0001
+;
+#NOTEDATA:;
+#STEPSTYPE:dance-double;
+#CREDIT:Someone;
+#DIFFICULTY:Easy;
+#METER:3;
+#NOTES:
+10000001
+;
+";
+        let step = parser.parse_from_string(data).unwrap();
+        assert_eq!(step.title.as_deref(), Some("Song"));
+        // Split timing is not supported
+        assert_eq!(parser.errors.len(), 1);
+        assert_eq!(
+            parser.errors[0].code,
+            ParseErrorCode::StepmaniaUnsupportedSplitTiming
+        );
+        assert_eq!(step.bpms.len(), 1);
+        assert_eq!(step.bpms[0].bpm, 120000);
+        assert_eq!(step.notes.len(), 2);
+
+        let chart = &step.notes[0];
+        assert_eq!(chart.name.as_deref(), Some("Mild"));
+        assert_eq!(chart.step_style, StepmaniaStepStyle::DanceSingle);
+        assert_eq!(chart.credit, "Old credit");
+        assert!(matches!(chart.difficulty, StepmaniaDifficulty::Hard));
+        assert_eq!(chart.meter, 9);
+        assert_eq!(chart.attacks.len(), 1);
+        assert_eq!(chart.attacks[0].start, 1500);
+        assert_eq!(chart.attacks[0].duration, 2000);
+        assert_eq!(chart.data.notes.len(), 1);
+        assert_eq!(chart.data.notes[0].len(), 16);
+
+        let chart = &step.notes[1];
+        assert_eq!(chart.step_style, StepmaniaStepStyle::DanceDouble);
+        assert_eq!(chart.credit, "Someone");
+        assert_eq!(chart.meter, 3);
+        assert_eq!(chart.data.column_count, 8);
+        assert!(step.attacks.is_empty());
     }
 
     #[test]
@@ -1748,14 +3863,14 @@ mod tests {
 #INSTRUMENTTRACKS:guitar=guiatarrr.ogg,
     drums= drums.mp3, vocal =yer.mp3;
 ";
-        let res = parser.parse_from_string(&data.to_string());
+        let res = parser.parse_from_string(data);
         assert!(res.is_ok());
         assert_eq!(parser.errors.len(), 0);
 
         let chart = res.unwrap();
         assert_eq!(chart.instrument_tracks.len(), 3);
 
-        let guitar = chart.instrument_tracks.get(0).unwrap();
+        let guitar = chart.instrument_tracks.first().unwrap();
         let drums = chart.instrument_tracks.get(1).unwrap();
         let vocals = chart.instrument_tracks.get(2).unwrap();
 
@@ -1768,4 +3883,551 @@ mod tests {
         assert_eq!(vocals.instrument, "vocal");
         assert_eq!(vocals.file, "yer.mp3");
     }
+
+    #[test]
+    fn it_should_convert_beats_to_time() {
+        let step = StepmaniaFile {
+            offset: Some(-500),
+            bpms: vec![
+                StepmaniaTimedBPM {
+                    beat: 0,
+                    bpm: 120000,
+                },
+                StepmaniaTimedBPM {
+                    beat: 4000,
+                    bpm: 240000,
+                },
+            ],
+            stops: vec![StepmaniaTimedDuration {
+                beat: 6000,
+                duration: 1000,
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(step.beat_to_ms(0.0), 500.0);
+        assert_eq!(step.beat_to_ms(4.0), 2500.0);
+        assert_eq!(step.beat_to_ms(6.0), 3000.0);
+        assert_eq!(step.beat_to_ms(7.0), 4250.0);
+        assert!((step.ms_to_beat(4250.0) - 7.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn it_should_validate_row_lengths() {
+        let mut parser = StepmaniaParser::new();
+        let data = "
+#NOTES:
+     dance-single:
+     :
+     Easy:
+     1:
+     0,0,0,0,0:
+1000
+01000
+0010
+001
+;
+";
+        let res = parser.parse_from_string(data);
+        assert!(res.is_ok());
+        assert_eq!(parser.errors.len(), 2);
+        assert_eq!(
+            parser.errors[0].code,
+            ParseErrorCode::StepmaniaInvalidRowLength
+        );
+        assert_eq!(parser.errors[0].line, 9);
+        assert_eq!(parser.errors[1].line, 11);
+
+        let step = res.unwrap();
+        let chart = &step.notes[0];
+        assert_eq!(chart.step_style, StepmaniaStepStyle::DanceSingle);
+        assert_eq!(chart.data.column_count, 4);
+        assert_eq!(chart.data.notes[0].len(), 16);
+    }
+
+    #[test]
+    fn it_should_warn_about_split_timing() {
+        let mut parser = StepmaniaParser::new();
+        let data = "#VERSION:0.83;
+#BPMS:0=120;
+#NOTEDATA:;
+#STEPSTYPE:malody-12k;
+#BPMS:0=150;
+#OFFSET:0.5;
+#NOTES:
+100000000001
+000000000000
+000000000000
+000000000000
+;
+#NOTEDATA:;
+#NOTES:
+010
+000
+000
+000
+;
+#STEPSTYPE:custom-three;
+";
+        let step = parser.parse_from_string(data).unwrap();
+        assert_eq!(step.bpms.len(), 1);
+        assert_eq!(step.bpms[0].bpm, 120000);
+
+        let errors: Vec<(ParseErrorCode, usize, usize)> = parser
+            .errors
+            .iter()
+            .map(|err| (err.code, err.line, err.column))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (ParseErrorCode::StepmaniaUnsupportedSplitTiming, 5, 7),
+                (ParseErrorCode::StepmaniaUnsupportedSplitTiming, 6, 9),
+            ]
+        );
+
+        // The column count of unknown step-styles is taken from the first row
+        let data = &step.notes[0].data;
+        assert_eq!(data.column_count, 12);
+        assert_eq!(data.positioned_notes()[1].column, 11);
+        assert_eq!(step.notes[1].data.column_count, 3);
+    }
+
+    #[test]
+    fn it_should_report_rejected_timed_values() {
+        let mut parser = StepmaniaParser::new();
+        let data = "
+#COMBOS:0=1=1,4=x=1,8=2;
+#SPEEDS:0=1=0=0,
+4=fast=0=0,8=2=1;
+#BPMS:0=120=7;
+";
+        let step = parser.parse_from_string(data).unwrap();
+        assert_eq!(step.combos.len(), 2);
+        assert_eq!(step.combos[1].miss, 2);
+        assert_eq!(step.speeds.len(), 2);
+        assert!(!step.speeds[1].in_seconds);
+
+        let errors: Vec<(ParseErrorCode, usize, usize)> = parser
+            .errors
+            .iter()
+            .map(|err| (err.code, err.line, err.column))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (ParseErrorCode::StepmaniaInvalidNumber, 2, 17),
+                (ParseErrorCode::StepmaniaInvalidNumber, 4, 3),
+                (ParseErrorCode::StepmaniaInvalidValueCount, 5, 7),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_track_spans() {
+        let data = std::fs::read_to_string("test-files/sample1.sm").unwrap();
+        let mut parser = StepmaniaParser::new();
+        let step = parser.parse_from_string(&data).unwrap();
+        let index = LineIndex::new(&data);
+
+        let title = step.spans.properties["title"];
+        assert_eq!(title.slice(&data), "ゾンビー・サーカス");
+        assert_eq!(index.line_column(title.start), (1, 8));
+        assert_eq!(step.spans.properties["artist"].slice(&data), "かめりあ");
+
+        let bpms = &step.spans.entries["bpms"];
+        assert_eq!(bpms.len(), step.bpms.len());
+        assert_eq!(bpms[1].slice(&data), "5.000=230.000");
+        assert_eq!(index.line_column(bpms[1].start), (35, 2));
+
+        let chart = &step.notes[0];
+        assert_eq!(chart.spans.properties["credit"].slice(&data), "cool-dood");
+        assert_eq!(chart.spans.properties["meter"].slice(&data), "20");
+        assert_eq!(chart.spans.notes.len(), chart.data.notes.len());
+        for (spans, notes) in chart.spans.notes.iter().zip(chart.data.notes.iter()) {
+            assert_eq!(spans.len(), notes.len());
+        }
+        let note = chart.spans.notes[0][0];
+        assert_eq!(note.slice(&data), "0");
+        assert_eq!(index.line_column(note.start), (56, 1));
+    }
+
+    #[test]
+    fn it_should_track_spans_of_escaped_values() {
+        let data = "#TITLE:\"Q\";\n#SUBTITLE: '日本' ;\n#LABELS:0=a\\:b\\:c,4=hello;\n";
+        let mut parser = StepmaniaParser::new();
+        let step = parser.parse_from_string(data).unwrap();
+        let index = LineIndex::new(data);
+
+        assert_eq!(step.spans.properties["title"].slice(data), "Q");
+        let subtitle = step.spans.properties["subtitle"];
+        assert_eq!(subtitle.slice(data), "日本");
+        assert_eq!(index.line_column(subtitle.end), (2, 15));
+        let labels = &step.spans.entries["labels"];
+        assert_eq!(labels[0].slice(data), "0=a\\:b\\:c");
+        assert_eq!(labels[1].slice(data), "4=hello");
+
+        let data = "#LABELS:0=\\:日,4=x;\n";
+        let step = parser.parse_from_string(data).unwrap();
+        let index = LineIndex::new(data);
+        let labels = &step.spans.entries["labels"];
+        assert_eq!(step.labels[0].label, ":日");
+        assert_eq!(labels[0].slice(data), "0=\\:日");
+        assert_eq!(index.line_column(labels[0].end), (1, 14));
+        assert_eq!(labels[1].slice(data), "4=x");
+    }
+
+    #[test]
+    fn it_should_track_error_spans() {
+        let data = "#TITLE:タイトル;\n#BPMS:0=120,4=x;\n";
+        let mut parser = StepmaniaParser::new();
+        parser.parse_from_string(data).unwrap();
+
+        assert_eq!(parser.errors.len(), 1);
+        let error = &parser.errors[0];
+        assert_eq!(error.code, ParseErrorCode::StepmaniaInvalidNumber);
+        let span = error.span.unwrap();
+        assert_eq!(span.slice(data), "x");
+        assert_eq!(span.line_column(data), (error.line, error.column));
+    }
+
+    #[test]
+    fn it_should_tokenize_without_copying() {
+        let data = "// header\n#TITLE:ゾンビー;\n#ARTIST:\"A\\;B\";\n#OFFSET:1\n#BPMS:0=120;";
+        let mut tokenizer = StepmaniaTokenizer::new(data);
+        let tokens: Vec<StepmaniaToken> = tokenizer.by_ref().collect();
+
+        let names: Vec<&str> = tokens.iter().map(|token| token.name).collect();
+        assert_eq!(names, vec!["TITLE", "ARTIST", "OFFSET", "BPMS"]);
+        for token in tokens.iter() {
+            assert_eq!(Span::of(data, token.raw), token.span);
+        }
+
+        assert!(matches!(tokens[0].value(), Cow::Borrowed("ゾンビー")));
+        assert_eq!(tokens[0].position, (2, 8));
+        assert_eq!(tokens[1].raw, "\"A\\;B\"");
+        assert_eq!(tokens[1].value(), "A;B");
+        assert_eq!(tokens[2].raw, "1");
+
+        assert_eq!(tokenizer.errors.len(), 1);
+        assert_eq!(
+            tokenizer.errors[0].code,
+            ParseErrorCode::StepmaniaExpectedValueEnd
+        );
+    }
+
+    #[test]
+    fn it_should_tokenize_large_files() {
+        let measure = "1000\n0100\n0010\n0001\n,\n";
+        let data = format!("#TITLE:Long;\n#NOTES:{};", measure.repeat(50_000));
+        let tokens: Vec<StepmaniaToken> = StepmaniaTokenizer::new(&data).collect();
+
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[1].raw.len(), measure.len() * 50_000);
+    }
+
+    #[test]
+    fn it_should_stream_like_the_parser() {
+        let data = std::fs::read_to_string("test-files/sample1.sm").unwrap();
+        let mut parser = StepmaniaParser::new();
+        let step = parser.parse_from_string(&data).unwrap();
+
+        let mut stream = StepmaniaStreamParser::new(data.as_bytes());
+        let mut charts: Vec<Vec<(Vec<StepmaniaNoteType>, Vec<Span>)>> = vec![];
+        let mut in_chart = false;
+        for event in stream.by_ref() {
+            match event.unwrap() {
+                StepmaniaEvent::Property { name, value, span } if !in_chart => {
+                    assert_eq!(step.spans.properties[&name], span);
+                    if name == "title" {
+                        assert_eq!(step.title.as_deref(), Some(value.as_str()));
+                    }
+                }
+                StepmaniaEvent::Property { name, span, .. } => {
+                    let chart = &step.notes[charts.len() - 1];
+                    assert_eq!(chart.spans.properties[&name], span);
+                }
+                StepmaniaEvent::ChartStart => {
+                    charts.push(vec![]);
+                    in_chart = true;
+                }
+                StepmaniaEvent::Measure { notes, spans, .. } => {
+                    let types = notes.iter().map(|note| note.note_type).collect();
+                    charts.last_mut().unwrap().push((types, spans));
+                }
+                StepmaniaEvent::ChartEnd => in_chart = false,
+            }
+        }
+
+        assert_eq!(charts.len(), step.notes.len());
+        for (measures, chart) in charts.iter().zip(step.notes.iter()) {
+            assert_eq!(measures.len(), chart.data.notes.len());
+            for (idx, (types, spans)) in measures.iter().enumerate() {
+                let expected: Vec<StepmaniaNoteType> =
+                    chart.data.notes[idx].iter().map(|n| n.note_type).collect();
+                assert_eq!(types, &expected);
+                assert_eq!(spans, &chart.spans.notes[idx]);
+            }
+        }
+        // Only the values of the notes are parsed
+        for err in stream.errors.iter() {
+            assert!(parser
+                .errors
+                .iter()
+                .any(|e| e.code == err.code && e.span == err.span));
+        }
+    }
+
+    #[test]
+    fn it_should_stream_ssc_charts() {
+        let data = "#TITLE:\"A\\;B\";
+garbage
+#NOTEDATA:;
+#STEPSTYPE:dance-single;
+#NOTES:
+1000
+0100
+,
+0[3]100{mod}
+0001
+;
+#NOTEDATA:;
+#METER:3
+#NOTES:
+10000001
+";
+        let mut stream = StepmaniaStreamParser::new(data.as_bytes());
+        let events: Vec<StepmaniaEvent> = stream.by_ref().map(|event| event.unwrap()).collect();
+        let summary: Vec<String> = events
+            .iter()
+            .map(|event| match event {
+                StepmaniaEvent::Property { name, value, .. } => format!("{}={}", name, value),
+                StepmaniaEvent::ChartStart => "start".to_string(),
+                StepmaniaEvent::Measure {
+                    index,
+                    column_count,
+                    notes,
+                    ..
+                } => format!("measure {} {}x{}", index, column_count, notes.len()),
+                StepmaniaEvent::ChartEnd => "end".to_string(),
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                "title=A;B",
+                "start",
+                "stepstype=dance-single",
+                "measure 0 4x8",
+                "measure 1 4x8",
+                "end",
+                "start",
+                "meter=3",
+                "measure 0 8x8",
+                "end",
+            ]
+        );
+
+        let StepmaniaEvent::Measure { notes, .. } = &events[4] else {
+            panic!("expected a measure");
+        };
+        assert_eq!(notes[0].keysound, Some(3));
+
+        let codes: Vec<(ParseErrorCode, usize, usize)> = stream
+            .errors
+            .iter()
+            .map(|err| (err.code, err.line, err.column))
+            .collect();
+        assert_eq!(
+            codes,
+            vec![
+                (ParseErrorCode::StepmaniaExpectedPropertyStart, 2, 1),
+                (ParseErrorCode::StepmaniaExpectedValueEnd, 13, 9),
+                (ParseErrorCode::StepmaniaExpectedValueEnd, 15, 9),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_write_parsable_files() {
+        let mut parser = StepmaniaParser::new();
+        let data = "
+#TITLE:Written;
+#INSTRUMENTTRACKS:guitar=guitar.ogg,drums=drums.ogg;
+#OFFSET:-0.25;
+#BPMS:0=120,4=180.5;
+#KEYSOUNDS:kick.wav,snare.wav;
+#NOTES:
+     dance-single:
+     PreFiXAUT:
+     Hard:
+     9:
+     0,0,0,0,0:
+1[0]000
+0000
+0010
+0001[1]
+,
+0000
+0000
+0000
+0000
+;
+";
+        let step = parser.parse_from_string(data).unwrap();
+        let written = StepmaniaWriter::new().write_to_string(&step).unwrap();
+
+        let mut parser = StepmaniaParser::new();
+        let reparsed = parser.parse_from_string(&written).unwrap();
+        assert_eq!(parser.errors.len(), 0);
+        assert_eq!(reparsed.title.as_deref(), Some("Written"));
+        assert_eq!(reparsed.offset, Some(-250));
+        assert_eq!(reparsed.instrument_tracks.len(), 2);
+        assert_eq!(reparsed.bpms.len(), 2);
+        assert_eq!(reparsed.bpms[1].bpm, 180500);
+        assert_eq!(reparsed.keysounds, vec!["kick.wav", "snare.wav"]);
+
+        let chart = &reparsed.notes[0];
+        assert_eq!(chart.meter, 9);
+        let notes: Vec<(u8, Option<u32>)> = chart
+            .data
+            .positioned_notes()
+            .iter()
+            .map(|n| (n.column, n.keysound))
+            .collect();
+        assert_eq!(notes, vec![(0, Some(0)), (2, None), (3, Some(1))]);
+    }
+
+    #[test]
+    fn it_should_parse_escaped_values() {
+        let mut parser = StepmaniaParser::new();
+        let data = "
+#TITLE:Sample\\; Remix;
+#SUBTITLE:\\#1 \\\\ \\: done;
+#ARTIST:  \"Someone; Else: feat. \\\"X\\\"\"  ;
+#GENRE:'Quoted';
+#CREDIT:'Till I Collapse;
+#CDTITLE:say \"hi\";
+";
+        let step = parser.parse_from_string(data).unwrap();
+        assert_eq!(parser.errors.len(), 0);
+        assert_eq!(step.title.as_deref(), Some("Sample; Remix"));
+        assert_eq!(step.subtitle.as_deref(), Some("#1 \\ : done"));
+        assert_eq!(step.artist.as_deref(), Some("Someone; Else: feat. \"X\""));
+        assert_eq!(step.genre.as_deref(), Some("Quoted"));
+        assert_eq!(step.credit.as_deref(), Some("'Till I Collapse"));
+        assert_eq!(step.cd_title.as_deref(), Some("say \"hi\""));
+    }
+
+    #[test]
+    fn it_should_recover_from_missing_value_ends() {
+        let mut parser = StepmaniaParser::new();
+        let data = "#TITLE:Missing
+#ARTIST:Someone;
+#SUBTITLE:Also missing  \n  \\#not a property
+   #BPMS:0=120;
+#NOTES:
+     dance-single:
+     :
+     Easy:
+     1:
+     0,0,0,0,0:
+1000
+0000
+0000
+0000
+";
+        let step = parser.parse_from_string(data).unwrap();
+        assert_eq!(step.title.as_deref(), Some("Missing"));
+        assert_eq!(step.artist.as_deref(), Some("Someone"));
+        assert_eq!(
+            step.subtitle.as_deref(),
+            Some("Also missing  \n  #not a property")
+        );
+        assert_eq!(step.bpms.len(), 1);
+        assert_eq!(step.notes.len(), 1);
+        assert_eq!(step.notes[0].data.positioned_notes().len(), 1);
+
+        let errors: Vec<(ParseErrorCode, usize, usize)> = parser
+            .errors
+            .iter()
+            .map(|err| (err.code, err.line, err.column))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (ParseErrorCode::StepmaniaExpectedValueEnd, 1, 15),
+                (ParseErrorCode::StepmaniaExpectedValueEnd, 4, 19),
+                (ParseErrorCode::StepmaniaExpectedValueEnd, 15, 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_write_escaped_values() {
+        let step = StepmaniaFile {
+            title: Some("Sample; Remix".to_string()),
+            subtitle: Some("#1 \\ : done".to_string()),
+            artist: Some("\"Weird\" Al".to_string()),
+            genre: Some("'Till I Collapse'".to_string()),
+            labels: vec![StepmaniaTimedLabel {
+                beat: 0,
+                label: "Intro: Part 1".to_string(),
+            }],
+            ..Default::default()
+        };
+        let written = StepmaniaWriter::new().write_to_string(&step).unwrap();
+        assert!(written.contains("#TITLE:Sample\\; Remix;"));
+
+        let mut parser = StepmaniaParser::new();
+        let reparsed = parser.parse_from_string(&written).unwrap();
+        assert_eq!(parser.errors.len(), 0);
+        assert_eq!(reparsed.title, step.title);
+        assert_eq!(reparsed.subtitle, step.subtitle);
+        assert_eq!(reparsed.artist, step.artist);
+        assert_eq!(reparsed.genre, step.genre);
+        assert_eq!(reparsed.labels[0].label, "Intro: Part 1");
+    }
+
+    #[test]
+    fn it_should_write_ssc_files() {
+        let mut parser = StepmaniaParser::new();
+        let data = "
+#VERSION:0.83;
+#DISPLAYBPM:*;
+#MUSICLENGTH:90;
+#WARPS:4=1;
+#BGCHANGES4:0=layer4.png=1.000=0=0=0;
+#BPMS:0=120;
+#NOTEDATA:;
+#STEPSTYPE:dance-single;
+#DIFFICULTY:Hard;
+#METER:9;
+#ATTACKS:TIME=1:LEN=2:MODS=50% drunk;
+#NOTES:
+1000
+0100
+0010
+0001
+;
+";
+        let step = parser.parse_from_string(data).unwrap();
+        let written = StepmaniaWriter::new().write_to_string(&step).unwrap();
+
+        let mut parser = StepmaniaParser::new();
+        let reparsed = parser.parse_from_string(&written).unwrap();
+        assert_eq!(parser.errors.len(), 0);
+        assert_eq!(reparsed.display_bpm, Some(StepmaniaBPMRange::Random));
+        assert_eq!(reparsed.music_length, Some(90000));
+        assert_eq!(reparsed.warps[0].end_beat, 5000);
+        assert_eq!(
+            reparsed.additional_background_changes[0][0].path,
+            "layer4.png"
+        );
+
+        let chart = &reparsed.notes[0];
+        assert_eq!(chart.meter, 9);
+        assert_eq!(chart.attacks.len(), 1);
+        assert_eq!(chart.data.positioned_notes().len(), 4);
+    }
 }
@@ -0,0 +1,446 @@
+use StepmaniaLane::*;
+use StepmaniaLimb::*;
+
+/// With which limb a lane is usually played
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepmaniaLimb {
+    Foot,
+    Hand,
+}
+
+/// The position/meaning of a lane on a single pad or controller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepmaniaLane {
+    Left,
+    Down,
+    Up,
+    Right,
+    UpLeft,
+    UpRight,
+    DownLeft,
+    DownRight,
+    Center,
+    /// A numbered key/button, starting from 1 on the left
+    Key(u8),
+    Scratch,
+}
+
+/// A single column of a step-style
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepmaniaColumn {
+    /// Index of the pad/controller the column is on, starting from 0
+    pub pad: u8,
+    pub limb: StepmaniaLimb,
+    pub lane: StepmaniaLane,
+}
+
+type Layout = &'static [(StepmaniaLimb, StepmaniaLane)];
+
+const LAYOUT_DANCE: Layout = &[(Foot, Left), (Foot, Down), (Foot, Up), (Foot, Right)];
+const LAYOUT_DANCE_SOLO: Layout = &[
+    (Foot, Left),
+    (Foot, UpLeft),
+    (Foot, Down),
+    (Foot, Up),
+    (Foot, UpRight),
+    (Foot, Right),
+];
+const LAYOUT_DANCE_THREEPANEL: Layout = &[(Foot, UpLeft), (Foot, Down), (Foot, UpRight)];
+const LAYOUT_PUMP: Layout = &[
+    (Foot, DownLeft),
+    (Foot, UpLeft),
+    (Foot, Center),
+    (Foot, UpRight),
+    (Foot, DownRight),
+];
+const LAYOUT_PUMP_HALF_LEFT: Layout = &[(Foot, Center), (Foot, UpRight), (Foot, DownRight)];
+const LAYOUT_PUMP_HALF_RIGHT: Layout = &[(Foot, DownLeft), (Foot, UpLeft), (Foot, Center)];
+const LAYOUT_KB7: Layout = &[
+    (Hand, Key(1)),
+    (Hand, Key(2)),
+    (Hand, Key(3)),
+    (Hand, Key(4)),
+    (Hand, Key(5)),
+    (Hand, Key(6)),
+    (Hand, Key(7)),
+];
+const LAYOUT_EZ2: Layout = &[
+    (Foot, UpLeft),
+    (Hand, UpLeft),
+    (Foot, Down),
+    (Hand, UpRight),
+    (Foot, UpRight),
+];
+const LAYOUT_EZ2_REAL: Layout = &[
+    (Foot, UpLeft),
+    (Hand, UpLeft),
+    (Hand, Left),
+    (Foot, Down),
+    (Hand, Right),
+    (Hand, UpRight),
+    (Foot, UpRight),
+];
+const LAYOUT_PARA: Layout = &[
+    (Hand, Left),
+    (Hand, UpLeft),
+    (Hand, Up),
+    (Hand, UpRight),
+    (Hand, Right),
+];
+const LAYOUT_DS3DDX: Layout = &[
+    (Hand, Left),
+    (Foot, DownLeft),
+    (Foot, UpLeft),
+    (Hand, Up),
+    (Hand, Down),
+    (Foot, UpRight),
+    (Foot, DownRight),
+    (Hand, Right),
+];
+const LAYOUT_BEAT5: Layout = &[
+    (Hand, Key(1)),
+    (Hand, Key(2)),
+    (Hand, Key(3)),
+    (Hand, Key(4)),
+    (Hand, Key(5)),
+    (Hand, Scratch),
+];
+const LAYOUT_BEAT7: Layout = &[
+    (Hand, Key(1)),
+    (Hand, Key(2)),
+    (Hand, Key(3)),
+    (Hand, Key(4)),
+    (Hand, Key(5)),
+    (Hand, Key(6)),
+    (Hand, Key(7)),
+    (Hand, Scratch),
+];
+const LAYOUT_MANIAX: Layout = &[(Hand, Left), (Hand, UpLeft), (Hand, UpRight), (Hand, Right)];
+const LAYOUT_TECHNO8: Layout = &[
+    (Foot, DownLeft),
+    (Foot, Left),
+    (Foot, UpLeft),
+    (Foot, Down),
+    (Foot, Up),
+    (Foot, UpRight),
+    (Foot, Right),
+    (Foot, DownRight),
+];
+/// Pop'n five only uses the five center buttons of the nine button layout
+const LAYOUT_PNM5: Layout = &[
+    (Hand, Key(3)),
+    (Hand, Key(4)),
+    (Hand, Key(5)),
+    (Hand, Key(6)),
+    (Hand, Key(7)),
+];
+const LAYOUT_PNM9: Layout = &[
+    (Hand, Key(1)),
+    (Hand, Key(2)),
+    (Hand, Key(3)),
+    (Hand, Key(4)),
+    (Hand, Key(5)),
+    (Hand, Key(6)),
+    (Hand, Key(7)),
+    (Hand, Key(8)),
+    (Hand, Key(9)),
+];
+
+/// The known step-styles (`stepstype`s) of Stepmania charts
+/// Serialized by its name, like "dance-single"
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "String", into = "String")
+)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum StepmaniaStepStyle {
+    #[default]
+    DanceSingle,
+    DanceDouble,
+    DanceCouple,
+    DanceSolo,
+    DanceThreepanel,
+    DanceRoutine,
+    PumpSingle,
+    PumpHalfdouble,
+    PumpDouble,
+    PumpCouple,
+    PumpRoutine,
+    Kb7Single,
+    Ez2Single,
+    Ez2Double,
+    Ez2Real,
+    ParaSingle,
+    Ds3ddxSingle,
+    BeatSingle5,
+    BeatDouble5,
+    BeatSingle7,
+    BeatDouble7,
+    ManiaxSingle,
+    ManiaxDouble,
+    TechnoSingle4,
+    TechnoSingle5,
+    TechnoSingle8,
+    TechnoDouble4,
+    TechnoDouble5,
+    TechnoDouble8,
+    PnmFive,
+    PnmNine,
+    /// A step-style which is not known, with its name
+    Custom(String),
+}
+
+/// All known step-styles, to look them up by name
+const KNOWN_STEP_STYLES: [StepmaniaStepStyle; 31] = [
+    StepmaniaStepStyle::DanceSingle,
+    StepmaniaStepStyle::DanceDouble,
+    StepmaniaStepStyle::DanceCouple,
+    StepmaniaStepStyle::DanceSolo,
+    StepmaniaStepStyle::DanceThreepanel,
+    StepmaniaStepStyle::DanceRoutine,
+    StepmaniaStepStyle::PumpSingle,
+    StepmaniaStepStyle::PumpHalfdouble,
+    StepmaniaStepStyle::PumpDouble,
+    StepmaniaStepStyle::PumpCouple,
+    StepmaniaStepStyle::PumpRoutine,
+    StepmaniaStepStyle::Kb7Single,
+    StepmaniaStepStyle::Ez2Single,
+    StepmaniaStepStyle::Ez2Double,
+    StepmaniaStepStyle::Ez2Real,
+    StepmaniaStepStyle::ParaSingle,
+    StepmaniaStepStyle::Ds3ddxSingle,
+    StepmaniaStepStyle::BeatSingle5,
+    StepmaniaStepStyle::BeatDouble5,
+    StepmaniaStepStyle::BeatSingle7,
+    StepmaniaStepStyle::BeatDouble7,
+    StepmaniaStepStyle::ManiaxSingle,
+    StepmaniaStepStyle::ManiaxDouble,
+    StepmaniaStepStyle::TechnoSingle4,
+    StepmaniaStepStyle::TechnoSingle5,
+    StepmaniaStepStyle::TechnoSingle8,
+    StepmaniaStepStyle::TechnoDouble4,
+    StepmaniaStepStyle::TechnoDouble5,
+    StepmaniaStepStyle::TechnoDouble8,
+    StepmaniaStepStyle::PnmFive,
+    StepmaniaStepStyle::PnmNine,
+];
+
+impl StepmaniaStepStyle {
+    pub fn from_name(str: &str) -> Self {
+        let name = str.trim().to_lowercase();
+        KNOWN_STEP_STYLES
+            .iter()
+            .find(|style| style.name() == name)
+            .cloned()
+            .unwrap_or(StepmaniaStepStyle::Custom(str.trim().to_string()))
+    }
+
+    /// The most common single player step-style for the amount of columns
+    pub fn for_column_count(column_count: u8) -> Option<Self> {
+        match column_count {
+            3 => Some(StepmaniaStepStyle::DanceThreepanel),
+            4 => Some(StepmaniaStepStyle::DanceSingle),
+            5 => Some(StepmaniaStepStyle::PumpSingle),
+            6 => Some(StepmaniaStepStyle::DanceSolo),
+            7 => Some(StepmaniaStepStyle::Kb7Single),
+            8 => Some(StepmaniaStepStyle::DanceDouble),
+            9 => Some(StepmaniaStepStyle::PnmNine),
+            10 => Some(StepmaniaStepStyle::PumpDouble),
+            16 => Some(StepmaniaStepStyle::BeatDouble7),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            StepmaniaStepStyle::DanceSingle => "dance-single",
+            StepmaniaStepStyle::DanceDouble => "dance-double",
+            StepmaniaStepStyle::DanceCouple => "dance-couple",
+            StepmaniaStepStyle::DanceSolo => "dance-solo",
+            StepmaniaStepStyle::DanceThreepanel => "dance-threepanel",
+            StepmaniaStepStyle::DanceRoutine => "dance-routine",
+            StepmaniaStepStyle::PumpSingle => "pump-single",
+            StepmaniaStepStyle::PumpHalfdouble => "pump-halfdouble",
+            StepmaniaStepStyle::PumpDouble => "pump-double",
+            StepmaniaStepStyle::PumpCouple => "pump-couple",
+            StepmaniaStepStyle::PumpRoutine => "pump-routine",
+            StepmaniaStepStyle::Kb7Single => "kb7-single",
+            StepmaniaStepStyle::Ez2Single => "ez2-single",
+            StepmaniaStepStyle::Ez2Double => "ez2-double",
+            StepmaniaStepStyle::Ez2Real => "ez2-real",
+            StepmaniaStepStyle::ParaSingle => "para-single",
+            StepmaniaStepStyle::Ds3ddxSingle => "ds3ddx-single",
+            StepmaniaStepStyle::BeatSingle5 => "beat-single5",
+            StepmaniaStepStyle::BeatDouble5 => "beat-double5",
+            StepmaniaStepStyle::BeatSingle7 => "beat-single7",
+            StepmaniaStepStyle::BeatDouble7 => "beat-double7",
+            StepmaniaStepStyle::ManiaxSingle => "maniax-single",
+            StepmaniaStepStyle::ManiaxDouble => "maniax-double",
+            StepmaniaStepStyle::TechnoSingle4 => "techno-single4",
+            StepmaniaStepStyle::TechnoSingle5 => "techno-single5",
+            StepmaniaStepStyle::TechnoSingle8 => "techno-single8",
+            StepmaniaStepStyle::TechnoDouble4 => "techno-double4",
+            StepmaniaStepStyle::TechnoDouble5 => "techno-double5",
+            StepmaniaStepStyle::TechnoDouble8 => "techno-double8",
+            StepmaniaStepStyle::PnmFive => "pnm-five",
+            StepmaniaStepStyle::PnmNine => "pnm-nine",
+            StepmaniaStepStyle::Custom(name) => name,
+        }
+    }
+
+    /// The layouts of each pad/controller the style is played on
+    fn layouts(&self) -> Vec<Layout> {
+        match self {
+            StepmaniaStepStyle::DanceSingle | StepmaniaStepStyle::TechnoSingle4 => {
+                vec![LAYOUT_DANCE]
+            }
+            StepmaniaStepStyle::DanceDouble
+            | StepmaniaStepStyle::DanceCouple
+            | StepmaniaStepStyle::DanceRoutine
+            | StepmaniaStepStyle::TechnoDouble4 => vec![LAYOUT_DANCE, LAYOUT_DANCE],
+            StepmaniaStepStyle::DanceSolo => vec![LAYOUT_DANCE_SOLO],
+            StepmaniaStepStyle::DanceThreepanel => vec![LAYOUT_DANCE_THREEPANEL],
+            StepmaniaStepStyle::PumpSingle | StepmaniaStepStyle::TechnoSingle5 => {
+                vec![LAYOUT_PUMP]
+            }
+            StepmaniaStepStyle::PumpHalfdouble => {
+                vec![LAYOUT_PUMP_HALF_LEFT, LAYOUT_PUMP_HALF_RIGHT]
+            }
+            StepmaniaStepStyle::PumpDouble
+            | StepmaniaStepStyle::PumpCouple
+            | StepmaniaStepStyle::PumpRoutine
+            | StepmaniaStepStyle::TechnoDouble5 => vec![LAYOUT_PUMP, LAYOUT_PUMP],
+            StepmaniaStepStyle::Kb7Single => vec![LAYOUT_KB7],
+            StepmaniaStepStyle::Ez2Single => vec![LAYOUT_EZ2],
+            StepmaniaStepStyle::Ez2Double => vec![LAYOUT_EZ2, LAYOUT_EZ2],
+            StepmaniaStepStyle::Ez2Real => vec![LAYOUT_EZ2_REAL],
+            StepmaniaStepStyle::ParaSingle => vec![LAYOUT_PARA],
+            StepmaniaStepStyle::Ds3ddxSingle => vec![LAYOUT_DS3DDX],
+            StepmaniaStepStyle::BeatSingle5 => vec![LAYOUT_BEAT5],
+            StepmaniaStepStyle::BeatDouble5 => vec![LAYOUT_BEAT5, LAYOUT_BEAT5],
+            StepmaniaStepStyle::BeatSingle7 => vec![LAYOUT_BEAT7],
+            StepmaniaStepStyle::BeatDouble7 => vec![LAYOUT_BEAT7, LAYOUT_BEAT7],
+            StepmaniaStepStyle::ManiaxSingle => vec![LAYOUT_MANIAX],
+            StepmaniaStepStyle::ManiaxDouble => vec![LAYOUT_MANIAX, LAYOUT_MANIAX],
+            StepmaniaStepStyle::TechnoSingle8 => vec![LAYOUT_TECHNO8],
+            StepmaniaStepStyle::TechnoDouble8 => vec![LAYOUT_TECHNO8, LAYOUT_TECHNO8],
+            StepmaniaStepStyle::PnmFive => vec![LAYOUT_PNM5],
+            StepmaniaStepStyle::PnmNine => vec![LAYOUT_PNM9],
+            StepmaniaStepStyle::Custom(_) => vec![],
+        }
+    }
+
+    /// The columns of the style from left to right.
+    /// Empty for custom styles, as their layout is unknown.
+    pub fn columns(&self) -> Vec<StepmaniaColumn> {
+        self.layouts()
+            .iter()
+            .enumerate()
+            .flat_map(|(pad, layout)| {
+                layout.iter().map(move |(limb, lane)| StepmaniaColumn {
+                    pad: pad as u8,
+                    limb: *limb,
+                    lane: *lane,
+                })
+            })
+            .collect()
+    }
+
+    /// The amount of columns every row of the style has, if the style is known
+    pub fn column_count(&self) -> Option<u8> {
+        let count: usize = self.layouts().iter().map(|layout| layout.len()).sum();
+        Some(count as u8).filter(|count| *count > 0)
+    }
+
+    /// The amount of pads/controllers the style is played on
+    pub fn pad_count(&self) -> u8 {
+        self.layouts().len() as u8
+    }
+
+    /// If the style is played by two players at the same time
+    pub fn is_multiplayer(&self) -> bool {
+        matches!(
+            self,
+            StepmaniaStepStyle::DanceCouple
+                | StepmaniaStepStyle::DanceRoutine
+                | StepmaniaStepStyle::PumpCouple
+                | StepmaniaStepStyle::PumpRoutine
+        )
+    }
+}
+
+impl From<String> for StepmaniaStepStyle {
+    fn from(name: String) -> Self {
+        StepmaniaStepStyle::from_name(&name)
+    }
+}
+
+impl From<StepmaniaStepStyle> for String {
+    fn from(style: StepmaniaStepStyle) -> Self {
+        style.name().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_look_up_styles_by_name() {
+        assert_eq!(
+            StepmaniaStepStyle::from_name("Dance-Single "),
+            StepmaniaStepStyle::DanceSingle
+        );
+        assert_eq!(
+            StepmaniaStepStyle::from_name("pnm-nine"),
+            StepmaniaStepStyle::PnmNine
+        );
+        assert_eq!(
+            StepmaniaStepStyle::from_name("lights-cabinet"),
+            StepmaniaStepStyle::Custom("lights-cabinet".to_string())
+        );
+
+        for style in KNOWN_STEP_STYLES.iter() {
+            assert_eq!(&StepmaniaStepStyle::from_name(style.name()), style);
+        }
+    }
+
+    #[test]
+    fn it_should_have_column_counts() {
+        assert_eq!(StepmaniaStepStyle::DanceSingle.column_count(), Some(4));
+        assert_eq!(StepmaniaStepStyle::DanceSolo.column_count(), Some(6));
+        assert_eq!(StepmaniaStepStyle::PumpHalfdouble.column_count(), Some(6));
+        assert_eq!(StepmaniaStepStyle::PumpDouble.column_count(), Some(10));
+        assert_eq!(StepmaniaStepStyle::BeatDouble7.column_count(), Some(16));
+        assert_eq!(
+            StepmaniaStepStyle::Custom("drum-single".to_string()).column_count(),
+            None
+        );
+
+        for count in 3..=10 {
+            let style = StepmaniaStepStyle::for_column_count(count).unwrap();
+            assert_eq!(style.column_count(), Some(count));
+        }
+    }
+
+    #[test]
+    fn it_should_describe_lanes() {
+        let columns = StepmaniaStepStyle::DanceDouble.columns();
+        assert_eq!(columns.len(), 8);
+        assert_eq!(columns[0].lane, StepmaniaLane::Left);
+        assert_eq!(columns[0].pad, 0);
+        assert_eq!(columns[7].lane, StepmaniaLane::Right);
+        assert_eq!(columns[7].pad, 1);
+
+        let columns = StepmaniaStepStyle::PumpHalfdouble.columns();
+        assert_eq!(columns[0].lane, StepmaniaLane::Center);
+        assert_eq!(columns[5].lane, StepmaniaLane::Center);
+        assert_eq!(columns[5].pad, 1);
+
+        let columns = StepmaniaStepStyle::BeatSingle7.columns();
+        assert_eq!(columns[7].lane, StepmaniaLane::Scratch);
+        assert_eq!(columns[7].limb, StepmaniaLimb::Hand);
+    }
+}
@@ -0,0 +1,59 @@
+//! Fixtures shared by the tests of the modules
+
+use super::stepmania::*;
+use super::stepstyle::*;
+
+/// A chart of the step-style with the notes as `(beat, column, type)`
+pub fn chart_from(
+    step_style: StepmaniaStepStyle,
+    notes: &[(f64, u8, StepmaniaNoteType)],
+) -> StepmaniaChart {
+    let positioned: Vec<StepmaniaPositionedNote> = notes
+        .iter()
+        .map(|(beat, column, note_type)| {
+            StepmaniaPositionedNote::at_beat(*beat, *column, *note_type)
+        })
+        .collect();
+    StepmaniaChart {
+        data: StepmaniaNoteData::from_positioned_notes(
+            step_style.column_count().unwrap(),
+            &positioned,
+        ),
+        step_style,
+        ..Default::default()
+    }
+}
+
+/// Taps at the `(beat, column)` positions
+pub fn taps(notes: impl IntoIterator<Item = (f64, u8)>) -> Vec<(f64, u8, StepmaniaNoteType)> {
+    notes
+        .into_iter()
+        .map(|(beat, column)| (beat, column, StepmaniaNoteType::Tap))
+        .collect()
+}
+
+/// The `(beat, column)` positions of rows with all of their columns
+pub fn chords(rows: &[(f64, &[u8])]) -> Vec<(f64, u8)> {
+    rows.iter()
+        .flat_map(|(beat, columns)| columns.iter().map(|column| (*beat, *column)))
+        .collect()
+}
+
+/// A file with the chart as its only chart, at a constant BPM in thousandths
+pub fn file_with(bpm: i64, chart: StepmaniaChart) -> StepmaniaFile {
+    StepmaniaFile {
+        bpms: vec![StepmaniaTimedBPM { beat: 0, bpm }],
+        notes: vec![chart],
+        ..Default::default()
+    }
+}
+
+/// The notes of the chart as `(beat, column, type)`
+pub fn notes_of(chart: &StepmaniaChart) -> Vec<(f64, u8, StepmaniaNoteType)> {
+    chart
+        .data
+        .positioned_notes()
+        .iter()
+        .map(|n| (n.beat(), n.column, n.note_type))
+        .collect()
+}
@@ -0,0 +1,588 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Result};
+
+use super::stepmania::*;
+use super::stepstyle::*;
+
+/// After how many measures the window of the autogen mapping moves
+const AUTOGEN_SHIFT_MEASURES: usize = 4;
+
+/// How the columns of a chart are mapped to the columns of another play-style
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepmaniaLaneMapping {
+    /// The target columns for each source column.
+    /// Notes of a column are copied into every target, or dropped if there are none.
+    Columns(Vec<Vec<u8>>),
+    /// Doubles the chart with the second half mirrored (i.E. single to double),
+    /// or folds the mirrored second half onto the first one (i.E. double to single).
+    MirrorHalves,
+    /// Spreads the columns evenly over the target columns, keeping their relative position
+    Spread,
+    /// Like Stepmania's autogen: the chart is played in a window which slides over
+    /// the wider target every few measures, but only while no hold is active.
+    /// Narrower targets are spread.
+    Autogen,
+}
+
+fn mirror_halves(column: u8, from: u8, to: u8) -> Result<Vec<u8>> {
+    if to == from * 2 {
+        Ok(vec![column, to - 1 - column])
+    } else if from == to * 2 {
+        if column < to {
+            Ok(vec![column])
+        } else {
+            Ok(vec![from - 1 - column])
+        }
+    } else {
+        bail!(
+            "cannot mirror halves from {} to {} columns, one has to be double the other",
+            from,
+            to
+        )
+    }
+}
+
+fn spread(column: u8, from: u8, to: u8) -> u8 {
+    if from <= 1 || to <= 1 {
+        return 0;
+    }
+    let pos = column as f64 * (to - 1) as f64 / (from - 1) as f64;
+    pos.round() as u8
+}
+
+/// Sort key to have the notes ordered by time, with tails before other notes of the row
+fn note_order(note: &StepmaniaPositionedNote) -> (usize, usize, bool, u8) {
+    let row = note.row * MAX_ROWS_PER_MEASURE / note.row_count.max(1);
+    (
+        note.measure,
+        row,
+        note.note_type != StepmaniaNoteType::Tail,
+        note.column,
+    )
+}
+
+impl StepmaniaNoteData {
+    /// Maps the notes to `column_count` columns with the mapping.
+    /// Notes which would land on an occupied or held column are dropped, and holds are
+    /// always kept together with their tail.
+    pub fn remap_columns(
+        &self,
+        column_count: u8,
+        mapping: &StepmaniaLaneMapping,
+    ) -> Result<StepmaniaNoteData> {
+        let from = self.column_count;
+        let to = column_count;
+        if let StepmaniaLaneMapping::Columns(columns) = mapping {
+            if columns.len() != from as usize {
+                bail!(
+                    "mapping has {} columns, but the chart has {}",
+                    columns.len(),
+                    from
+                );
+            }
+            if let Some(invalid) = columns.iter().flatten().find(|c| **c >= to) {
+                bail!("mapping targets column {} of only {}", invalid, to);
+            }
+        }
+        if *mapping == StepmaniaLaneMapping::MirrorHalves {
+            mirror_halves(0, from, to)?;
+        }
+
+        let mut notes = self.positioned_notes();
+        notes.sort_by_key(note_order);
+
+        let mut positioned: Vec<StepmaniaPositionedNote> = vec![];
+        // Target columns of the holds which are still active, by their source column
+        let mut open_holds: HashMap<u8, Vec<u8>> = HashMap::new();
+        let mut held = vec![false; to as usize];
+        let mut occupied: Vec<(usize, usize, u8)> = vec![];
+        let mut offset: u8 = 0;
+        let mut direction: i8 = 1;
+        let mut window_measure = 0;
+
+        for note in notes.into_iter() {
+            let row = note_order(&note);
+            let row = (row.0, row.1);
+            occupied.retain(|(measure, r, _)| (*measure, *r) == row);
+
+            let targets = if note.note_type == StepmaniaNoteType::Tail {
+                let targets = open_holds.remove(&note.column).unwrap_or_default();
+                for target in targets.iter() {
+                    held[*target as usize] = false;
+                }
+                targets
+            } else {
+                if note.measure >= window_measure + AUTOGEN_SHIFT_MEASURES && open_holds.is_empty()
+                {
+                    window_measure = note.measure;
+                    if to > from {
+                        let max = to - from;
+                        if (direction > 0 && offset == max) || (direction < 0 && offset == 0) {
+                            direction = -direction;
+                        }
+                        offset = (offset as i8 + direction) as u8;
+                    }
+                }
+
+                let targets = match mapping {
+                    StepmaniaLaneMapping::Columns(columns) => columns[note.column as usize].clone(),
+                    StepmaniaLaneMapping::MirrorHalves => mirror_halves(note.column, from, to)?,
+                    StepmaniaLaneMapping::Spread => vec![spread(note.column, from, to)],
+                    StepmaniaLaneMapping::Autogen if to > from => vec![note.column + offset],
+                    StepmaniaLaneMapping::Autogen => vec![spread(note.column, from, to)],
+                };
+                let targets: Vec<u8> = targets
+                    .into_iter()
+                    .filter(|t| !held[*t as usize] && !occupied.iter().any(|(_, _, c)| *c == *t))
+                    .collect();
+
+                if matches!(
+                    note.note_type,
+                    StepmaniaNoteType::HoldHead | StepmaniaNoteType::RollHead
+                ) {
+                    for target in targets.iter() {
+                        held[*target as usize] = true;
+                    }
+                    open_holds.insert(note.column, targets.clone());
+                }
+                targets
+            };
+
+            for target in targets {
+                occupied.push((row.0, row.1, target));
+                positioned.push(StepmaniaPositionedNote {
+                    column: target,
+                    ..note.clone()
+                });
+            }
+        }
+
+        Ok(StepmaniaNoteData::from_positioned_notes(to, &positioned))
+    }
+}
+
+impl StepmaniaChart {
+    /// Converts the chart to another (known) step-style, remapping the notes with the mapping
+    pub fn convert_step_style(
+        &mut self,
+        step_style: StepmaniaStepStyle,
+        mapping: &StepmaniaLaneMapping,
+    ) -> Result<()> {
+        let column_count = step_style
+            .column_count()
+            .ok_or_else(|| anyhow!("unknown column count of step-style {}", step_style.name()))?;
+        self.data = self.data.remap_columns(column_count, mapping)?;
+        self.step_style = step_style;
+
+        Ok(())
+    }
+}
+
+/// Turn modifiers which are applied permanently to a chart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepmaniaTurn {
+    /// Reverses the order of the columns
+    Mirror,
+    /// Turns every pad by 90 degrees to the left
+    Left,
+    /// Turns every pad by 90 degrees to the right
+    Right,
+    /// Swaps the columns randomly for the whole chart, with the seed for the randomness
+    Shuffle(u64),
+    /// Swaps the columns randomly for every row, with the seed for the randomness
+    SuperShuffle(u64),
+}
+
+/// A small seeded random number generator (SplitMix64), so that the same seed
+/// always produces the same chart, independent of any platform or dependency.
+struct SeededRandom {
+    state: u64,
+}
+
+impl SeededRandom {
+    fn new(seed: u64) -> SeededRandom {
+        SeededRandom { state: seed }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// Shuffles the list in place (Fisher-Yates)
+    fn shuffle<T>(&mut self, list: &mut [T]) {
+        for idx in (1..list.len()).rev() {
+            let other = (self.next() % (idx as u64 + 1)) as usize;
+            list.swap(idx, other);
+        }
+    }
+}
+
+// The column each column of a pad is moved to when it's turned to the left, like Stepmania's turn tables.
+// Diagonals without a turned counterpart on the pad are swapped with the opposite diagonal.
+const TURN_DANCE: &[u8] = &[1, 3, 0, 2];
+const TURN_DANCE_SOLO: &[u8] = &[2, 4, 5, 0, 1, 3];
+const TURN_DANCE_THREEPANEL: &[u8] = &[2, 1, 0];
+const TURN_PUMP: &[u8] = &[4, 0, 2, 1, 3];
+const TURN_PUMP_HALF_LEFT: &[u8] = &[0, 2, 1];
+const TURN_PUMP_HALF_RIGHT: &[u8] = &[1, 0, 2];
+const TURN_TECHNO8: &[u8] = &[7, 3, 0, 6, 1, 2, 4, 5];
+
+/// The turn tables of each pad of the step-style, if it can be turned
+fn turn_tables(step_style: &StepmaniaStepStyle) -> Option<Vec<&'static [u8]>> {
+    let tables = match step_style {
+        StepmaniaStepStyle::DanceSingle | StepmaniaStepStyle::TechnoSingle4 => vec![TURN_DANCE],
+        StepmaniaStepStyle::DanceDouble
+        | StepmaniaStepStyle::DanceCouple
+        | StepmaniaStepStyle::DanceRoutine
+        | StepmaniaStepStyle::TechnoDouble4 => vec![TURN_DANCE, TURN_DANCE],
+        StepmaniaStepStyle::DanceSolo => vec![TURN_DANCE_SOLO],
+        StepmaniaStepStyle::DanceThreepanel => vec![TURN_DANCE_THREEPANEL],
+        StepmaniaStepStyle::PumpSingle | StepmaniaStepStyle::TechnoSingle5 => vec![TURN_PUMP],
+        StepmaniaStepStyle::PumpHalfdouble => vec![TURN_PUMP_HALF_LEFT, TURN_PUMP_HALF_RIGHT],
+        StepmaniaStepStyle::PumpDouble
+        | StepmaniaStepStyle::PumpCouple
+        | StepmaniaStepStyle::PumpRoutine
+        | StepmaniaStepStyle::TechnoDouble5 => vec![TURN_PUMP, TURN_PUMP],
+        StepmaniaStepStyle::TechnoSingle8 => vec![TURN_TECHNO8],
+        StepmaniaStepStyle::TechnoDouble8 => vec![TURN_TECHNO8, TURN_TECHNO8],
+        _ => return None,
+    };
+
+    Some(tables)
+}
+
+/// The column each column is turned into, with every pad turned by its table
+fn turned_columns(step_style: &StepmaniaStepStyle, left: bool) -> Result<Vec<u8>> {
+    let tables = turn_tables(step_style)
+        .ok_or_else(|| anyhow!("step-style {} can't be turned", step_style.name()))?;
+
+    let mut columns = vec![];
+    for table in tables {
+        let offset = columns.len() as u8;
+        columns.extend(table.iter().map(|column| offset + column));
+    }
+
+    if left {
+        return Ok(columns);
+    }
+
+    // Turning to the right moves every column back to where it came from
+    let mut inverse = vec![0; columns.len()];
+    for (from, to) in columns.iter().enumerate() {
+        inverse[*to as usize] = from as u8;
+    }
+    Ok(inverse)
+}
+
+impl StepmaniaNoteData {
+    /// Moves every note (except tails, which stay with their head) into a random free column of its row
+    fn super_shuffle(&self, random: &mut SeededRandom) -> StepmaniaNoteData {
+        let mut notes = self.positioned_notes();
+        notes.sort_by_key(note_order);
+
+        let mut positioned: Vec<StepmaniaPositionedNote> = vec![];
+        // Target column of the holds which are still active, by their source column
+        let mut open_holds: HashMap<u8, u8> = HashMap::new();
+        let mut idx = 0;
+
+        while idx < notes.len() {
+            let row = note_order(&notes[idx]);
+            let end = notes[idx..]
+                .iter()
+                .position(|note| {
+                    let other = note_order(note);
+                    (other.0, other.1) != (row.0, row.1)
+                })
+                .map_or(notes.len(), |len| idx + len);
+
+            // The tails stay in the column of their head, which is not free for this row either
+            let mut free: Vec<u8> = (0..self.column_count)
+                .filter(|column| !open_holds.values().any(|held| held == column))
+                .collect();
+            let mut targets = vec![];
+            for note in notes[idx..end].iter() {
+                if note.note_type == StepmaniaNoteType::Tail {
+                    if let Some(column) = open_holds.remove(&note.column) {
+                        targets.push(Some(column));
+                        continue;
+                    }
+                }
+                targets.push(None);
+            }
+            random.shuffle(&mut free);
+
+            for (note, target) in notes[idx..end].iter().zip(targets) {
+                let column = match target {
+                    Some(column) => column,
+                    None => {
+                        if note.note_type == StepmaniaNoteType::Tail {
+                            // A tail without a head is meaningless
+                            continue;
+                        }
+                        let Some(column) = free.pop() else {
+                            continue;
+                        };
+                        if matches!(
+                            note.note_type,
+                            StepmaniaNoteType::HoldHead | StepmaniaNoteType::RollHead
+                        ) {
+                            open_holds.insert(note.column, column);
+                        }
+                        column
+                    }
+                };
+                positioned.push(StepmaniaPositionedNote {
+                    column,
+                    ..note.clone()
+                });
+            }
+
+            idx = end;
+        }
+
+        StepmaniaNoteData::from_positioned_notes(self.column_count, &positioned)
+    }
+}
+
+impl StepmaniaChart {
+    /// Applies the turn to the notes of the chart, keeping holds intact
+    pub fn apply_turn(&mut self, turn: StepmaniaTurn) -> Result<()> {
+        let column_count = self.data.column_count;
+        let columns = match turn {
+            StepmaniaTurn::Mirror => (0..column_count).rev().collect(),
+            StepmaniaTurn::Left => turned_columns(&self.step_style, true)?,
+            StepmaniaTurn::Right => turned_columns(&self.step_style, false)?,
+            StepmaniaTurn::Shuffle(seed) => {
+                let mut columns: Vec<u8> = (0..column_count).collect();
+                SeededRandom::new(seed).shuffle(&mut columns);
+                columns
+            }
+            StepmaniaTurn::SuperShuffle(seed) => {
+                self.data = self.data.super_shuffle(&mut SeededRandom::new(seed));
+                return Ok(());
+            }
+        };
+
+        let mapping = StepmaniaLaneMapping::Columns(columns.into_iter().map(|c| vec![c]).collect());
+        self.data = self.data.remap_columns(column_count, &mapping)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn it_should_mirror_single_to_double() {
+        let mut chart = chart_from(
+            StepmaniaStepStyle::DanceSingle,
+            &[
+                (0.0, 0, StepmaniaNoteType::Tap),
+                (1.0, 2, StepmaniaNoteType::HoldHead),
+                (2.0, 2, StepmaniaNoteType::Tail),
+            ],
+        );
+        chart
+            .convert_step_style(
+                StepmaniaStepStyle::DanceDouble,
+                &StepmaniaLaneMapping::MirrorHalves,
+            )
+            .unwrap();
+
+        assert_eq!(chart.step_style, StepmaniaStepStyle::DanceDouble);
+        assert_eq!(chart.data.column_count, 8);
+        assert_eq!(
+            notes_of(&chart),
+            vec![
+                (0.0, 0, StepmaniaNoteType::Tap),
+                (0.0, 7, StepmaniaNoteType::Tap),
+                (1.0, 2, StepmaniaNoteType::HoldHead),
+                (1.0, 5, StepmaniaNoteType::HoldHead),
+                (2.0, 2, StepmaniaNoteType::Tail),
+                (2.0, 5, StepmaniaNoteType::Tail),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_spread_and_keep_holds() {
+        let mut chart = chart_from(
+            StepmaniaStepStyle::DanceDouble,
+            &[
+                (0.0, 0, StepmaniaNoteType::HoldHead),
+                (1.0, 1, StepmaniaNoteType::Tap),
+                (2.0, 0, StepmaniaNoteType::Tail),
+                (3.0, 7, StepmaniaNoteType::Tap),
+            ],
+        );
+        chart
+            .convert_step_style(
+                StepmaniaStepStyle::DanceSingle,
+                &StepmaniaLaneMapping::Spread,
+            )
+            .unwrap();
+
+        // The tap lands in the held column and is dropped
+        assert_eq!(
+            notes_of(&chart),
+            vec![
+                (0.0, 0, StepmaniaNoteType::HoldHead),
+                (2.0, 0, StepmaniaNoteType::Tail),
+                (3.0, 3, StepmaniaNoteType::Tap),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_map_custom_columns() {
+        let mut chart = chart_from(
+            StepmaniaStepStyle::DanceSingle,
+            &[
+                (0.0, 0, StepmaniaNoteType::Tap),
+                (0.0, 3, StepmaniaNoteType::Tap),
+                (1.0, 1, StepmaniaNoteType::Mine),
+            ],
+        );
+        let mapping = StepmaniaLaneMapping::Columns(vec![vec![0], vec![2], vec![4], vec![6]]);
+        chart
+            .convert_step_style(StepmaniaStepStyle::Kb7Single, &mapping)
+            .unwrap();
+
+        assert_eq!(
+            notes_of(&chart),
+            vec![
+                (0.0, 0, StepmaniaNoteType::Tap),
+                (0.0, 6, StepmaniaNoteType::Tap),
+                (1.0, 2, StepmaniaNoteType::Mine),
+            ]
+        );
+
+        let invalid = StepmaniaLaneMapping::Columns(vec![vec![0]]);
+        assert!(chart
+            .convert_step_style(StepmaniaStepStyle::DanceSingle, &invalid)
+            .is_err());
+    }
+
+    #[test]
+    fn it_should_slide_autogen_window() {
+        let notes: Vec<(f64, u8, StepmaniaNoteType)> = (0..12)
+            .map(|measure| (measure as f64 * 4.0, 0, StepmaniaNoteType::Tap))
+            .collect();
+        let mut chart = chart_from(StepmaniaStepStyle::DanceSingle, &notes);
+        chart
+            .convert_step_style(
+                StepmaniaStepStyle::DanceSolo,
+                &StepmaniaLaneMapping::Autogen,
+            )
+            .unwrap();
+
+        let columns: Vec<u8> = notes_of(&chart).iter().map(|n| n.1).collect();
+        assert_eq!(columns, vec![0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn it_should_mirror_and_turn() {
+        let notes = [
+            (0.0, 0, StepmaniaNoteType::Tap),
+            (1.0, 2, StepmaniaNoteType::HoldHead),
+            (2.0, 2, StepmaniaNoteType::Tail),
+        ];
+
+        let mut chart = chart_from(StepmaniaStepStyle::DanceSingle, &notes);
+        chart.apply_turn(StepmaniaTurn::Mirror).unwrap();
+        let columns: Vec<u8> = notes_of(&chart).iter().map(|n| n.1).collect();
+        assert_eq!(columns, vec![3, 1, 1]);
+
+        let mut chart = chart_from(StepmaniaStepStyle::DanceSingle, &notes);
+        chart.apply_turn(StepmaniaTurn::Left).unwrap();
+        let columns: Vec<u8> = notes_of(&chart).iter().map(|n| n.1).collect();
+        assert_eq!(columns, vec![1, 0, 0]);
+
+        let mut chart = chart_from(StepmaniaStepStyle::DanceSingle, &notes);
+        chart.apply_turn(StepmaniaTurn::Right).unwrap();
+        let columns: Vec<u8> = notes_of(&chart).iter().map(|n| n.1).collect();
+        assert_eq!(columns, vec![2, 3, 3]);
+
+        let mut chart = chart_from(StepmaniaStepStyle::Kb7Single, &notes);
+        assert!(chart.apply_turn(StepmaniaTurn::Left).is_err());
+    }
+
+    #[test]
+    fn it_should_turn_asymmetric_layouts() {
+        let notes: Vec<(f64, u8, StepmaniaNoteType)> = (0..6)
+            .map(|column| (column as f64, column, StepmaniaNoteType::Tap))
+            .collect();
+
+        let mut chart = chart_from(StepmaniaStepStyle::DanceSolo, &notes);
+        chart.apply_turn(StepmaniaTurn::Left).unwrap();
+        let columns: Vec<u8> = notes_of(&chart).iter().map(|n| n.1).collect();
+        assert_eq!(columns, vec![2, 4, 5, 0, 1, 3]);
+
+        chart.apply_turn(StepmaniaTurn::Right).unwrap();
+        assert_eq!(notes_of(&chart), notes);
+
+        let mut chart = chart_from(StepmaniaStepStyle::PumpHalfdouble, &notes);
+        chart.apply_turn(StepmaniaTurn::Right).unwrap();
+        let columns: Vec<u8> = notes_of(&chart).iter().map(|n| n.1).collect();
+        assert_eq!(columns, vec![0, 2, 1, 4, 3, 5]);
+    }
+
+    #[test]
+    fn it_should_shuffle_with_seed() {
+        let notes: Vec<(f64, u8, StepmaniaNoteType)> = (0..16)
+            .map(|beat| (beat as f64, beat % 4, StepmaniaNoteType::Tap))
+            .collect();
+
+        let mut first = chart_from(StepmaniaStepStyle::DanceSingle, &notes);
+        first.apply_turn(StepmaniaTurn::Shuffle(1234)).unwrap();
+        let mut second = chart_from(StepmaniaStepStyle::DanceSingle, &notes);
+        second.apply_turn(StepmaniaTurn::Shuffle(1234)).unwrap();
+        assert_eq!(notes_of(&first), notes_of(&second));
+
+        // The whole chart uses the same permutation
+        let columns: Vec<u8> = notes_of(&first).iter().map(|n| n.1).collect();
+        assert_eq!(columns[0..4], columns[4..8]);
+        let mut sorted = columns[0..4].to_vec();
+        sorted.sort();
+        assert_eq!(sorted, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn it_should_super_shuffle_with_holds_intact() {
+        let mut notes = vec![
+            (0.0, 0, StepmaniaNoteType::HoldHead),
+            (4.0, 0, StepmaniaNoteType::Tail),
+        ];
+        for beat in 0..8 {
+            notes.push((beat as f64 + 0.5, 1, StepmaniaNoteType::Tap));
+            notes.push((beat as f64 + 0.5, 2, StepmaniaNoteType::Tap));
+        }
+
+        let mut chart = chart_from(StepmaniaStepStyle::DanceSingle, &notes);
+        chart.apply_turn(StepmaniaTurn::SuperShuffle(42)).unwrap();
+        let shuffled = notes_of(&chart);
+        assert_eq!(shuffled.len(), notes.len());
+
+        let head = shuffled
+            .iter()
+            .find(|n| n.2 == StepmaniaNoteType::HoldHead)
+            .unwrap();
+        let tail = shuffled
+            .iter()
+            .find(|n| n.2 == StepmaniaNoteType::Tail)
+            .unwrap();
+        assert_eq!(head.1, tail.1);
+        // Nothing is placed inside of the hold
+        assert!(!shuffled
+            .iter()
+            .any(|n| n.1 == head.1 && n.0 > head.0 && n.0 < tail.0));
+    }
+}